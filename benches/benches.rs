@@ -1,7 +1,9 @@
-use criterion::Criterion;
 use criterion::{criterion_group, criterion_main};
+use criterion::{BenchmarkId, Criterion};
 use once_cell::sync::Lazy;
 use rand::{distributions::Alphanumeric, Rng};
+use std::thread;
+use tempfile::TempDir;
 
 use kvs::KvStore;
 use kvs::KvsEngine;
@@ -25,7 +27,6 @@ fn rand_generate(size: usize) -> String {
     let s: String = rand::thread_rng()
         .sample_iter(&Alphanumeric)
         .take(size)
-        .map(char::from)
         .collect();
     s
 }
@@ -79,5 +80,46 @@ fn sled_read(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, kvs_write, sled_write, kvs_read, sled_read);
+fn kvs_concurrent_read(c: &mut Criterion) {
+    let temp_dir = TempDir::new().unwrap();
+    let kvs = KvStore::open(temp_dir.path()).unwrap();
+    for (key, value) in RANDOM_KEYS.iter().zip(RANDOM_VALUES.iter()) {
+        kvs.set(key.to_owned(), value.to_owned()).unwrap();
+    }
+
+    let mut group = c.benchmark_group("kvs concurrent read");
+    for threads in [1, 2, 4, 8] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(threads),
+            &threads,
+            |b, &threads| {
+                b.iter(|| {
+                    let handles: Vec<_> = (0..threads)
+                        .map(|_| {
+                            let kvs = kvs.clone();
+                            thread::spawn(move || {
+                                for key in RANDOM_KEYS.iter() {
+                                    let _ = kvs.get(key.to_owned());
+                                }
+                            })
+                        })
+                        .collect();
+                    for handle in handles {
+                        handle.join().unwrap();
+                    }
+                });
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    kvs_write,
+    sled_write,
+    kvs_read,
+    sled_read,
+    kvs_concurrent_read
+);
 criterion_main!(benches);
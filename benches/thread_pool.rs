@@ -0,0 +1,97 @@
+use criterion::{criterion_group, criterion_main};
+use criterion::{BenchmarkId, Criterion};
+use crossbeam_utils::sync::WaitGroup;
+use std::process::Stdio;
+use std::thread;
+use tempfile::TempDir;
+
+use kvs::client::KvsClient;
+use kvs::thread_pool::*;
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use common::{free_addr, kvs_server, Server};
+
+/// `--pool` names of the pools to compare
+#[cfg(not(feature = "rayon"))]
+const POOLS: &[&str] = &["shared-queue", "work-stealing"];
+#[cfg(feature = "rayon")]
+const POOLS: &[&str] = &["shared-queue", "work-stealing", "rayon"];
+
+/// `--threads` of the server, fewer than the clients so that the pools
+/// have requests queued to schedule
+const THREADS: u32 = 4;
+const CLIENTS: usize = 32;
+const REQUESTS: usize = 100;
+
+fn spawn_jobs<P: ThreadPool>(pool: &P, jobs: usize) {
+    let wg = WaitGroup::new();
+    for _ in 0..jobs {
+        let wg = wg.clone();
+        pool.spawn(move || drop(wg));
+    }
+    wg.wait();
+}
+
+fn pool_spawn(c: &mut Criterion) {
+    let mut group = c.benchmark_group("thread pool spawn");
+    let shared_queue = SharedQueueThreadPool::new(4).unwrap();
+    group.bench_function("shared-queue", |b| {
+        b.iter(|| spawn_jobs(&shared_queue, 1000))
+    });
+    let work_stealing = WorkStealingThreadPool::new(4).unwrap();
+    group.bench_function("work-stealing", |b| {
+        b.iter(|| spawn_jobs(&work_stealing, 1000))
+    });
+    #[cfg(feature = "rayon")]
+    {
+        let rayon = RayonThreadPool::new(4).unwrap();
+        group.bench_function("rayon", |b| b.iter(|| spawn_jobs(&rayon, 1000)));
+    }
+    group.finish();
+}
+
+/// `CLIENTS` concurrent clients each setting then getting `REQUESTS` keys,
+/// served by `THREADS` threads.
+fn server_workload(c: &mut Criterion) {
+    let mut group = c.benchmark_group("server workload");
+    group.sample_size(10);
+
+    for pool in POOLS {
+        let temp_dir = TempDir::new().unwrap();
+        let addr = free_addr();
+        let _server = Server::spawn(
+            kvs_server(&temp_dir, &addr)
+                .args(["--engine", "kvs", "--pool", pool])
+                .args(["--threads", &THREADS.to_string()])
+                .stderr(Stdio::null()),
+            &addr,
+        );
+
+        group.bench_with_input(BenchmarkId::from_parameter(pool), &addr, |b, addr| {
+            b.iter(|| {
+                let clients: Vec<_> = (0..CLIENTS)
+                    .map(|client| {
+                        let addr = addr.clone();
+                        thread::spawn(move || {
+                            let mut kvs = KvsClient::connect(addr.as_str()).unwrap();
+                            for i in 0..REQUESTS {
+                                let key = format!("key{}-{}", client, i);
+                                kvs.set(key.clone(), "value".to_owned()).unwrap();
+                                assert!(kvs.get(key).unwrap().is_some());
+                            }
+                        })
+                    })
+                    .collect();
+                for client in clients {
+                    client.join().unwrap();
+                }
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, pool_spawn, server_workload);
+criterion_main!(benches);
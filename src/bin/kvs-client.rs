@@ -1,14 +1,16 @@
-use std::io::{Read, Write};
-use std::net::TcpStream;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Duration;
 
 use clap::{AppSettings, Parser, Subcommand};
+use kvs::client::KvsClient;
 use kvs::kvs::EngineError;
-use kvs::Result;
+use kvs::{Result, WriteBatch};
 
 #[derive(Parser)]
 #[clap(name = "kvs-client", author, version)]
 #[clap(about = "A KvStore CLI Client", long_about = None)]
-struct KvsClient {
+struct KvsClientCli {
     #[clap(subcommand)]
     command: Commands,
 }
@@ -20,8 +22,14 @@ enum Commands {
     Set {
         #[clap(required = true)]
         key: String,
-        #[clap(required = true)]
-        value: String,
+        #[clap(required_unless_present = "value-file")]
+        value: Option<String>,
+        /// Reads the value, as raw bytes, from a file instead
+        #[clap(long, parse(from_os_str), conflicts_with = "value")]
+        value_file: Option<PathBuf>,
+        /// Seconds after which the key expires
+        #[clap(long)]
+        ttl: Option<u64>,
         #[clap(long)]
         addr: Option<String>,
     },
@@ -30,6 +38,9 @@ enum Commands {
     Get {
         #[clap(required = true)]
         key: String,
+        /// Writes the value, as raw bytes, to a file instead of stdout
+        #[clap(long, parse(from_os_str))]
+        value_file: Option<PathBuf>,
         #[clap(long)]
         addr: Option<String>,
     },
@@ -42,9 +53,44 @@ enum Commands {
         #[clap(long)]
         addr: Option<String>,
     },
+    /// Lists the keys from START up to END, excluded, with their values
+    Scan {
+        start: Option<String>,
+        end: Option<String>,
+        /// Lists the keys starting with PREFIX instead
+        #[clap(long, conflicts_with_all = &["start", "end"])]
+        prefix: Option<String>,
+        #[clap(long)]
+        addr: Option<String>,
+    },
+    /// Sets KEY to NEW, or removes it without NEW, only if its value is
+    /// EXPECTED, or if it doesn't exist without EXPECTED
+    #[clap(setting(AppSettings::ArgRequiredElseHelp))]
+    Cas {
+        #[clap(required = true)]
+        key: String,
+        #[clap(long)]
+        expected: Option<String>,
+        #[clap(long)]
+        new: Option<String>,
+        #[clap(long)]
+        addr: Option<String>,
+    },
+    /// Applies sets and removes all at once, e.g. `set k1 v1 rm k2`
+    Batch {
+        #[clap(required = true)]
+        ops: Vec<String>,
+        #[clap(long)]
+        addr: Option<String>,
+    },
+    /// Prints the statistics of the server, thread pool metrics included
+    Stats {
+        #[clap(long)]
+        addr: Option<String>,
+    },
 }
 fn main() {
-    let args = KvsClient::parse();
+    let args = KvsClientCli::parse();
 
     if let Err(e) = run(args) {
         eprintln!("{}", e);
@@ -52,64 +98,108 @@ fn main() {
     }
 }
 
-fn run(args: KvsClient) -> Result<()> {
+fn run(args: KvsClientCli) -> Result<()> {
     match args.command {
-        Commands::Set { key, value, addr } => {
-            let ip_port = addr.unwrap_or("127.0.0.1:4000".to_owned());
-            let mut stream = TcpStream::connect(ip_port)?;
-            stream.write(&['s' as u8])?;
-            stream.write(&(key.len() as u32).to_be_bytes())?;
-            stream.write(key.as_bytes())?;
-            stream.write(&(value.len() as u32).to_be_bytes())?;
-            stream.write(value.as_bytes())?;
-            stream.flush()?;
-
-            let mut status = [0; 1];
-            stream.read_exact(&mut status)?;
+        Commands::Set {
+            key,
+            value,
+            value_file,
+            ttl,
+            addr,
+        } => {
+            let value = match value_file {
+                Some(path) => std::fs::read(path)?,
+                None => value.unwrap_or_default().into_bytes(),
+            };
+            let mut client = connect(addr)?;
+            match ttl {
+                Some(ttl) => {
+                    client.set_bytes_with_ttl(key.into_bytes(), value, Duration::from_secs(ttl))?
+                }
+                None => client.set_bytes(key.into_bytes(), value)?,
+            }
         }
-        Commands::Get { key, addr } => {
-            let ip_port = addr.unwrap_or("127.0.0.1:4000".to_owned());
-            let mut stream = TcpStream::connect(ip_port)?;
-            stream.write(&['g' as u8])?;
-            stream.write(&(key.len() as u32).to_be_bytes())?;
-            stream.write(key.as_bytes())?;
-            stream.flush()?;
-
-            let mut status = [0; 1];
-            stream.read_exact(&mut status)?;
-
-            let mut bytes = [0; 4];
-            stream.read_exact(&mut bytes)?;
-            let value_size = u32::from_be_bytes(bytes) as usize;
-
-            let mut value = vec![0; value_size];
-            stream.read_exact(&mut value)?;
-            let value = String::from_utf8_lossy(&value).to_string();
-            println!("{}", value);
+        Commands::Get {
+            key,
+            value_file,
+            addr,
+        } => match connect(addr)?.get_bytes(key.into_bytes())? {
+            Some(value) => match value_file {
+                Some(path) => std::fs::write(path, value)?,
+                None => {
+                    let mut stdout = std::io::stdout();
+                    stdout.write_all(&value)?;
+                    stdout.write_all(b"\n")?;
+                }
+            },
+            None => println!("Key not found"),
+        },
+        Commands::Rm { key, addr } => connect(addr)?.remove(key)?,
+        Commands::Scan {
+            start,
+            end,
+            prefix,
+            addr,
+        } => {
+            let mut client = connect(addr)?;
+            let pairs = match prefix {
+                Some(prefix) => client.scan_prefix_bytes(prefix.into_bytes())?,
+                None => client.scan_bytes(
+                    start.unwrap_or_default().into_bytes(),
+                    end.map(String::into_bytes),
+                )?,
+            };
+            for (key, value) in pairs {
+                println!(
+                    "{}\t{}",
+                    String::from_utf8_lossy(&key),
+                    String::from_utf8_lossy(&value)
+                );
+            }
         }
-        Commands::Rm { key, addr } => {
-            let ip_port = addr.unwrap_or("127.0.0.1:4000".to_owned());
-            let mut stream = TcpStream::connect(ip_port)?;
-            stream.write(&['r' as u8])?;
-            stream.write(&(key.len() as u32).to_be_bytes())?;
-            stream.write(key.as_bytes())?;
-            stream.flush()?;
-
-            let mut status = [0; 1];
-            stream.read_exact(&mut status)?;
-
-            if status[0] != 0x00 {
-                let mut bytes = [0; 4];
-                stream.read_exact(&mut bytes)?;
-                let value_size = u32::from_be_bytes(bytes) as usize;
+        Commands::Cas {
+            key,
+            expected,
+            new,
+            addr,
+        } => {
+            if !connect(addr)?.compare_and_swap(key.clone(), expected, new)? {
+                return Err(EngineError::Unknown(anyhow::anyhow!(
+                    "Value mismatch, `{}` was not swapped",
+                    key
+                )));
+            }
+        }
+        Commands::Batch { ops, addr } => {
+            let mut batch = WriteBatch::new();
+            let mut ops = ops.iter();
+            while let Some(op) = ops.next() {
+                match (op.as_str(), ops.next()) {
+                    ("set", Some(key)) => {
+                        let value = ops.next().ok_or_else(|| invalid_batch(op))?;
+                        batch.set(key.as_str(), value.as_str());
+                    }
+                    ("rm", Some(key)) => batch.remove(key.as_str()),
+                    _ => return Err(invalid_batch(op)),
+                }
+            }
 
-                let mut value = vec![0; value_size];
-                stream.read_exact(&mut value)?;
-                let _ = String::from_utf8_lossy(&value).to_string();
-                return Err(EngineError::NotFound(key));
+            connect(addr)?.apply_batch(batch)?;
+        }
+        Commands::Stats { addr } => {
+            for (name, value) in connect(addr)?.stats()? {
+                println!("{}\t{}", name, value);
             }
         }
     }
 
     Ok(())
 }
+
+fn connect(addr: Option<String>) -> Result<KvsClient> {
+    KvsClient::connect(addr.as_deref().unwrap_or("127.0.0.1:4000"))
+}
+
+fn invalid_batch(op: &str) -> EngineError {
+    EngineError::Unknown(anyhow::anyhow!("invalid batch operation `{}`", op))
+}
@@ -1,21 +1,35 @@
 use kvs::engine::KvsEngine;
 use kvs::kvs::EngineError;
+use kvs::protocol::{self, ErrorCode, ErrorReply, Request};
+use kvs::resp;
+use kvs::server::{self, execute};
 use kvs::thread_pool::*;
-use kvs::{KvStore, SledEngine};
+use kvs::{KvStore, SledEngine, SyncPolicy};
 
-use anyhow::{anyhow, bail, Result};
+use anyhow::{anyhow, Result};
 use clap::{ArgEnum, Parser};
+use crossbeam::channel::{self, Sender};
 use log::debug;
 use log::error;
+use log::info;
 use log::warn;
 
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+
+use std::collections::HashMap;
 use std::env::current_dir;
 use std::fmt;
 use std::fs;
-use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::net::{self, TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
 use std::process::exit;
 use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ArgEnum, Debug)]
 enum Engine {
@@ -47,6 +61,45 @@ impl fmt::Display for Engine {
     }
 }
 
+/// Protocol spoken with the clients
+#[derive(Copy, Clone, PartialEq, Eq, ArgEnum, Debug)]
+enum Protocol {
+    Kvs,
+    Resp,
+}
+
+/// Thread pool serving the requests of the blocking server
+#[derive(Copy, Clone, PartialEq, Eq, ArgEnum, Debug)]
+enum Pool {
+    SharedQueue,
+    WorkStealing,
+    Naive,
+    #[cfg(feature = "rayon")]
+    Rayon,
+}
+
+/// What the shared-queue pool does with a new request once its queue is
+/// full
+#[derive(Copy, Clone, PartialEq, Eq, ArgEnum, Debug)]
+enum QueueFull {
+    /// Stop reading requests until there is place
+    Block,
+    /// Tell the client of the new request the server is busy
+    Reject,
+    /// Tell the client of the oldest queued request the server is busy
+    DropOldest,
+}
+
+impl From<QueueFull> for QueuePolicy {
+    fn from(full: QueueFull) -> QueuePolicy {
+        match full {
+            QueueFull::Block => QueuePolicy::Block,
+            QueueFull::Reject => QueuePolicy::Reject,
+            QueueFull::DropOldest => QueuePolicy::DropOldest,
+        }
+    }
+}
+
 #[derive(Parser)]
 #[clap(name = "kvs-server", author, version)]
 #[clap(about = "A KvStore CLI Server", long_about = None)]
@@ -56,12 +109,54 @@ struct KvsServer {
 
     #[clap(long, arg_enum)]
     engine: Option<Engine>,
+
+    /// Protocol spoken with the clients, `resp` for Redis clients
+    #[clap(long, arg_enum, default_value = "kvs")]
+    protocol: Protocol,
+
+    /// Thread pool serving the requests, idle connections hold no thread.
+    /// The pools but shared-queue report no pool metrics to stats requests.
+    #[clap(long, arg_enum, default_value = "shared-queue")]
+    pool: Pool,
+
+    /// Threads serving the requests, the number of CPUs by default
+    #[clap(long)]
+    threads: Option<u32>,
+
+    /// Threads the shared-queue pool may start while requests wait for one,
+    /// 64 or `--threads` if more by default
+    #[clap(long)]
+    max_threads: Option<u32>,
+
+    /// Requests queued by the shared-queue pool waiting for a thread,
+    /// unbounded by default
+    #[clap(long)]
+    queue_capacity: Option<usize>,
+
+    /// What the shared-queue pool does with a new request once its queue
+    /// is full
+    #[clap(long, arg_enum, default_value = "block")]
+    queue_policy: QueueFull,
+
+    /// Serve connections with the async server on a tokio runtime
+    #[cfg(feature = "async")]
+    #[clap(long = "async")]
+    tokio: bool,
+
+    /// Also serve the HTTP/JSON gateway on this address
+    #[cfg(feature = "http")]
+    #[clap(long)]
+    http: Option<String>,
+
+    /// Seconds given to the open connections to finish on shutdown
+    #[clap(long, default_value = "10")]
+    drain_timeout: u64,
 }
 
 fn main() -> Result<()> {
-    env_logger::init();
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
     let args = KvsServer::parse();
-    let addr = args.addr.unwrap_or("127.0.0.1:4000".to_owned());
+    let addr = args.addr.clone().unwrap_or("127.0.0.1:4000".to_owned());
 
     // check if engine exists
     let engine = if let Some(engine) = args.engine {
@@ -76,162 +171,637 @@ fn main() -> Result<()> {
         current_engine()?.expect("please specify engine")
     };
 
-    debug!("kvs-server version: {:?}", env!("CARGO_PKG_VERSION"));
-    debug!("listening {:?} with storage engine {:?}", addr, engine);
+    info!("kvs-server version: {:?}", env!("CARGO_PKG_VERSION"));
+    info!("listening {:?} with storage engine {:?}", addr, engine);
 
     fs::write(current_dir()?.join("engine"), format!("{}", engine))?;
 
+    #[cfg(feature = "async")]
+    if args.tokio && args.protocol != Protocol::Kvs {
+        return Err(anyhow!("the async server only speaks the kvs protocol"));
+    }
+
+    if args.queue_capacity.is_some() && args.pool != Pool::SharedQueue {
+        return Err(anyhow!("--queue-capacity needs the shared-queue pool"));
+    }
+    if args.max_threads.is_some() && args.pool != Pool::SharedQueue {
+        return Err(anyhow!("--max-threads needs the shared-queue pool"));
+    }
+
     let tcp_listener = TcpListener::bind(addr)?;
+    let dir = current_dir()?;
+
+    // writes acknowledged to the clients survive a killed server, sled
+    // needs a flush of each of them for that
+    match engine {
+        Engine::Kvs => run(KvStore::open(dir)?, tcp_listener, &args),
+        Engine::Sled => run(
+            SledEngine::open_with(dir, SyncPolicy::Always)?,
+            tcp_listener,
+            &args,
+        ),
+    }
+}
+
+/// Serve the clients with clones of the engine, opened once, until
+/// SIGINT or SIGTERM. The engine is synced once the clients are served.
+fn run<E: KvsEngine>(engine: E, listener: TcpListener, args: &KvsServer) -> Result<()> {
+    info!("opened storage engine");
+
+    let shutdown = Shutdown::default();
+    handle_signals(shutdown.clone())?;
+
+    #[cfg(feature = "http")]
+    let gateway = match &args.http {
+        Some(addr) => Some(start_gateway(
+            engine.clone(),
+            addr,
+            pool_threads(args),
+            &shutdown,
+        )?),
+        None => None,
+    };
+
+    #[cfg(feature = "async")]
+    let served = if args.tokio {
+        let drain_timeout = Duration::from_secs(args.drain_timeout);
+        serve_async(engine.clone(), listener, &shutdown, drain_timeout)
+    } else {
+        serve_with_pool(engine.clone(), listener, args, &shutdown)
+    };
+    #[cfg(not(feature = "async"))]
+    let served = serve_with_pool(engine.clone(), listener, args, &shutdown);
+
+    // the gateway stops too if serving failed
+    shutdown.trigger();
+    #[cfg(feature = "http")]
+    if let Some(gateway) = gateway {
+        if gateway.join().is_err() {
+            error!("http gateway panicked");
+        }
+    }
+
+    engine.sync()?;
+    info!("synced storage engine");
+    served
+}
+
+/// `serve` with the thread pool picked by `--pool`.
+fn serve_with_pool<E: KvsEngine>(
+    engine: E,
+    listener: TcpListener,
+    args: &KvsServer,
+    shutdown: &Shutdown,
+) -> Result<()> {
+    let threads = pool_threads(args);
+    info!(
+        "serving requests with the {:?} thread pool of {} threads",
+        args.pool, threads
+    );
+    match args.pool {
+        Pool::SharedQueue => {
+            let max = args.max_threads.unwrap_or(DEFAULT_MAX_THREADS.max(threads));
+            let mut options = PoolOptions::new()
+                .policy(args.queue_policy.into())
+                .max_threads(max);
+            if let Some(capacity) = args.queue_capacity {
+                options = options.capacity(capacity);
+            }
+            let pool = SharedQueueThreadPool::with_options(threads, options)?;
+            let monitor = Some(pool.monitor());
+            serve(engine, listener, pool, monitor, args, shutdown)
+        }
+        Pool::WorkStealing => {
+            let pool = WorkStealingThreadPool::new(threads)?;
+            serve(engine, listener, pool, None, args, shutdown)
+        }
+        Pool::Naive => {
+            let pool = NaiveThreadPool::new(threads)?;
+            serve(engine, listener, pool, None, args, shutdown)
+        }
+        #[cfg(feature = "rayon")]
+        Pool::Rayon => {
+            let pool = RayonThreadPool::new(threads)?;
+            serve(engine, listener, pool, None, args, shutdown)
+        }
+    }
+}
+
+/// Default `--max-threads`, a request stalled mid-frame holds a thread up
+/// to `REQUEST_TIMEOUT`
+const DEFAULT_MAX_THREADS: u32 = 64;
+
+/// How long a request may take to arrive once its first bytes are read,
+/// and its reply to be sent
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Pause after a failed accept, running out of file descriptors lasts until
+/// some connections are closed
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// `--threads`, or the number of CPUs
+fn pool_threads(args: &KvsServer) -> u32 {
+    args.threads
+        .unwrap_or_else(|| thread::available_parallelism().map_or(4, |cpus| cpus.get() as u32))
+}
+
+/// Accept connections and wait for their requests until shutdown. Each
+/// connection with requests to read is handed to the pool with a clone of
+/// the engine, and waited for again once its requests are replied. A
+/// failed accept, out of file descriptors say, pauses accepting for
+/// `ACCEPT_BACKOFF`.
+///
+/// Stats requests are replied with the metrics of `monitor`, pools without
+/// one only reply the version. On shutdown idle connections are closed and
+/// the others stop reading requests, those still open after
+/// `--drain-timeout` are closed.
+fn serve<E: KvsEngine, P: ThreadPool>(
+    engine: E,
+    listener: TcpListener,
+    pool: P,
+    monitor: Option<PoolMonitor>,
+    args: &KvsServer,
+    shutdown: &Shutdown,
+) -> Result<()> {
+    let protocol = args.protocol;
+    let drain_timeout = Duration::from_secs(args.drain_timeout);
+    listener.set_nonblocking(true)?;
+    let (waker, wakeups) = UnixStream::pair()?;
+    waker.set_nonblocking(true)?;
+    wakeups.set_nonblocking(true)?;
+    let waker = Arc::new(waker);
+    let shutdown_waker = Arc::clone(&waker);
+    shutdown.on_trigger(move || wake(&shutdown_waker))?;
+
+    let connections = Arc::new(Connections::default());
+    let busy = BusyReplier::spawn()?;
+    // connections handed back by the pool once their requests are replied
+    let (served_tx, served_rx) = channel::unbounded::<Conn>();
+    let mut idle = Vec::new();
+    let mut accept_after = None;
+    while !shutdown.is_triggered() {
+        let mut fds = vec![
+            readable(listener.as_raw_fd()),
+            readable(wakeups.as_raw_fd()),
+        ];
+        fds.extend(idle.iter().map(|conn: &Conn| readable(conn.as_raw_fd())));
+        let backoff = accept_after
+            .map(|after: Instant| after.saturating_duration_since(Instant::now()))
+            .filter(|backoff| !backoff.is_zero());
+        if backoff.is_some() {
+            fds[0].events = 0;
+        }
+        if let Err(e) = poll(&mut fds, backoff) {
+            if e.kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e.into());
+        }
+
+        while (&wakeups).read(&mut [0; 64]).is_ok() {}
+        // appended after the polled connections, their index is kept
+        idle.extend(served_rx.try_iter());
+        for i in (0..fds.len() - 2).rev() {
+            if fds[i + 2].revents == 0 {
+                continue;
+            }
+            let queued = Queued {
+                conn: Some(idle.swap_remove(i)),
+                protocol,
+                busy: busy.clone(),
+            };
+            let engine = engine.clone();
+            let monitor = monitor.clone();
+            let served = served_tx.clone();
+            let waker = Arc::clone(&waker);
+            pool.spawn(move || {
+                debug!("spawn job in thread: {:?}", std::thread::current().id());
+                let mut conn = queued.take();
+                let open = match protocol {
+                    Protocol::Kvs => serve_requests(&engine, monitor.as_ref(), &mut conn),
+                    Protocol::Resp => serve_commands(&engine, &mut conn),
+                };
+                match open {
+                    Ok(true) => {
+                        if served.send(conn).is_ok() {
+                            wake(&waker);
+                        }
+                    }
+                    Ok(false) => {}
+                    Err(e) => error!("serving client failed: {:?}", e),
+                }
+            })
+        }
 
-    let thread_pool = SharedQueueThreadPool::new(4)?;
-
-    for stream in tcp_listener.incoming() {
-        match stream {
-            Ok(stream) => match engine {
-                Engine::Kvs => thread_pool.spawn(|| {
-                    debug!("spawn job in thread: {:?}", std::thread::current().id());
-                    handle_client(
-                        KvStore::open(std::env::current_dir().unwrap()).unwrap(),
-                        stream,
-                    )
-                    .unwrap()
-                }),
-                Engine::Sled => thread_pool.spawn(|| {
-                    handle_client(
-                        SledEngine::open(std::env::current_dir().unwrap()).unwrap(),
-                        stream,
-                    )
-                    .unwrap()
-                }),
-            },
-            Err(err) => {
-                if err.kind() != std::io::ErrorKind::WouldBlock {
+        if fds[0].revents == 0 {
+            continue;
+        }
+        accept_after = None;
+        loop {
+            let accepted = listener
+                .accept()
+                .and_then(|(stream, _)| Conn::new(stream, &connections));
+            match accepted {
+                Ok(conn) => idle.push(conn),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("accepting connection failed: {}", e);
+                    accept_after = Some(Instant::now() + ACCEPT_BACKOFF);
                     break;
                 }
             }
         }
+    }
+    info!("stopped accepting connections");
+
+    drop(idle);
+    // connections handed back from now on are closed
+    drop(served_rx);
+    connections.close_reads();
+    if !connections.wait_closed(drain_timeout) {
+        warn!(
+            "connections still open after {:?}, closing them",
+            drain_timeout
+        );
+        connections.close();
+    }
+    pool.shutdown();
+
+    Ok(())
+}
+
+/// Wake the loop of `serve` up.
+fn wake(waker: &UnixStream) {
+    // a wake up already pending does as well
+    let _ = (&*waker).write(&[0]);
+}
 
-        std::thread::sleep(std::time::Duration::from_millis(10));
+fn readable(fd: RawFd) -> libc::pollfd {
+    libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
     }
+}
 
+/// Wait for one of `fds` to be readable or closed, at most `timeout` if
+/// any.
+fn poll(fds: &mut [libc::pollfd], timeout: Option<Duration>) -> std::io::Result<()> {
+    let timeout = timeout.map_or(-1, |timeout| {
+        timeout.as_millis().clamp(1, i32::MAX as u128) as i32
+    });
+    // SAFETY: `fds` is a valid slice of pollfd for the length given
+    let ready = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+    if ready < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
     Ok(())
 }
 
-fn current_engine() -> Result<Option<Engine>> {
-    let engine = current_dir()?.join("engine");
-    if !engine.exists() {
-        return Ok(None);
+/// Serve connections with the async server on a new tokio runtime.
+#[cfg(feature = "async")]
+fn serve_async<E: KvsEngine>(
+    engine: E,
+    listener: TcpListener,
+    shutdown: &Shutdown,
+    drain_timeout: Duration,
+) -> Result<()> {
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    shutdown.on_trigger(move || {
+        let _ = tx.send(());
+    })?;
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        listener.set_nonblocking(true)?;
+        let listener = tokio::net::TcpListener::from_std(listener)?;
+        let stopped = async {
+            let _ = rx.await;
+        };
+        kvs::server::KvsServer::new(engine)
+            .drain_timeout(drain_timeout)
+            .run_until(listener, stopped)
+            .await?;
+        Ok(())
+    })
+}
+
+/// Serve the HTTP gateway on its own thread until shutdown.
+#[cfg(feature = "http")]
+fn start_gateway<E: KvsEngine>(
+    engine: E,
+    addr: &str,
+    threads: u32,
+    shutdown: &Shutdown,
+) -> Result<thread::JoinHandle<()>> {
+    let gateway = kvs::http::HttpGateway::new(engine, TcpListener::bind(addr)?)?;
+    info!("http gateway listening {:?}", addr);
+
+    let stopper = gateway.stopper();
+    shutdown.on_trigger(move || stopper.stop())?;
+    let pool = SharedQueueThreadPool::new(threads)?;
+    Ok(thread::spawn(move || gateway.run(pool)))
+}
+
+/// Trigger `shutdown` on the first SIGINT or SIGTERM, exit right away on
+/// the next one.
+fn handle_signals(shutdown: Shutdown) -> Result<()> {
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+    thread::Builder::new()
+        .name("kvs-signals".to_owned())
+        .spawn(move || {
+            for signal in signals.forever() {
+                if shutdown.is_triggered() {
+                    warn!("received signal {} again, exiting", signal);
+                    exit(1);
+                }
+                info!("received signal {}, shutting down", signal);
+                shutdown.trigger();
+            }
+        })?;
+    Ok(())
+}
+
+/// Shutdown of the server, triggered once
+#[derive(Clone, Default)]
+struct Shutdown(Arc<(Mutex<bool>, Condvar)>);
+
+impl Shutdown {
+    fn trigger(&self) {
+        let (triggered, cvar) = &*self.0;
+        *triggered.lock().unwrap() = true;
+        cvar.notify_all();
     }
 
-    match fs::read_to_string(engine)?.parse() {
-        Ok(engine) => Ok(Some(engine)),
-        Err(e) => {
-            warn!("The content of engine file is invalid: {}", e);
-            Ok(None)
+    fn is_triggered(&self) -> bool {
+        *self.0 .0.lock().unwrap()
+    }
+
+    /// Run `f` on a thread of its own once triggered.
+    fn on_trigger(&self, f: impl FnOnce() + Send + 'static) -> Result<()> {
+        let shutdown = self.clone();
+        thread::Builder::new()
+            .name("kvs-shutdown".to_owned())
+            .spawn(move || {
+                let (triggered, cvar) = &*shutdown.0;
+                let guard = cvar
+                    .wait_while(triggered.lock().unwrap(), |triggered| !*triggered)
+                    .unwrap();
+                drop(guard);
+                f();
+            })?;
+        Ok(())
+    }
+}
+
+/// Open connections of the blocking server
+#[derive(Default)]
+struct Connections {
+    // next id and open streams
+    open: Mutex<(u64, HashMap<u64, TcpStream>)>,
+    closed: Condvar,
+}
+
+/// Open connection, untracked on drop
+struct TrackedConnection {
+    connections: Arc<Connections>,
+    id: u64,
+}
+
+impl Connections {
+    fn track(self: &Arc<Self>, stream: &TcpStream) -> std::io::Result<TrackedConnection> {
+        let mut open = self.open.lock().unwrap();
+        let id = open.0;
+        open.0 += 1;
+        open.1.insert(id, stream.try_clone()?);
+        Ok(TrackedConnection {
+            connections: Arc::clone(self),
+            id,
+        })
+    }
+
+    /// Stop reading requests, the connections close once the requests
+    /// already read are replied.
+    fn close_reads(&self) {
+        for stream in self.open.lock().unwrap().1.values() {
+            let _ = stream.shutdown(net::Shutdown::Read);
+        }
+    }
+
+    fn close(&self) {
+        for stream in self.open.lock().unwrap().1.values() {
+            let _ = stream.shutdown(net::Shutdown::Both);
         }
     }
+
+    /// Wait for the connections to close, false on timeout.
+    fn wait_closed(&self, timeout: Duration) -> bool {
+        let open = self.open.lock().unwrap();
+        let (open, _) = self
+            .closed
+            .wait_timeout_while(open, timeout, |open| !open.1.is_empty())
+            .unwrap();
+        open.1.is_empty()
+    }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ArgEnum, Debug)]
-enum Method {
-    Set,
-    Get,
-    Remove,
-}
-
-/// The protocol is simple:
-/// we use first byte to indicate method:
-/// 's' 0x73 -> `Set`
-/// 'g' 0x67 -> `Get`
-/// 'r' 0x72 -> `Remove`
-/// and 4 bytes to indicate key size, followed by key,
-/// and 4 bytes to indicate value size(if value exist), followed by value
-/// return status code:
-/// 0x00 -> success
-/// 0x01 -> failed,
-/// followed by the returned value size and value it self
-fn handle_client<T: KvsEngine>(engine: T, mut stream: TcpStream) -> Result<()> {
-    let mut bytes = [0; 1];
-    stream.read_exact(&mut bytes)?;
-
-    let method = bytes[0] as char;
-
-    let method = match method {
-        's' => Method::Set,
-        'g' => Method::Get,
-        'r' => Method::Remove,
-        _ => bail!("Invalid method"),
-    };
+impl Drop for TrackedConnection {
+    fn drop(&mut self) {
+        let mut open = self.connections.open.lock().unwrap();
+        open.1.remove(&self.id);
+        self.connections.closed.notify_all();
+    }
+}
 
-    debug!("method: {:?}", method);
+/// Client connection of the blocking server
+struct Conn {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+    // set once the kvs protocol handshake is done
+    version: Option<u32>,
+    _connection: TrackedConnection,
+}
 
-    let mut bytes = [0; 4];
-    stream.read_exact(&mut bytes)?;
-    let key_size = u32::from_be_bytes(bytes) as usize;
+impl Conn {
+    fn new(stream: TcpStream, connections: &Arc<Connections>) -> std::io::Result<Conn> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+        Ok(Conn {
+            _connection: connections.track(&stream)?,
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+            version: None,
+        })
+    }
 
-    let mut key = vec![0; key_size];
-    stream.read_exact(&mut key)?;
-    let key = String::from_utf8_lossy(&key).to_string();
+    fn as_raw_fd(&self) -> RawFd {
+        self.reader.get_ref().as_raw_fd()
+    }
+}
 
-    debug!("key_size: {:?}, key: {:?}", key_size, key);
+/// Connection waiting for a thread of the pool, told the server is busy
+/// if the pool drops it.
+struct Queued {
+    conn: Option<Conn>,
+    protocol: Protocol,
+    busy: BusyReplier,
+}
 
-    if method == Method::Get {
-        let value = match engine.get(key) {
-            Ok(value) => match value {
-                Some(value) => {
-                    stream.write(&0_u8.to_be_bytes())?;
-                    value.to_owned()
-                }
-                None => {
-                    stream.write(&1_u8.to_be_bytes())?;
-                    "Key not found".to_owned()
-                }
-            },
-            Err(e) => {
-                stream.write(&1_u8.to_be_bytes())?;
-                bail!("Command get failed: {:?}", e);
-            }
-        };
+impl Queued {
+    fn take(mut self) -> Conn {
+        self.conn.take().unwrap()
+    }
+}
 
-        stream.write(&(value.len() as u32).to_be_bytes())?;
-        stream.write(value.as_bytes())?;
-        stream.flush()?;
+impl Drop for Queued {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            warn!("server busy, rejecting request");
+            self.busy.reply(conn, self.protocol);
+        }
+    }
+}
 
-        return Ok(());
+/// Connections waiting for their busy reply, the next ones are closed
+/// without one
+const BUSY_BACKLOG: usize = 64;
+
+/// Thread telling the rejected connections the server is busy, away from
+/// the accept loop. It stops once the server is dropped.
+#[derive(Clone)]
+struct BusyReplier(Sender<(Conn, Protocol)>);
+
+impl BusyReplier {
+    fn spawn() -> Result<BusyReplier> {
+        let (tx, rx) = channel::bounded::<(Conn, Protocol)>(BUSY_BACKLOG);
+        thread::Builder::new()
+            .name("busy-replier".to_owned())
+            .spawn(move || {
+                for (conn, protocol) in rx {
+                    if let Err(e) = reply_busy(conn, protocol) {
+                        debug!("replying busy failed: {:?}", e);
+                    }
+                }
+            })?;
+        Ok(BusyReplier(tx))
     }
 
-    if matches!(method, Method::Remove) {
-        if let Err(e) = engine.remove(key) {
-            if matches!(e, EngineError::NotFound(_)) {
-                let value = "Key not found".to_owned();
+    fn reply(&self, conn: Conn, protocol: Protocol) {
+        if self.0.try_send((conn, protocol)).is_err() {
+            debug!("too many connections waiting for a busy reply, closing");
+        }
+    }
+}
 
-                stream.write(&1_u8.to_be_bytes())?;
-                stream.write(&(value.len() as u32).to_be_bytes())?;
-                stream.write(value.as_bytes())?;
+/// Tell a client the server is too busy to serve it, and close its
+/// connection. The client is given a second to handshake.
+fn reply_busy(mut conn: Conn, protocol: Protocol) -> Result<()> {
+    let stream = conn.writer.get_ref();
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(1)))?;
+    match protocol {
+        Protocol::Kvs => {
+            if conn.version.is_none() {
+                protocol::accept_handshake(&mut conn.reader, &mut conn.writer)?;
             }
+            let busy = ErrorReply::new(ErrorCode::Busy, "server busy");
+            protocol::write_reply(&mut conn.writer, 0, &Err(busy))?;
         }
+        Protocol::Resp => {
+            resp::write_value(
+                &mut conn.writer,
+                &resp::Value::Error("ERR server busy".to_owned()),
+            )?;
+        }
+    }
+    conn.writer.flush()?;
+    Ok(())
+}
 
-        stream.write(&0_u8.to_be_bytes())?;
-        stream.flush()?;
-        return Ok(());
+fn current_engine() -> Result<Option<Engine>> {
+    let engine = current_dir()?.join("engine");
+    if !engine.exists() {
+        return Ok(None);
     }
 
-    let mut bytes = [0; 4];
-    stream.read_exact(&mut bytes)?;
-    let value_size = u32::from_be_bytes(bytes) as usize;
+    match fs::read_to_string(engine)?.parse() {
+        Ok(engine) => Ok(Some(engine)),
+        Err(e) => {
+            warn!("The content of engine file is invalid: {}", e);
+            Ok(None)
+        }
+    }
+}
 
-    let mut value = vec![0; value_size];
-    stream.read_exact(&mut value)?;
-    let value = String::from_utf8_lossy(&value).to_string();
+/// Serve the requests of a client read so far, see `kvs::protocol` for
+/// the wire format. Replies to pipelined requests are sent together once
+/// all of them are read. False once the client closed the connection.
+fn serve_requests<T: KvsEngine>(
+    engine: &T,
+    monitor: Option<&PoolMonitor>,
+    conn: &mut Conn,
+) -> Result<bool> {
+    if conn.version.is_none() {
+        let version = match protocol::accept_handshake(&mut conn.reader, &mut conn.writer) {
+            Ok(version) => version,
+            // closed without a handshake, checking that the server is up
+            Err(EngineError::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        debug!("protocol version: {}", version);
+        conn.version = Some(version);
+        if conn.reader.buffer().is_empty() {
+            return Ok(true);
+        }
+    }
 
-    debug!("value_size: {:?}, value: {:?}", value_size, value);
+    loop {
+        let (id, request) = match protocol::read_request(&mut conn.reader)? {
+            Some(request) => request,
+            None => return Ok(false),
+        };
+        let reply = request.and_then(|request| match request {
+            Request::Stats => Ok(server::stats(monitor.map(PoolMonitor::metrics).as_ref())),
+            request => execute(engine, request),
+        });
+        if let Err(e) = &reply {
+            debug!("request {} failed: {:?}", id, e);
+        }
+        protocol::write_reply(&mut conn.writer, id, &reply)?;
 
-    if matches!(method, Method::Set) {
-        engine.set(key, value)?;
-        stream.write(&0_u8.to_be_bytes())?;
-        stream.flush()?;
-        return Ok(());
+        if conn.reader.buffer().is_empty() {
+            conn.writer.flush()?;
+            return Ok(true);
+        }
     }
+}
 
-    bail!("handle client request failed");
+/// Serve the commands of a Redis client read so far, see `kvs::resp`.
+/// False once the client quit or closed the connection.
+fn serve_commands<T: KvsEngine>(engine: &T, conn: &mut Conn) -> Result<bool> {
+    loop {
+        let command = match resp::read_command(&mut conn.reader) {
+            Ok(Some(command)) => command,
+            Ok(None) => return Ok(false),
+            Err(e) if e.kind() == ErrorKind::InvalidData => {
+                let reply = resp::Value::Error(format!("ERR Protocol error: {}", e));
+                resp::write_value(&mut conn.writer, &reply)?;
+                conn.writer.flush()?;
+                return Ok(false);
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let quit = command[0].eq_ignore_ascii_case(b"quit");
+        resp::write_value(&mut conn.writer, &resp::execute(engine, command))?;
+        if quit {
+            conn.writer.flush()?;
+            return Ok(false);
+        }
+
+        if conn.reader.buffer().is_empty() {
+            conn.writer.flush()?;
+            return Ok(true);
+        }
+    }
 }
@@ -0,0 +1,192 @@
+use std::time::Duration;
+
+use tokio::io::{AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+use super::unexpected;
+use crate::engine::{EngineError, WriteBatch};
+use crate::protocol::{tokio_io, ErrorCode, Request, Response};
+use crate::Result;
+
+/// Async client of a kvs-server, running on a tokio runtime.
+///
+/// Unlike `KvsClient`, a failed connection is not opened again, the
+/// client should be connected again.
+///
+/// ```no_run
+/// # async fn run() -> kvs::Result<()> {
+/// use kvs::client::AsyncKvsClient;
+///
+/// let mut client = AsyncKvsClient::connect("127.0.0.1:4000").await?;
+/// client.set("key1".to_owned(), "value1".to_owned()).await?;
+/// assert_eq!(client.get("key1".to_owned()).await?, Some("value1".to_owned()));
+/// client.remove("key1".to_owned()).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct AsyncKvsClient {
+    reader: BufReader<OwnedReadHalf>,
+    writer: BufWriter<OwnedWriteHalf>,
+    version: u32,
+    next_id: u64,
+}
+
+impl AsyncKvsClient {
+    /// Connect to a kvs-server.
+    pub async fn connect(addr: impl ToSocketAddrs) -> Result<AsyncKvsClient> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+
+        let (reader, writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut writer = BufWriter::new(writer);
+        let version = tokio_io::handshake(&mut reader, &mut writer).await?;
+
+        Ok(AsyncKvsClient {
+            reader,
+            writer,
+            version,
+            next_id: 0,
+        })
+    }
+
+    /// Protocol version agreed with the server
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Get the value of a string key.
+    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes()).await? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Get the value of a key.
+    pub async fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match self.request(&Request::Get { key }).await? {
+            Response::Value(value) => Ok(value),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Set the value of a string key to a string.
+    pub async fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes()).await
+    }
+
+    /// Set the value of a key.
+    pub async fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.done(&Request::Set { key, value }).await
+    }
+
+    /// Set the value of a string key to a string for `ttl`.
+    pub async fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_bytes_with_ttl(key.into_bytes(), value.into_bytes(), ttl)
+            .await
+    }
+
+    /// Set the value of a key for `ttl`.
+    pub async fn set_bytes_with_ttl(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<()> {
+        self.done(&Request::SetWithTtl { key, value, ttl }).await
+    }
+
+    /// Remove a string key, `EngineError::NotFound` if it doesn't exist.
+    pub async fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes()).await
+    }
+
+    /// Remove a key, `EngineError::NotFound` if it doesn't exist.
+    pub async fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        match self.done(&Request::Remove { key: key.clone() }).await {
+            Err(EngineError::Server {
+                code: ErrorCode::NotFound,
+                ..
+            }) => Err(EngineError::not_found(&key)),
+            res => res,
+        }
+    }
+
+    /// Apply the operations of `batch` in order, all or nothing.
+    pub async fn apply_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        self.done(&Request::Batch(batch)).await
+    }
+
+    /// Set the value of `key` to `new`, or remove it if `new` is `None`,
+    /// only if its current value is `expected`. Returns whether the value
+    /// was swapped.
+    pub async fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        let request = Request::CompareAndSwap {
+            key: key.into_bytes(),
+            expected: expected.map(String::into_bytes),
+            new: new.map(String::into_bytes),
+        };
+        match self.request(&request).await? {
+            Response::Swapped(swapped) => Ok(swapped),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Pairs with keys from `start` up to `end`, excluded, in key order.
+    pub async fn scan_bytes(
+        &mut self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.pairs(&Request::Scan { start, end }).await
+    }
+
+    /// Pairs with keys starting with `prefix`, in key order.
+    pub async fn scan_prefix_bytes(&mut self, prefix: Vec<u8>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.pairs(&Request::ScanPrefix { prefix }).await
+    }
+
+    /// Statistics of the server as names and values, see `Request::Stats`.
+    pub async fn stats(&mut self) -> Result<Vec<(String, String)>> {
+        Ok(super::stats(self.pairs(&Request::Stats).await?))
+    }
+
+    async fn done(&mut self, request: &Request) -> Result<()> {
+        match self.request(request).await? {
+            Response::Done => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    async fn pairs(&mut self, request: &Request) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        match self.request(request).await? {
+            Response::Pairs(pairs) => Ok(pairs),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    async fn request(&mut self, request: &Request) -> Result<Response> {
+        let expected = self.next_id;
+        self.next_id += 1;
+        tokio_io::write_request(&mut self.writer, expected, request).await?;
+        self.writer.flush().await?;
+
+        let (id, reply) = tokio_io::read_reply(&mut self.reader).await?;
+        // a busy server replies to no request in particular
+        let busy = matches!(&reply, Err(e) if e.code == ErrorCode::Busy);
+        if id != expected && !busy {
+            return Err(EngineError::Protocol(format!(
+                "reply to request {} instead of {}",
+                id, expected
+            )));
+        }
+        Ok(reply?)
+    }
+}
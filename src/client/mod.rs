@@ -0,0 +1,417 @@
+//! Client side of the kvs-server protocol
+
+#[cfg(feature = "async")]
+mod async_client;
+
+#[cfg(feature = "async")]
+pub use async_client::AsyncKvsClient;
+
+use std::collections::VecDeque;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use log::warn;
+
+use crate::engine::{EngineError, WriteBatch};
+use crate::protocol::{self, ErrorCode, ReplyResult, Request, Response};
+use crate::Result;
+
+/// Options to connect a KvsClient with, see `KvsClient::connect_with`.
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use kvs::client::{ClientOptions, KvsClient};
+///
+/// let options = ClientOptions::new()
+///     .connect_timeout(Duration::from_secs(1))
+///     .timeout(Duration::from_secs(10))
+///     .retries(3);
+/// let mut client = KvsClient::connect_with("127.0.0.1:4000", options)?;
+/// # Ok::<(), kvs::engine::EngineError>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    connect_timeout: Option<Duration>,
+    timeout: Option<Duration>,
+    retries: u32,
+}
+
+impl Default for ClientOptions {
+    fn default() -> ClientOptions {
+        ClientOptions {
+            connect_timeout: Some(Duration::from_secs(5)),
+            timeout: None,
+            retries: 1,
+        }
+    }
+}
+
+impl ClientOptions {
+    /// Default options
+    pub fn new() -> ClientOptions {
+        ClientOptions::default()
+    }
+
+    /// Give up connecting to the server after `timeout`, 5 seconds by
+    /// default.
+    pub fn connect_timeout(mut self, timeout: Duration) -> ClientOptions {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Give up on a request after `timeout` without progress, no timeout by
+    /// default.
+    pub fn timeout(mut self, timeout: Duration) -> ClientOptions {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Number of times a request that can safely run twice is sent again on
+    /// a new connection when the connection fails, 1 by default.
+    pub fn retries(mut self, retries: u32) -> ClientOptions {
+        self.retries = retries;
+        self
+    }
+}
+
+/// Client of a kvs-server.
+///
+/// The connection is kept open between requests. A connection that fails
+/// is dropped and the next request opens a new one, gets, scans and sets
+/// are then sent again, while removes, batches and compare and swaps fail
+/// since the server may have applied them already.
+///
+/// ```no_run
+/// use kvs::client::KvsClient;
+///
+/// let mut client = KvsClient::connect("127.0.0.1:4000")?;
+/// client.set("key1".to_owned(), "value1".to_owned())?;
+/// assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+/// client.remove("key1".to_owned())?;
+/// # Ok::<(), kvs::engine::EngineError>(())
+/// ```
+pub struct KvsClient {
+    addrs: Vec<SocketAddr>,
+    options: ClientOptions,
+    conn: Option<Connection>,
+}
+
+impl KvsClient {
+    /// Connect to a kvs-server with the default options.
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<KvsClient> {
+        KvsClient::connect_with(addr, ClientOptions::default())
+    }
+
+    /// Connect to a kvs-server.
+    pub fn connect_with(addr: impl ToSocketAddrs, options: ClientOptions) -> Result<KvsClient> {
+        let mut client = KvsClient {
+            addrs: addr.to_socket_addrs()?.collect(),
+            options,
+            conn: None,
+        };
+        client.conn = Some(client.open()?);
+        Ok(client)
+    }
+
+    /// Get the value of a string key.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Get the value of a key.
+    pub fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match self.request(&Request::Get { key })? {
+            Response::Value(value) => Ok(value),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Set the value of a string key to a string.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Set the value of a key.
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.done(&Request::Set { key, value })
+    }
+
+    /// Set the value of a string key to a string for `ttl`.
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_bytes_with_ttl(key.into_bytes(), value.into_bytes(), ttl)
+    }
+
+    /// Set the value of a key for `ttl`.
+    pub fn set_bytes_with_ttl(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<()> {
+        self.done(&Request::SetWithTtl { key, value, ttl })
+    }
+
+    /// Remove a string key, `EngineError::NotFound` if it doesn't exist.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Remove a key, `EngineError::NotFound` if it doesn't exist.
+    pub fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        match self.done(&Request::Remove { key: key.clone() }) {
+            Err(EngineError::Server {
+                code: ErrorCode::NotFound,
+                ..
+            }) => Err(EngineError::not_found(&key)),
+            res => res,
+        }
+    }
+
+    /// Apply the operations of `batch` in order, all or nothing.
+    pub fn apply_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        self.done(&Request::Batch(batch))
+    }
+
+    /// Set the value of `key` to `new`, or remove it if `new` is `None`,
+    /// only if its current value is `expected`. Returns whether the value
+    /// was swapped.
+    pub fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        let request = Request::CompareAndSwap {
+            key: key.into_bytes(),
+            expected: expected.map(String::into_bytes),
+            new: new.map(String::into_bytes),
+        };
+        match self.request(&request)? {
+            Response::Swapped(swapped) => Ok(swapped),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Pairs with keys from `start` up to `end`, excluded, in key order.
+    pub fn scan_bytes(
+        &mut self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.pairs(&Request::Scan { start, end })
+    }
+
+    /// Pairs with keys starting with `prefix`, in key order.
+    pub fn scan_prefix_bytes(&mut self, prefix: Vec<u8>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.pairs(&Request::ScanPrefix { prefix })
+    }
+
+    /// Statistics of the server as names and values, see `Request::Stats`
+    /// and `kvs::server::stats`.
+    pub fn stats(&mut self) -> Result<Vec<(String, String)>> {
+        Ok(stats(self.pairs(&Request::Stats)?))
+    }
+
+    fn done(&mut self, request: &Request) -> Result<()> {
+        match self.request(request)? {
+            Response::Done => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    fn pairs(&mut self, request: &Request) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        match self.request(request)? {
+            Response::Pairs(pairs) => Ok(pairs),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Send `request` and wait for its reply, retrying on a new connection
+    /// if it is safe to run it twice.
+    fn request(&mut self, request: &Request) -> Result<Response> {
+        let retries = match request {
+            Request::Get { .. }
+            | Request::Set { .. }
+            | Request::SetWithTtl { .. }
+            | Request::Scan { .. }
+            | Request::ScanPrefix { .. }
+            | Request::Stats => self.options.retries,
+            _ => 0,
+        };
+
+        let mut attempt = 0;
+        loop {
+            match self.try_request(request) {
+                Err(EngineError::Io(e)) if attempt < retries => {
+                    warn!("request failed, retrying on a new connection: {}", e);
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    fn try_request(&mut self, request: &Request) -> Result<Response> {
+        let mut conn = match self.conn.take() {
+            Some(conn) => conn,
+            None => self.open()?,
+        };
+
+        // the connection is dropped on failure, a late reply would be taken
+        // for the reply to the next request
+        conn.send(request)?;
+        let reply = conn.recv()?;
+        // the server closes the connection once it replied busy
+        if !matches!(&reply, Err(e) if e.code == ErrorCode::Busy) {
+            self.conn = Some(conn);
+        }
+        Ok(reply?)
+    }
+
+    fn open(&self) -> Result<Connection> {
+        let mut last_error = None;
+        for addr in &self.addrs {
+            let stream = match self.options.connect_timeout {
+                Some(timeout) => TcpStream::connect_timeout(addr, timeout),
+                None => TcpStream::connect(addr),
+            };
+            match stream {
+                Ok(stream) => {
+                    stream.set_read_timeout(self.options.timeout)?;
+                    stream.set_write_timeout(self.options.timeout)?;
+                    return Connection::from_stream(stream);
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+
+        Err(last_error
+            .unwrap_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to")
+            })
+            .into())
+    }
+}
+
+fn unexpected(response: Response) -> EngineError {
+    EngineError::Protocol(format!("unexpected response {:?}", response))
+}
+
+fn stats(pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Vec<(String, String)> {
+    pairs
+        .into_iter()
+        .map(|(name, value)| {
+            (
+                String::from_utf8_lossy(&name).into_owned(),
+                String::from_utf8_lossy(&value).into_owned(),
+            )
+        })
+        .collect()
+}
+
+/// Connection to a kvs-server, kept open for any number of requests.
+///
+/// Requests are buffered and only sent on `flush` or when a reply is
+/// awaited, so that many of them go out in a single round trip. Replies
+/// come back in the order of the requests.
+///
+/// ```no_run
+/// use kvs::client::Connection;
+/// use kvs::protocol::Request;
+///
+/// let mut conn = Connection::connect("127.0.0.1:4000")?;
+/// for i in 0..1000 {
+///     let key = format!("key{}", i).into_bytes();
+///     conn.send(&Request::Set { key, value: b"value".to_vec() })?;
+/// }
+/// for _ in 0..1000 {
+///     conn.recv()?;
+/// }
+/// # Ok::<(), kvs::engine::EngineError>(())
+/// ```
+///
+/// Replies are not read while sending, so the requests in flight should
+/// be bounded, a few thousands of them at most.
+pub struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+    version: u32,
+    next_id: u64,
+    // ids of the requests not replied yet, in order
+    pending: VecDeque<u64>,
+}
+
+impl Connection {
+    /// Connect to a kvs-server.
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Connection> {
+        Connection::from_stream(TcpStream::connect(addr)?)
+    }
+
+    /// Handshake with a kvs-server over an open stream, the timeouts of the
+    /// stream apply to the handshake and to the requests.
+    pub fn from_stream(stream: TcpStream) -> Result<Connection> {
+        stream.set_nodelay(true)?;
+
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+        let version = protocol::handshake(&mut reader, &mut writer)?;
+
+        Ok(Connection {
+            reader,
+            writer,
+            version,
+            next_id: 0,
+            pending: VecDeque::new(),
+        })
+    }
+
+    /// Protocol version agreed with the server
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Queue a request, returns its id.
+    pub fn send(&mut self, request: &Request) -> Result<u64> {
+        let id = self.next_id;
+        protocol::write_request(&mut self.writer, id, request)?;
+        self.next_id += 1;
+        self.pending.push_back(id);
+        Ok(id)
+    }
+
+    /// Send the queued requests.
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// Number of requests not replied yet
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Wait for the reply to the oldest request not replied yet, sending
+    /// the queued requests first.
+    pub fn recv(&mut self) -> Result<ReplyResult> {
+        let expected = self
+            .pending
+            .pop_front()
+            .ok_or_else(|| EngineError::Protocol("no request waiting for a reply".to_owned()))?;
+        self.flush()?;
+
+        let (id, reply) = protocol::read_reply(&mut self.reader)?;
+        // a busy server replies to no request in particular
+        let busy = matches!(&reply, Err(e) if e.code == ErrorCode::Busy);
+        if id != expected && !busy {
+            return Err(EngineError::Protocol(format!(
+                "reply to request {} instead of {}",
+                id, expected
+            )));
+        }
+        Ok(reply)
+    }
+}
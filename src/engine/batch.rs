@@ -0,0 +1,86 @@
+//! Write batches
+
+use std::vec;
+
+/// Operation of a `WriteBatch`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchOp {
+    /// Set the value of a key
+    Set {
+        /// Key
+        key: Vec<u8>,
+        /// Value
+        value: Vec<u8>,
+    },
+
+    /// Remove a key
+    Remove {
+        /// Key
+        key: Vec<u8>,
+    },
+}
+
+/// Sets and removes applied in order, all or nothing, by
+/// `KvsEngine::apply_batch`.
+///
+/// Removing a key that doesn't exist is not an error in a batch, the
+/// operation is skipped.
+///
+/// ```no_run
+/// use kvs::{KvStore, KvsEngine, WriteBatch};
+///
+/// let store = KvStore::open("/tmp/kvs")?;
+/// let mut batch = WriteBatch::new();
+/// batch.set("user:42:name".to_owned(), "alice".to_owned());
+/// batch.remove("user:42:email".to_owned());
+/// store.apply_batch(batch)?;
+/// # Ok::<(), kvs::engine::EngineError>(())
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    /// Empty batch
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    /// Set the value of `key`, either text or raw bytes.
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) {
+        self.ops.push(BatchOp::Set {
+            key: key.into(),
+            value: value.into(),
+        });
+    }
+
+    /// Remove `key`, either text or raw bytes.
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) {
+        self.ops.push(BatchOp::Remove { key: key.into() });
+    }
+
+    /// Number of operations
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Whether the batch has no operation
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Operations in order
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+}
+
+impl IntoIterator for WriteBatch {
+    type Item = BatchOp;
+    type IntoIter = vec::IntoIter<BatchOp>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.ops.into_iter()
+    }
+}
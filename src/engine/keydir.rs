@@ -0,0 +1,152 @@
+//! Concurrent index of KvStore
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap};
+use std::hash::{Hash, Hasher};
+use std::ops::{Bound, RangeBounds};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+const SHARDS: usize = 64;
+
+/// Keys read at once by a scan
+const SCAN_CHUNK: usize = 256;
+
+/// Map of keys split into shards, each behind its own lock, so that
+/// readers never wait for each other and a writer only blocks the readers
+/// of one shard for the time of an insert.
+///
+/// The keys are also kept in order for range scans.
+pub(crate) struct Keydir<V> {
+    shards: Vec<RwLock<HashMap<Vec<u8>, V>>>,
+    ordered: RwLock<BTreeSet<Vec<u8>>>,
+}
+
+impl<V: Clone> Keydir<V> {
+    pub(crate) fn new() -> Keydir<V> {
+        Keydir {
+            shards: (0..SHARDS).map(|_| RwLock::new(HashMap::new())).collect(),
+            ordered: RwLock::new(BTreeSet::new()),
+        }
+    }
+
+    fn shard(&self, key: &[u8]) -> &RwLock<HashMap<Vec<u8>, V>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % SHARDS]
+    }
+
+    pub(crate) fn get(&self, key: &[u8]) -> Option<V> {
+        self.shard(key).read().unwrap().get(key).cloned()
+    }
+
+    // the ordered keys are updated before the shard is unlocked, so that
+    // they agree with the shards for each key
+
+    pub(crate) fn insert(&self, key: Vec<u8>, value: V) -> Option<V> {
+        let mut shard = self.shard(&key).write().unwrap();
+        let old = shard.insert(key.clone(), value);
+        if old.is_none() {
+            self.ordered.write().unwrap().insert(key);
+        }
+        old
+    }
+
+    pub(crate) fn remove(&self, key: &[u8]) -> Option<V> {
+        let mut shard = self.shard(key).write().unwrap();
+        let old = shard.remove(key);
+        if old.is_some() {
+            self.ordered.write().unwrap().remove(key);
+        }
+        old
+    }
+
+    /// Remove `key` only if its value is still `expected`.
+    pub(crate) fn remove_if_eq(&self, key: &[u8], expected: &V) -> bool
+    where
+        V: PartialEq,
+    {
+        let mut shard = self.shard(key).write().unwrap();
+        if shard.get(key) != Some(expected) {
+            return false;
+        }
+        shard.remove(key);
+        self.ordered.write().unwrap().remove(key);
+        true
+    }
+
+    /// Keys in `range`, in order, read as they are iterated.
+    pub(crate) fn range<R: RangeBounds<Vec<u8>>>(self: &Arc<Self>, range: R) -> Keys<V> {
+        Keys {
+            keydir: Arc::clone(self),
+            start: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+            chunk: Vec::new().into_iter(),
+            done: false,
+        }
+    }
+
+    /// Keys starting with `prefix`, in order, read as they are iterated.
+    pub(crate) fn prefix(
+        self: &Arc<Self>,
+        prefix: Vec<u8>,
+    ) -> impl Iterator<Item = Vec<u8>> + Send + 'static
+    where
+        V: Send + Sync + 'static,
+    {
+        self.range(prefix.clone()..)
+            .take_while(move |key| key.starts_with(&prefix))
+    }
+
+    /// Lock the shard of `key` for writing, the ordered keys are not
+    /// updated so the key must not be added or removed.
+    pub(crate) fn lock(&self, key: &[u8]) -> RwLockWriteGuard<'_, HashMap<Vec<u8>, V>> {
+        self.shard(key).write().unwrap()
+    }
+
+    /// Read lock on each shard in turn, there is no snapshot of the whole
+    /// map.
+    pub(crate) fn shards(&self) -> impl Iterator<Item = RwLockReadGuard<'_, HashMap<Vec<u8>, V>>> {
+        self.shards.iter().map(|shard| shard.read().unwrap())
+    }
+}
+
+/// Keys of a range in order, read from the ordered keys a chunk at a time
+/// so that writers are never held up for the whole scan. Keys added or
+/// removed meanwhile may or may not be seen.
+pub(crate) struct Keys<V> {
+    keydir: Arc<Keydir<V>>,
+    // bounds of the keys not read yet
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    chunk: std::vec::IntoIter<Vec<u8>>,
+    done: bool,
+}
+
+impl<V> Iterator for Keys<V> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        if let Some(key) = self.chunk.next() {
+            return Some(key);
+        }
+        if self.done {
+            return None;
+        }
+
+        let chunk: Vec<_> = self
+            .keydir
+            .ordered
+            .read()
+            .unwrap()
+            .range::<Vec<u8>, _>((self.start.as_ref(), self.end.as_ref()))
+            .take(SCAN_CHUNK)
+            .cloned()
+            .collect();
+        self.done = chunk.len() < SCAN_CHUNK;
+        if let Some(last) = chunk.last() {
+            self.start = Bound::Excluded(last.clone());
+        }
+        self.chunk = chunk.into_iter();
+        self.chunk.next()
+    }
+}
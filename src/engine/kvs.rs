@@ -5,286 +5,1053 @@
 use std::collections::HashMap;
 use std::fs::File;
 use std::fs::OpenOptions;
+use std::io::prelude::*;
 use std::io::BufWriter;
-use std::io::SeekFrom;
-use std::io::{prelude::*, BufReader};
-use std::path::PathBuf;
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::sync::Mutex;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::{Condvar, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use thiserror::Error;
-// use bincode;
+use crossbeam::channel::{self, Receiver, RecvTimeoutError, Sender};
+use log::{error, info, warn};
 use serde::{Deserialize, Serialize};
-use serde_json;
-use walkdir::WalkDir;
+use thiserror::Error;
 
+use crate::engine::keydir::Keydir;
+use crate::engine::options::{KvStoreOptions, SyncPolicy};
+use crate::engine::record::{self, Hint, LogFormat, LogIter, RecordPos};
 pub use crate::engine::KvsEngine;
+use crate::engine::{is_empty_range, BatchOp, ScanBytes, ScanKeys, WriteBatch};
+use crate::protocol::ErrorCode;
 
 /// Result for engine
 pub type Result<T> = std::result::Result<T, EngineError>;
 
-/// Log entry
+/// Log entry, keys and values are raw bytes
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Entry {
     /// Set
     Set {
         /// Key
-        key: String,
+        key: Vec<u8>,
         /// value
-        value: String,
+        value: Vec<u8>,
     },
 
     /// Remove
     Remove {
         /// Key
-        key: String,
+        key: Vec<u8>,
+    },
+
+    /// Set until an expiration time
+    SetWithTtl {
+        /// Key
+        key: Vec<u8>,
+        /// value
+        value: Vec<u8>,
+        /// Expiration time, in milliseconds since the unix epoch
+        expires_at: u64,
     },
 }
 
-/// Log path + offset
-#[derive(Debug, Clone)]
+impl Entry {
+    fn expires_at(&self) -> Option<u64> {
+        match self {
+            Entry::SetWithTtl { expires_at, .. } => Some(*expires_at),
+            _ => None,
+        }
+    }
+}
+
+/// Log file + position of the entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct LogPointer {
-    // Log Path
+    // id of the log file in the segment table
+    file_id: u64,
+
+    // position of the encoded entry
+    pos: RecordPos,
+
+    // expiration time of the value, in milliseconds since the unix epoch
+    expires_at: Option<u64>,
+}
+
+impl LogPointer {
+    fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at as u128 <= now_millis())
+    }
+}
+
+/// A log file open for reading, shared by all readers.
+///
+/// The handle stays valid when the active file is renamed to a data file,
+/// and when a compaction removes the file under a reader.
+struct Segment {
     path: PathBuf,
+    file: File,
+    format: LogFormat,
+}
+
+/// Torn or corrupted tail dropped from the active file on open
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// Path of the recovered file
+    pub path: PathBuf,
+
+    /// Offset of the first bad record, the file is truncated to it
+    pub offset: u64,
 
-    // offset
-    offset: usize,
+    /// Number of bytes dropped
+    pub dropped_bytes: u64,
 }
 
 /// Store key-value pair
 #[derive(Clone)]
 pub struct KvStore {
-    keydir: Arc<Mutex<HashMap<String, LogPointer>>>,
-    active_file_writer: Arc<Mutex<BufWriter<File>>>,
+    inner: Arc<KvStoreInner>,
+    compactor: Arc<Worker>,
+    // only held to stop the syncer with the last clone of the store
+    _syncer: Option<Arc<Worker>>,
+}
+
+struct KvStoreInner {
+    keydir: Arc<Keydir<LogPointer>>,
+    segments: RwLock<HashMap<u64, Arc<Segment>>>,
+    next_file_id: AtomicU64,
+    active_file_id: AtomicU64,
+    // stamp of the newest data or compact file
+    last_stamp: AtomicU64,
+    active_file_writer: Mutex<BufWriter<File>>,
+    // hints of the records of the active file, written out when it is
+    // sealed. Only updated while holding the writer.
+    active_file_hints: Mutex<Vec<Hint>>,
     active_file_path: PathBuf,
     dir_path: PathBuf,
-    file_threshold: usize,
-    max_size: usize,
+    options: KvStoreOptions,
+    // size of all log files
+    total_bytes: AtomicU64,
+    // size of the records of the sealed files that are overwritten or
+    // removed, only they can be compacted
+    stale_bytes: AtomicU64,
+    // the same for the active file, counted in once it is sealed
+    active_stale_bytes: AtomicU64,
+    sync_state: Mutex<SyncState>,
+    synced: Condvar,
+    recovery: Option<RecoveryReport>,
 }
 
-impl KvStore {
-    /// Create KvStore instance.
-    pub fn new(dir_path: PathBuf) -> Result<Self> {
-        let active_file_path = dir_path.join("db.log");
-        let active_file = Arc::new(Mutex::new(BufWriter::new(
-            OpenOptions::new()
-                .create(true)
-                .read(true)
-                .write(true)
-                .append(true)
-                .open(active_file_path.clone())?,
-        )));
+/// How far the active file is written and synced to disk.
+struct SyncState {
+    // sequence number of the last write
+    written_seq: u64,
+    // length of the active file after the last write
+    written_len: u64,
+    // sequence number of the last write known to be on disk
+    synced_seq: u64,
+    // length of the active file known to be on disk
+    synced_len: u64,
+    // a writer is syncing on behalf of the group
+    syncing: bool,
+    // handle to sync the active file, replaced when it is sealed
+    file: Arc<File>,
+}
 
-        Ok(KvStore {
-            keydir: Arc::new(Mutex::new(HashMap::new())),
-            active_file_writer: active_file,
-            active_file_path,
-            dir_path,
-            file_threshold: 10 * 1024,
-            max_size: 5 * 10 * 1024,
+/// Background thread owned by the store, it stops once the store is
+/// dropped.
+struct Worker {
+    tx: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Worker {
+    /// Spawn a thread running `run`, the receiver is disconnected when the
+    /// worker is dropped.
+    fn spawn<F>(name: &str, run: F) -> Result<Worker>
+    where
+        F: FnOnce(Receiver<()>) + Send + 'static,
+    {
+        // at most one pending request, more would be served together anyway
+        let (tx, rx) = channel::bounded::<()>(1);
+        let handle = thread::Builder::new()
+            .name(name.to_owned())
+            .spawn(move || run(rx))?;
+
+        Ok(Worker {
+            tx: Some(tx),
+            handle: Some(handle),
         })
     }
 
-    /// Truncate current active file and create data file.
-    fn truncate_active_file(&self, active_file: &mut File) -> Result<()> {
-        let start = SystemTime::now();
-        let since_the_epoch = start
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards");
-
-        let data_file_path = self.dir_path.join(format!(
-            "data-{}.log",
-            since_the_epoch.as_millis().to_string()
-        ));
-
-        println!(
-            "truncate active file, rename to filename: {:?}",
-            data_file_path
-        );
-        std::fs::rename(self.active_file_path.clone(), data_file_path.clone())?;
-
-        self.scan_file(data_file_path)?;
+    /// Wake the worker up, does nothing if a request is pending.
+    fn notify(&self) {
+        if let Some(tx) = &self.tx {
+            let _ = tx.try_send(());
+        }
+    }
+}
 
-        *active_file = OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .append(true)
-            .open(self.active_file_path.clone())?;
+impl Drop for Worker {
+    fn drop(&mut self) {
+        // disconnect the channel and wait for the running job
+        drop(self.tx.take());
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                warn!("kvs worker thread panicked");
+            }
+        }
+    }
+}
 
-        Ok(())
+impl KvStore {
+    /// Create KvStore instance.
+    pub fn new(dir_path: PathBuf) -> Result<Self> {
+        KvStore::from_inner(KvStoreInner::new(dir_path, KvStoreOptions::default())?)
     }
 
-    fn total_size(&self) -> usize {
-        let entries = WalkDir::new(self.dir_path.clone()).into_iter();
-        let len: walkdir::Result<u64> = entries
-            .map(|res| {
-                res.and_then(|entry| entry.metadata())
-                    .map(|metadata| metadata.len())
-            })
-            .sum();
-        len.expect("fail to get directory size") as usize
+    fn from_inner(inner: KvStoreInner) -> Result<KvStore> {
+        let inner = Arc::new(inner);
+
+        let weak = Arc::downgrade(&inner);
+        let compactor = Worker::spawn("kvs-compactor", move |rx| {
+            for _ in rx.iter() {
+                let inner = match weak.upgrade() {
+                    Some(inner) => inner,
+                    None => return,
+                };
+                if let Err(e) = inner.compact() {
+                    error!("compaction failed: {:?}", e);
+                }
+            }
+        })?;
+
+        let syncer = match inner.options.sync_policy {
+            SyncPolicy::Interval(interval) => {
+                let weak = Arc::downgrade(&inner);
+                let syncer = Worker::spawn("kvs-syncer", move |rx| loop {
+                    match rx.recv_timeout(interval) {
+                        Err(RecvTimeoutError::Timeout) => {}
+                        Ok(()) => continue,
+                        Err(RecvTimeoutError::Disconnected) => return,
+                    }
+                    let inner = match weak.upgrade() {
+                        Some(inner) => inner,
+                        None => return,
+                    };
+                    if let Err(e) = inner.sync_written() {
+                        error!("sync failed: {:?}", e);
+                    }
+                })?;
+                Some(Arc::new(syncer))
+            }
+            _ => None,
+        };
+
+        Ok(KvStore {
+            inner,
+            compactor: Arc::new(compactor),
+            _syncer: syncer,
+        })
     }
 
     /// Create KvStore from file.
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with(path, KvStoreOptions::default())
+    }
+
+    /// Create KvStore from file with the given options.
+    pub fn open_with(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
         let path: PathBuf = path.into();
+        if options.create_dir {
+            std::fs::create_dir_all(&path)?;
+        }
+
+        // a crash may leave a torn record at the end of the active file
+        let active_file_path = path.join("db.log");
+        let recovery = recover_active_file(&active_file_path, options.read_buffer_size)?;
+
+        // stamps keep growing past the ones of the files left by a previous
+        // run, whatever the clock says
+        let last_stamp = AtomicU64::new(last_file_stamp(&path)?);
+
+        // logs written by older versions are never appended to, seal the
+        // active file so that new writes go to a binary log.
+        if active_file_path.exists() {
+            let mut active_file = OpenOptions::new().read(true).open(&active_file_path)?;
+            if matches!(
+                record::detect_format(&mut active_file)?,
+                Some(LogFormat::Json | LogFormat::BinaryV1)
+            ) {
+                seal_active_file(&path, &active_file_path, &last_stamp)?;
+            }
+        }
 
         let mut compacted_paths = vec![];
+        let mut compact_leftovers = vec![];
         let mut data_paths = vec![];
         for entry in std::fs::read_dir(path.clone())? {
             let p = entry?.path();
-            if p.is_file() {
-                if let Some(file_name) = p.file_name().map(|s| s.to_string_lossy()) {
-                    if file_name.starts_with("compact") {
+            if !p.is_file() {
+                continue;
+            }
+            if let Some(file_name) = p.file_name().map(|s| s.to_string_lossy()) {
+                let is_log = p.extension().is_some_and(|ext| ext == "log");
+                if file_name.starts_with("compact") {
+                    if is_log {
                         compacted_paths.push(p.clone());
+                    } else {
+                        compact_leftovers.push(p.clone());
                     }
-                    if file_name.starts_with("data") {
-                        data_paths.push(p);
-                    }
+                }
+                if is_log && file_name.starts_with("data") {
+                    data_paths.push(p);
                 }
             }
         }
-        compacted_paths.sort_by(|a, b| b.cmp(a));
+        compacted_paths.sort_by_key(|p| std::cmp::Reverse(file_stamp(p)));
+        data_paths.sort_by_key(|p| file_stamp(p));
         let compacted_path = compacted_paths
             .first()
             .cloned()
             .unwrap_or(path.join("compact.log"));
 
-        let kv_store = KvStore::new(path)?;
+        // a crash during a compaction may leave the previous compact file,
+        // or the temporary files of the new one, only the newest is loaded
+        let compact_hint_path = record::hint_path(&compacted_path);
+        for stale_path in compacted_paths.iter().skip(1).chain(&compact_leftovers) {
+            if *stale_path != compact_hint_path {
+                info!("remove stale compact file {:?}", stale_path);
+                std::fs::remove_file(stale_path)?;
+            }
+        }
+
+        let mut inner = KvStoreInner::new(path, options)?;
+        inner.recovery = recovery;
+        inner.last_stamp = last_stamp;
 
         if compacted_path.exists() {
-            kv_store.scan_file(compacted_path)?;
+            inner.load_file(compacted_path)?;
         }
 
         for data_path in data_paths {
-            kv_store.scan_file(data_path)?;
+            inner.load_file(data_path)?;
         }
 
-        kv_store.scan_active_file()?;
+        inner.scan_active_file()?;
+
+        KvStore::from_inner(inner)
+    }
+
+    /// Report of the torn tail dropped from the active file when the store
+    /// was opened, `None` if the log was intact.
+    pub fn recovery_report(&self) -> Option<&RecoveryReport> {
+        self.inner.recovery.as_ref()
+    }
+
+    /// Drop the store as if the machine lost power: the writes to the
+    /// active file that are not synced yet are thrown away.
+    ///
+    /// Only meant to check the guarantees of each `SyncPolicy` in tests.
+    #[doc(hidden)]
+    pub fn simulate_crash(self) -> Result<()> {
+        let active_file = self.inner.active_file_writer.lock().unwrap();
+        let synced_len = self.inner.sync_state.lock().unwrap().synced_len;
+        active_file.get_ref().set_len(synced_len)?;
+        drop(active_file);
+        Ok(())
+    }
 
-        Ok(kv_store)
+    /// Compact the sealed files, then drop the store as if the machine
+    /// lost power once `removed` of them were deleted.
+    ///
+    /// Only meant to check that compaction is crash safe in tests, with
+    /// the background compaction turned off.
+    #[doc(hidden)]
+    pub fn simulate_crash_in_compaction(self, removed: usize) -> Result<()> {
+        self.inner.compact_removing(removed)
     }
 
+    /// Hold the writer until the returned guard is dropped, writes wait
+    /// for it meanwhile.
+    ///
+    /// Only meant to check that reads don't wait for writes in tests.
+    #[doc(hidden)]
+    pub fn hold_writer(&self) -> impl Drop + '_ {
+        self.inner.active_file_writer.lock().unwrap()
+    }
+
+    fn maybe_compact(&self) {
+        if self.inner.should_compact() {
+            self.compactor.notify();
+        }
+    }
+}
+
+impl KvStoreInner {
+    fn new(dir_path: PathBuf, options: KvStoreOptions) -> Result<KvStoreInner> {
+        let active_file_path = dir_path.join("db.log");
+        let active_file = open_active_file(&active_file_path)?;
+        active_file.sync_all()?;
+        let active_file_len = active_file.metadata()?.len();
+        let sync_state = SyncState {
+            written_seq: 0,
+            written_len: active_file_len,
+            synced_seq: 0,
+            synced_len: active_file_len,
+            syncing: false,
+            file: Arc::new(active_file.try_clone()?),
+        };
+
+        let inner = KvStoreInner {
+            keydir: Arc::new(Keydir::new()),
+            segments: RwLock::new(HashMap::new()),
+            next_file_id: AtomicU64::new(0),
+            active_file_id: AtomicU64::new(0),
+            last_stamp: AtomicU64::new(0),
+            active_file_writer: Mutex::new(BufWriter::with_capacity(
+                options.write_buffer_size,
+                active_file,
+            )),
+            active_file_hints: Mutex::new(Vec::new()),
+            active_file_path,
+            dir_path,
+            options,
+            total_bytes: AtomicU64::new(active_file_len),
+            stale_bytes: AtomicU64::new(0),
+            active_stale_bytes: AtomicU64::new(0),
+            sync_state: Mutex::new(sync_state),
+            synced: Condvar::new(),
+            recovery: None,
+        };
+        let active_file_id =
+            inner.add_segment(inner.active_file_path.clone(), LogFormat::Binary)?;
+        inner.active_file_id.store(active_file_id, Ordering::SeqCst);
+
+        Ok(inner)
+    }
+
+    /// Open a log file for reading and give it an id.
+    fn add_segment(&self, path: PathBuf, format: LogFormat) -> Result<u64> {
+        let file = OpenOptions::new().read(true).open(&path)?;
+        let id = self.next_file_id.fetch_add(1, Ordering::SeqCst);
+        let segment = Segment { path, file, format };
+        self.segments.write().unwrap().insert(id, Arc::new(segment));
+        Ok(id)
+    }
+
+    fn segment(&self, id: u64) -> Option<Arc<Segment>> {
+        self.segments.read().unwrap().get(&id).cloned()
+    }
+
+    /// Truncate current active file and create data file.
+    fn truncate_active_file(&self, active_file: &mut File) -> Result<()> {
+        let data_file_path =
+            seal_active_file(&self.dir_path, &self.active_file_path, &self.last_stamp)?;
+
+        // pointers keep the id of the file, only its path changes
+        let active_file_id = self.active_file_id.load(Ordering::SeqCst);
+        let mut segments = self.segments.write().unwrap();
+        if let Some(segment) = segments.get(&active_file_id).cloned() {
+            let segment = Segment {
+                path: data_file_path.clone(),
+                file: segment.file.try_clone()?,
+                format: segment.format,
+            };
+            segments.insert(active_file_id, Arc::new(segment));
+        }
+        drop(segments);
+
+        // the data file is never written again, sync it for good
+        active_file.sync_all()?;
+
+        let hints = std::mem::take(&mut *self.active_file_hints.lock().unwrap());
+        record::write_hints(&data_file_path, &hints)?;
+
+        *active_file = open_active_file(&self.active_file_path)?;
+        active_file.sync_all()?;
+        let active_file_id = self.add_segment(self.active_file_path.clone(), LogFormat::Binary)?;
+        self.active_file_id.store(active_file_id, Ordering::SeqCst);
+        let sealed_stale_bytes = self.active_stale_bytes.swap(0, Ordering::SeqCst);
+        self.stale_bytes
+            .fetch_add(sealed_stale_bytes, Ordering::SeqCst);
+        self.total_bytes
+            .fetch_add(record::HEADER_LEN, Ordering::SeqCst);
+
+        let mut state = self.sync_state.lock().unwrap();
+        state.synced_seq = state.written_seq;
+        state.written_len = record::HEADER_LEN;
+        state.synced_len = record::HEADER_LEN;
+        state.file = Arc::new(active_file.try_clone()?);
+        drop(state);
+        self.synced.notify_all();
+
+        Ok(())
+    }
+
+    /// Count `len` bytes of the file `file_id` as stale.
+    fn add_stale(&self, file_id: u64, len: u64) {
+        let stale_bytes = if file_id == self.active_file_id.load(Ordering::SeqCst) {
+            &self.active_stale_bytes
+        } else {
+            &self.stale_bytes
+        };
+        stale_bytes.fetch_add(len, Ordering::SeqCst);
+    }
+
+    fn should_compact(&self) -> bool {
+        let stale_bytes = self.stale_bytes.load(Ordering::SeqCst);
+        let total_bytes = self.total_bytes.load(Ordering::SeqCst);
+        stale_bytes >= self.options.compaction_min_bytes
+            && stale_bytes as f64 >= self.options.compaction_ratio * total_bytes as f64
+    }
+
+    /// Merge all sealed files into a new compact file.
+    ///
+    /// Only the records the keydir still points to are copied, reads and
+    /// writes keep going while they are copied. The keys that were not
+    /// written meanwhile are then pointed to the compact file and the
+    /// sealed files are removed.
     fn compact(&self) -> Result<()> {
-        // compact
-        let start = SystemTime::now();
-        let since_the_epoch = start
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards");
-        let compact_path = self.dir_path.join(format!(
-            "compact-{}.log",
-            since_the_epoch.as_millis().to_string()
-        ));
-        let mut compact_file = OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .open(compact_path.clone())?;
-
-        let keydir = self.keydir.lock().unwrap();
-        for (key, _) in keydir.iter() {
-            if let Some(val) = self.read_value(&*keydir, key.to_owned())? {
-                let entry = Entry::Set {
+        self.compact_removing(usize::MAX)
+    }
+
+    /// Compaction stopping after removing `max_removed` sealed files, as a
+    /// crash would.
+    fn compact_removing(&self, max_removed: usize) -> Result<()> {
+        // no data file can be sealed while holding the writer
+        let active_file = self.active_file_writer.lock().unwrap();
+        let active_file_id = self.active_file_id.load(Ordering::SeqCst);
+        let sealed: HashMap<u64, Arc<Segment>> = self
+            .segments
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(id, _)| **id != active_file_id)
+            .map(|(id, segment)| (*id, segment.clone()))
+            .collect();
+        drop(active_file);
+
+        if sealed.is_empty() {
+            return Ok(());
+        }
+
+        let mut live = Vec::new();
+        for shard in self.keydir.shards() {
+            live.extend(
+                shard
+                    .iter()
+                    .filter(|(_, pointer)| sealed.contains_key(&pointer.file_id))
+                    .map(|(key, pointer)| (key.clone(), *pointer)),
+            );
+        }
+
+        // expired keys are dropped instead of copied, unless written again
+        // meanwhile
+        let (expired, live): (Vec<_>, Vec<_>) = live
+            .into_iter()
+            .partition(|(_, pointer)| pointer.is_expired());
+        for (key, pointer) in expired {
+            if self.keydir.remove_if_eq(&key, &pointer) {
+                self.add_stale(pointer.file_id, pointer.pos.len);
+            }
+        }
+
+        let compact_path = new_file_path(&self.dir_path, "compact", &self.last_stamp);
+        let tmp_path = compact_path.with_extension("log.tmp");
+        let mut compact_file = BufWriter::new(
+            OpenOptions::new()
+                .create(true)
+                .truncate(true)
+                .write(true)
+                .open(&tmp_path)?,
+        );
+        record::write_header(&mut compact_file)?;
+
+        let mut hints = Vec::with_capacity(live.len());
+        let mut moved = Vec::with_capacity(live.len());
+        let mut offset = record::HEADER_LEN;
+        for (key, pointer) in live {
+            let segment = &sealed[&pointer.file_id];
+            let buf = record::read_record(&segment.file, segment.format, pointer.pos)?;
+            compact_file.write_all(&buf)?;
+
+            let pos = RecordPos {
+                offset,
+                len: buf.len() as u64,
+            };
+            hints.push(match pointer.expires_at {
+                Some(expires_at) => Hint::SetWithTtl {
                     key: key.clone(),
-                    value: val.clone(),
-                };
+                    offset,
+                    len: pos.len,
+                    expires_at,
+                },
+                None => Hint::Set {
+                    key: key.clone(),
+                    offset,
+                    len: pos.len,
+                },
+            });
+            moved.push((key, pointer, pos));
+            offset += pos.len;
+        }
+
+        compact_file.flush()?;
+        compact_file.get_ref().sync_all()?;
+        drop(compact_file);
+        record::write_hints(&compact_path, &hints)?;
+        std::fs::rename(&tmp_path, &compact_path)?;
+        // the compact file must be there before any sealed file goes
+        sync_dir(&self.dir_path)?;
+        let compact_file_id = self.add_segment(compact_path.clone(), LogFormat::Binary)?;
 
-                writeln!(compact_file, "{}", serde_json::to_string(&entry)?)?;
+        // swap the new index in, keys written meanwhile keep their pointer
+        for (key, old, pos) in moved {
+            let mut shard = self.keydir.lock(&key);
+            match shard.get_mut(&key) {
+                Some(pointer) if *pointer == old => {
+                    *pointer = LogPointer {
+                        file_id: compact_file_id,
+                        pos,
+                        expires_at: old.expires_at,
+                    }
+                }
+                _ => self.add_stale(compact_file_id, pos.len),
             }
         }
-        drop(keydir);
 
-        compact_file.sync_all()?;
+        // remove stale data files and compact files, readers that still
+        // hold a pointer to them look the key up again
+        let mut segments = self.segments.write().unwrap();
+        for id in sealed.keys() {
+            segments.remove(id);
+        }
+        drop(segments);
 
-        // remove stale data files and compact files
-        for entry in std::fs::read_dir(self.dir_path.clone())? {
-            let p = entry?.path();
-            if p.is_file() && p != compact_path {
-                if let Some(file_name) = p.file_name().map(|s| s.to_string_lossy()) {
-                    if file_name.starts_with("compact") || file_name.starts_with("data") {
-                        std::fs::remove_file(p)?;
+        // oldest first, a crash must not leave a value whose tombstone is
+        // already gone. The older compact file predates all data files.
+        let mut sealed: Vec<_> = sealed.into_values().collect();
+        sealed.sort_by_key(|segment| (is_data_file(&segment.path), file_stamp(&segment.path)));
+        let mut sealed_bytes = 0;
+        for segment in sealed.iter().take(max_removed) {
+            sealed_bytes += segment.file.metadata()?.len();
+            let hint_path = record::hint_path(&segment.path);
+            if hint_path.exists() {
+                std::fs::remove_file(hint_path)?;
+            }
+            std::fs::remove_file(&segment.path)?;
+        }
+
+        let dropped = sealed_bytes.saturating_sub(offset);
+        self.total_bytes.fetch_add(offset, Ordering::SeqCst);
+        self.total_bytes.fetch_sub(sealed_bytes, Ordering::SeqCst);
+        let _ = self
+            .stale_bytes
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |stale| {
+                Some(stale.saturating_sub(dropped))
+            });
+        info!(
+            "compacted {} files into {:?}, dropped {} bytes",
+            sealed.len(),
+            compact_path,
+            dropped
+        );
+
+        Ok(())
+    }
+
+    fn scan_active_file(&self) -> Result<()> {
+        let file = OpenOptions::new().read(true).open(&self.active_file_path)?;
+        if let Some((_, hints)) = record::scan_hints(file, self.options.read_buffer_size)? {
+            *self.active_file_hints.lock().unwrap() = hints.clone();
+            let active_file_id = self.active_file_id.load(Ordering::SeqCst);
+            self.apply_hints(active_file_id, &self.active_file_path, hints)?;
+        }
+        Ok(())
+    }
+
+    /// Refresh inner from the hint file of a sealed file, or scan the file
+    /// itself if it has no usable hint file.
+    fn load_file(&self, path: PathBuf) -> Result<()> {
+        let (format, hints) = match record::read_hints(&path)? {
+            Some(hints) => (LogFormat::Binary, hints),
+            None => {
+                let file = OpenOptions::new().read(true).open(&path)?;
+                record::scan_hints(file, self.options.read_buffer_size)?
+                    .unwrap_or((LogFormat::Binary, vec![]))
+            }
+        };
+        let file_id = self.add_segment(path.clone(), format)?;
+        self.apply_hints(file_id, &path, hints)
+    }
+
+    /// Replay the records of a log file, in order.
+    fn apply_hints(&self, file_id: u64, path: &Path, hints: Vec<Hint>) -> Result<()> {
+        self.total_bytes
+            .fetch_add(std::fs::metadata(path)?.len(), Ordering::SeqCst);
+
+        for hint in hints {
+            match hint {
+                Hint::Set { key, offset, len } => {
+                    let pointer = LogPointer {
+                        file_id,
+                        pos: RecordPos { offset, len },
+                        expires_at: None,
+                    };
+                    if let Some(old) = self.keydir.insert(key, pointer) {
+                        self.add_stale(old.file_id, old.pos.len);
+                    }
+                }
+                Hint::Remove { key, len } => {
+                    if let Some(old) = self.keydir.remove(&key) {
+                        self.add_stale(old.file_id, old.pos.len);
+                    }
+                    self.add_stale(file_id, len);
+                }
+                Hint::SetWithTtl {
+                    key,
+                    offset,
+                    len,
+                    expires_at,
+                } => {
+                    let pointer = LogPointer {
+                        file_id,
+                        pos: RecordPos { offset, len },
+                        expires_at: Some(expires_at),
+                    };
+                    // an expired value hides the older ones like a tombstone
+                    let old = if pointer.is_expired() {
+                        self.add_stale(file_id, len);
+                        self.keydir.remove(&key)
+                    } else {
+                        self.keydir.insert(key, pointer)
+                    };
+                    if let Some(old) = old {
+                        self.add_stale(old.file_id, old.pos.len);
                     }
                 }
             }
         }
+        Ok(())
+    }
 
-        // clear key-dir and active file
-        let mut keydir = self.keydir.lock().unwrap();
-        keydir.clear();
-        drop(keydir);
+    /// Pointer of `key`, unless it has expired.
+    fn live_pointer(&self, key: &[u8]) -> Option<LogPointer> {
+        self.keydir.get(key).filter(|pointer| !pointer.is_expired())
+    }
+
+    fn read_value(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        loop {
+            let pointer = match self.live_pointer(key) {
+                Some(pointer) => pointer,
+                None => return Ok(None),
+            };
+            // the file was just merged by a compaction, the key points to
+            // the compact file by now
+            let segment = match self.segment(pointer.file_id) {
+                Some(segment) => segment,
+                None => continue,
+            };
+
+            let entry = record::read_entry(&segment.file, segment.format, pointer.pos)?;
+            return match entry {
+                Entry::Set { value, .. } | Entry::SetWithTtl { value, .. } => Ok(Some(value)),
+                Entry::Remove { .. } => Err(EngineError::NotFound(
+                    "DB log error, there should be a Set entry".to_owned(),
+                )),
+            };
+        }
+    }
+
+    /// Flush a write to the active file, `len` is the length of the file
+    /// after the write. Returns the sequence number of the write.
+    ///
+    /// With `SyncPolicy::Always` the write is synced right away.
+    fn commit(&self, active_file: &mut BufWriter<File>, len: u64) -> Result<u64> {
+        active_file.flush()?;
+
+        let mut state = self.sync_state.lock().unwrap();
+        state.written_seq += 1;
+        state.written_len = len;
+        if self.options.sync_policy == SyncPolicy::Always {
+            active_file.get_ref().sync_data()?;
+            state.synced_seq = state.written_seq;
+            state.synced_len = len;
+        }
+
+        Ok(state.written_seq)
+    }
+
+    /// Wait until the write with sequence number `seq` is on disk, with
+    /// `SyncPolicy::GroupCommit`.
+    ///
+    /// The first waiter syncs everything written so far while the writers
+    /// coming after it wait, so concurrent writers share a single sync.
+    fn wait_synced(&self, seq: u64) -> Result<()> {
+        if self.options.sync_policy != SyncPolicy::GroupCommit {
+            return Ok(());
+        }
 
+        let mut state = self.sync_state.lock().unwrap();
+        while state.synced_seq < seq {
+            if state.syncing {
+                state = self.synced.wait(state).unwrap();
+                continue;
+            }
+
+            state.syncing = true;
+            drop(state);
+            let res = self.sync_written();
+            state = self.sync_state.lock().unwrap();
+            state.syncing = false;
+            self.synced.notify_all();
+            res?;
+        }
+
+        Ok(())
+    }
+
+    /// Flush the active file and sync it.
+    fn sync(&self) -> Result<()> {
+        self.active_file_writer.lock().unwrap().flush()?;
+        self.sync_written()
+    }
+
+    /// Sync everything written to the active file so far.
+    fn sync_written(&self) -> Result<()> {
+        let state = self.sync_state.lock().unwrap();
+        let (seq, len, file) = (state.written_seq, state.written_len, state.file.clone());
+        drop(state);
+
+        file.sync_data()?;
+
+        let mut state = self.sync_state.lock().unwrap();
+        state.synced_seq = state.synced_seq.max(seq);
+        // the active file may have been sealed meanwhile
+        if Arc::ptr_eq(&state.file, &file) {
+            state.synced_len = state.synced_len.max(len);
+        }
+        Ok(())
+    }
+
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
         let mut active_file = self.active_file_writer.lock().unwrap();
-        active_file.seek(SeekFrom::Start(0))?;
-        active_file.get_mut().set_len(0)?;
+        let seq = self.write_set(&mut active_file, key, value, None)?;
         drop(active_file);
 
-        // scan
-        self.scan_file(compact_path)?;
+        self.wait_synced(seq)
+    }
 
-        Ok(())
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let expires_at = expiration_time(ttl);
+        let mut active_file = self.active_file_writer.lock().unwrap();
+        let seq = self.write_set(&mut active_file, key, value, Some(expires_at))?;
+        drop(active_file);
+
+        self.wait_synced(seq)
     }
 
-    fn scan_active_file(&self) -> Result<()> {
-        self.scan_file(self.active_file_path.clone())
+    /// Append a set record while holding the writer, returns the sequence
+    /// number of the write.
+    fn write_set(
+        &self,
+        active_file: &mut BufWriter<File>,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+    ) -> Result<u64> {
+        let file_size = active_file.get_ref().metadata()?.len();
+
+        let entry = match expires_at {
+            Some(expires_at) => Entry::SetWithTtl {
+                key: key.clone(),
+                value,
+                expires_at,
+            },
+            None => Entry::Set {
+                key: key.clone(),
+                value,
+            },
+        };
+        let buf = record::encode(&entry)?;
+        active_file.write_all(&buf)?;
+        let seq = self.commit(active_file, file_size + buf.len() as u64)?;
+        self.total_bytes
+            .fetch_add(buf.len() as u64, Ordering::SeqCst);
+
+        let pos = RecordPos {
+            offset: file_size,
+            len: buf.len() as u64,
+        };
+        self.active_file_hints
+            .lock()
+            .unwrap()
+            .push(Hint::new(&entry, pos));
+        let old = self.keydir.insert(
+            key,
+            LogPointer {
+                file_id: self.active_file_id.load(Ordering::SeqCst),
+                pos,
+                expires_at,
+            },
+        );
+        if let Some(old) = old {
+            self.add_stale(old.file_id, old.pos.len);
+        }
+
+        if file_size > self.options.segment_size {
+            self.truncate_active_file(active_file.get_mut())?;
+        }
+
+        Ok(seq)
     }
 
-    /// Scan file and refresh inner
-    fn scan_file(&self, path: PathBuf) -> Result<()> {
-        let mut bytes_len = 0;
-        let reader = BufReader::new(OpenOptions::new().read(true).open(path.clone())?);
-        for line in reader.lines() {
-            let line_string = line?;
-            let entry: Entry = serde_json::from_str(&line_string)?;
+    /// Write all operations of the batch as a single batch record.
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()> {
+        let mut active_file = self.active_file_writer.lock().unwrap();
+        let file_size = active_file.get_ref().metadata()?.len();
+
+        // removes of missing keys are skipped, keys set or removed earlier
+        // in the batch are taken into account
+        let mut exists: HashMap<Vec<u8>, bool> = HashMap::new();
+        let mut entries = Vec::with_capacity(batch.len());
+        for op in batch {
+            match op {
+                BatchOp::Set { key, value } => {
+                    exists.insert(key.clone(), true);
+                    entries.push(Entry::Set { key, value });
+                }
+                BatchOp::Remove { key } => {
+                    let found = match exists.get(&key) {
+                        Some(found) => *found,
+                        None => self.live_pointer(&key).is_some(),
+                    };
+                    if found {
+                        exists.insert(key.clone(), false);
+                        entries.push(Entry::Remove { key });
+                    }
+                }
+            }
+        }
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        let (buf, positions) = record::encode_batch(&entries)?;
+        active_file.write_all(&buf)?;
+        let seq = self.commit(&mut active_file, file_size + buf.len() as u64)?;
+        self.total_bytes
+            .fetch_add(buf.len() as u64, Ordering::SeqCst);
+
+        let file_id = self.active_file_id.load(Ordering::SeqCst);
+        let mut hints = self.active_file_hints.lock().unwrap();
+        for (entry, pos) in entries.into_iter().zip(positions) {
+            let pos = RecordPos {
+                offset: file_size + pos.offset,
+                len: pos.len,
+            };
+            hints.push(Hint::new(&entry, pos));
+            let expires_at = entry.expires_at();
             match entry {
-                Entry::Set { key, .. } => {
-                    let mut keydir = self.keydir.lock().unwrap();
-                    keydir.insert(
-                        key,
-                        LogPointer {
-                            path: path.clone(),
-                            offset: bytes_len,
-                        },
-                    );
+                Entry::Set { key, .. } | Entry::SetWithTtl { key, .. } => {
+                    let pointer = LogPointer {
+                        file_id,
+                        pos,
+                        expires_at,
+                    };
+                    if let Some(old) = self.keydir.insert(key, pointer) {
+                        self.add_stale(old.file_id, old.pos.len);
+                    }
                 }
                 Entry::Remove { key } => {
-                    let mut keydir = self.keydir.lock().unwrap();
-                    keydir.remove(&key);
+                    if let Some(old) = self.keydir.remove(&key) {
+                        self.add_stale(old.file_id, old.pos.len);
+                    }
+                    self.add_stale(file_id, pos.len);
                 }
             }
-            bytes_len += line_string.as_bytes().len() + 1;
         }
+        drop(hints);
 
-        Ok(())
+        if file_size > self.options.segment_size {
+            self.truncate_active_file(active_file.get_mut())?;
+        }
+        drop(active_file);
+
+        self.wait_synced(seq)
+    }
+
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.read_value(&key)
     }
 
-    fn read_value(
+    fn remove(&self, key: Vec<u8>) -> Result<()> {
+        let mut active_file = self.active_file_writer.lock().unwrap();
+        if self.live_pointer(&key).is_none() {
+            return Err(EngineError::not_found(&key));
+        }
+
+        let seq = self.write_remove(&mut active_file, key)?;
+        drop(active_file);
+
+        self.wait_synced(seq)
+    }
+
+    /// Append a tombstone while holding the writer, the key must exist.
+    /// Returns the sequence number of the write.
+    fn write_remove(&self, active_file: &mut BufWriter<File>, key: Vec<u8>) -> Result<u64> {
+        let file_size = active_file.get_ref().metadata()?.len();
+        let entry = Entry::Remove { key: key.clone() };
+        let buf = record::encode(&entry)?;
+        active_file.write_all(&buf)?;
+        let seq = self.commit(active_file, file_size + buf.len() as u64)?;
+        let pos = RecordPos {
+            offset: file_size,
+            len: buf.len() as u64,
+        };
+        self.active_file_hints
+            .lock()
+            .unwrap()
+            .push(Hint::new(&entry, pos));
+
+        if let Some(old) = self.keydir.remove(&key) {
+            self.total_bytes
+                .fetch_add(buf.len() as u64, Ordering::SeqCst);
+            self.add_stale(old.file_id, old.pos.len);
+            self.add_stale(self.active_file_id.load(Ordering::SeqCst), buf.len() as u64);
+        }
+
+        Ok(seq)
+    }
+
+    /// Swap the value of `key` if it is `expected`, reading and writing
+    /// under the writer so that no write can come in between.
+    fn compare_and_swap(
         &self,
-        keydir: &HashMap<String, LogPointer>,
-        key: String,
-    ) -> Result<Option<String>> {
-        if let Some(log_pointer) = keydir.get(&key) {
-            let file = OpenOptions::new()
-                .read(true)
-                .open(log_pointer.path.clone())?;
-            let mut reader = BufReader::new(file);
-            reader.seek(SeekFrom::Start(log_pointer.offset as u64))?;
-            let mut entry_string = String::new();
-            reader.read_line(&mut entry_string)?;
-            let entry: Entry = serde_json::from_str(&entry_string)?;
-
-            if let Entry::Set { value, .. } = entry {
-                // println!("<===== Get key: {:?}, value: {:?}", key, value);
-                Ok(Some(value))
-            } else {
-                return Err(EngineError::NotFound(
-                    "DB log error, there should be a Set entry".to_owned(),
-                ));
-            }
-        } else {
-            Ok(None)
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+        expires_at: Option<u64>,
+    ) -> Result<bool> {
+        let mut active_file = self.active_file_writer.lock().unwrap();
+        let current = self.read_value(&key)?;
+        if current != expected {
+            return Ok(false);
         }
+
+        let seq = match (current, new) {
+            (_, Some(value)) => self.write_set(&mut active_file, key, value, expires_at)?,
+            (Some(_), None) => self.write_remove(&mut active_file, key)?,
+            (None, None) => return Ok(true),
+        };
+        drop(active_file);
+
+        self.wait_synced(seq)?;
+        Ok(true)
     }
 }
 
@@ -303,70 +1070,286 @@ pub enum EngineError {
     #[error("Kvs: serealize json failed")]
     Serde(#[from] serde_json::Error),
 
+    /// bincode error
+    #[error("Kvs: serialize bincode failed")]
+    Bincode(#[from] bincode::Error),
+
+    /// Log file written by an unknown format version
+    #[error("Kvs: unsupported log format version `{0}`")]
+    UnsupportedFormat(u32),
+
+    /// Torn or corrupted log record at the given offset
+    #[error("Kvs: corrupted log record at offset `{0}`")]
+    Corrupted(u64),
+
+    /// Value read through a `String` method is not UTF-8
+    #[error("Kvs: value is not valid UTF-8")]
+    Utf8(#[from] std::string::FromUtf8Error),
+
+    /// Peer violating the protocol between kvs-server and its clients
+    #[error("Kvs: protocol error, {0}")]
+    Protocol(String),
+
+    /// Error replied by kvs-server
+    #[error("{message}")]
+    Server {
+        /// Reason
+        code: ErrorCode,
+        /// Message of the server
+        message: String,
+    },
+
     /// Unknown error
     #[error(transparent)]
     Unknown(#[from] anyhow::Error),
 }
 
+impl EngineError {
+    /// `NotFound` for a binary key
+    pub(crate) fn not_found(key: &[u8]) -> EngineError {
+        EngineError::NotFound(String::from_utf8_lossy(key).into_owned())
+    }
+}
+
 impl KvsEngine for KvStore {
-    fn set(&self, key: String, value: String) -> Result<()> {
-        let mut active_file = self.active_file_writer.lock().unwrap();
-        let file_size = active_file.get_ref().metadata()?.len() as usize;
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.inner.set(key, value)?;
+        self.maybe_compact();
+        Ok(())
+    }
 
-        // println!(
-        //     "=====> Set key: {:?}, value: {:?}, file: {:?}",
-        //     key, value, active_file
-        // );
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.inner.get(key)
+    }
 
-        let entry = Entry::Set {
-            key: key.clone(),
-            value: value.clone(),
-        };
-        writeln!(active_file, "{}", serde_json::to_string(&entry)?)?;
-        active_file.flush()?;
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        self.inner.remove(key)?;
+        self.maybe_compact();
+        Ok(())
+    }
 
-        let mut keydir = self.keydir.lock().unwrap();
-        keydir.insert(
-            key,
-            LogPointer {
-                path: self.active_file_path.clone(),
-                offset: file_size,
-            },
-        );
-        drop(keydir);
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.inner.set_with_ttl(key, value, ttl)?;
+        self.maybe_compact();
+        Ok(())
+    }
 
-        if file_size > self.file_threshold {
-            self.truncate_active_file(active_file.get_mut())?;
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.inner.apply_batch(batch)?;
+        self.maybe_compact();
+        Ok(())
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        let swapped = self.inner.compare_and_swap(key, expected, new, None)?;
+        self.maybe_compact();
+        Ok(swapped)
+    }
+
+    fn compare_and_swap_bytes_with_ttl(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<bool> {
+        let expires_at = expiration_time(ttl);
+        let swapped = self
+            .inner
+            .compare_and_swap(key, expected, Some(new), Some(expires_at))?;
+        self.maybe_compact();
+        Ok(swapped)
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<ScanBytes> {
+        if is_empty_range(&range) {
+            return Ok(Box::new(std::iter::empty()));
         }
 
-        drop(active_file);
+        // values are read lazily, keys removed meanwhile are skipped
+        let keys = self.inner.keydir.range(range);
+        let inner = self.inner.clone();
+        Ok(Box::new(keys.filter_map(
+            move |key| match inner.read_value(&key) {
+                Ok(Some(value)) => Some(Ok((key, value))),
+                Ok(None) => None,
+                Err(e) => Some(Err(e)),
+            },
+        )))
+    }
 
-        if self.total_size() > self.max_size {
-            self.compact()?;
+    fn scan_keys<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<ScanKeys> {
+        if is_empty_range(&range) {
+            return Ok(Box::new(std::iter::empty()));
         }
 
-        Ok(())
+        let keys = self.inner.keydir.range(range);
+        let inner = self.inner.clone();
+        Ok(Box::new(
+            keys.filter(move |key| inner.live_pointer(key).is_some())
+                .map(Ok),
+        ))
+    }
+
+    fn scan_prefix_keys(&self, prefix: Vec<u8>) -> Result<ScanKeys> {
+        let keys = self.inner.keydir.prefix(prefix);
+        let inner = self.inner.clone();
+        Ok(Box::new(
+            keys.filter(move |key| inner.live_pointer(key).is_some())
+                .map(Ok),
+        ))
     }
 
-    fn get(&self, key: String) -> Result<Option<String>> {
-        let keydir = self.keydir.lock().unwrap();
-        self.read_value(&*keydir, key)
+    fn sync(&self) -> Result<()> {
+        self.inner.sync()
     }
+}
 
-    fn remove(&self, key: String) -> Result<()> {
-        let mut keydir = self.keydir.lock().unwrap();
-        if !keydir.contains_key(&key) {
-            return Err(EngineError::NotFound(key));
+pub(crate) fn now_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis()
+}
+
+/// Expiration time of a value set now for `ttl`, in milliseconds since
+/// the unix epoch. A time too far to be counted saturates, the value then
+/// never expires.
+pub(crate) fn expiration_time(ttl: Duration) -> u64 {
+    u64::try_from(now_millis().saturating_add(ttl.as_millis())).unwrap_or(u64::MAX)
+}
+
+/// Path of a new data or compact file, stamped with the current time.
+/// The stamp is bumped past `last_stamp`, the files are loaded in the order
+/// of their stamps even if several are created in the same millisecond and
+/// the older ones are removed by a compaction meanwhile.
+fn new_file_path(dir_path: &Path, prefix: &str, last_stamp: &AtomicU64) -> PathBuf {
+    loop {
+        let now = u64::try_from(now_millis()).unwrap_or(u64::MAX);
+        let next = |last: u64| last.saturating_add(1).max(now);
+        let last = last_stamp
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| Some(next(last)))
+            .unwrap_or_else(|last| last);
+        let path = dir_path.join(format!("{}-{}.log", prefix, next(last)));
+        if !path.exists() {
+            return path;
         }
+    }
+}
 
-        let mut active_file = self.active_file_writer.lock().unwrap();
-        let entry = Entry::Remove { key: key.clone() };
-        writeln!(active_file, "{}", serde_json::to_string(&entry)?)?;
-        active_file.flush()?;
-        drop(active_file);
+/// Newest stamp of the data and compact files in `dir_path`.
+fn last_file_stamp(dir_path: &Path) -> Result<u64> {
+    let mut last = 0;
+    for entry in std::fs::read_dir(dir_path)? {
+        if let Some(stamp) = file_stamp(&entry?.path()) {
+            last = last.max(u64::try_from(stamp).unwrap_or(u64::MAX));
+        }
+    }
+    Ok(last)
+}
 
-        keydir.remove(&key);
+/// Timestamp in the name of data and compact files.
+fn file_stamp(path: &Path) -> Option<u128> {
+    let stem = path.file_stem()?.to_str()?;
+    let (_, stamp) = stem.split_once('-')?;
+    stamp.parse().ok()
+}
 
-        Ok(())
+fn is_data_file(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with("data"))
+}
+
+/// Make the renames and removals in `dir_path` durable.
+fn sync_dir(dir_path: &Path) -> Result<()> {
+    File::open(dir_path)?.sync_all()?;
+    Ok(())
+}
+
+/// Open the active file, a fresh file starts with the binary header.
+fn open_active_file(path: &Path) -> Result<File> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(path)?;
+
+    if file.metadata()?.len() == 0 {
+        record::write_header(&mut file)?;
     }
+
+    Ok(file)
+}
+
+/// Rename the active file to a data file.
+fn seal_active_file(
+    dir_path: &Path,
+    active_file_path: &Path,
+    last_stamp: &AtomicU64,
+) -> Result<PathBuf> {
+    let data_file_path = new_file_path(dir_path, "data", last_stamp);
+
+    info!(
+        "truncate active file, rename to filename: {:?}",
+        data_file_path
+    );
+    std::fs::rename(active_file_path, &data_file_path)?;
+
+    Ok(data_file_path)
+}
+
+/// Truncate a torn or corrupted tail of the active file back to the last
+/// good record.
+fn recover_active_file(path: &Path, buffer_size: usize) -> Result<Option<RecoveryReport>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let file = OpenOptions::new().read(true).open(path)?;
+    let corrupted = match LogIter::new(file, buffer_size) {
+        Ok(Some(entries)) => {
+            let mut corrupted = None;
+            for item in entries {
+                match item {
+                    Ok(_) => {}
+                    Err(EngineError::Corrupted(offset)) => {
+                        corrupted = Some(offset);
+                        break;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            corrupted
+        }
+        Ok(None) => None,
+        Err(EngineError::Corrupted(offset)) => Some(offset),
+        Err(e) => return Err(e),
+    };
+
+    let offset = match corrupted {
+        Some(offset) => offset,
+        None => return Ok(None),
+    };
+
+    let file = OpenOptions::new().write(true).open(path)?;
+    let file_len = file.metadata()?.len();
+    file.set_len(offset)?;
+    file.sync_all()?;
+
+    let report = RecoveryReport {
+        path: path.to_owned(),
+        offset,
+        dropped_bytes: file_len - offset,
+    };
+    warn!(
+        "dropped {} bytes of torn or corrupted records at offset {} of {:?}",
+        report.dropped_bytes, report.offset, report.path
+    );
+
+    Ok(Some(report))
 }
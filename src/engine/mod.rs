@@ -1,24 +1,191 @@
 //! kvs engine
 
+mod batch;
+mod keydir;
 pub mod kvs;
+mod options;
+mod record;
 pub mod sled_engine;
 
+pub use crate::engine::batch::{BatchOp, WriteBatch};
 pub use crate::engine::kvs::EngineError;
 pub use crate::engine::kvs::KvStore;
+pub use crate::engine::kvs::RecoveryReport;
 pub use crate::engine::kvs::Result;
+pub use options::{KvStoreOptions, SyncPolicy};
 pub use sled_engine::SledEngine;
 
+use std::ops::{Bound, RangeBounds};
+use std::time::Duration;
+
+/// Key/value pairs returned by a scan, in key order
+pub type Scan = Box<dyn Iterator<Item = Result<(String, String)>> + Send>;
+
+/// Binary key/value pairs returned by a scan, in key order
+pub type ScanBytes = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + Send>;
+
+/// Binary keys returned by a scan, in order
+pub type ScanKeys = Box<dyn Iterator<Item = Result<Vec<u8>>> + Send>;
+
 /// Storage interface called by KvsServer
+///
+/// Keys and values are arbitrary bytes, the `String` methods are
+/// convenience wrappers that fail with `EngineError::Utf8` on values that
+/// are not UTF-8.
 pub trait KvsEngine: Clone + Send + 'static {
+    /// Set the value of a key.
+    /// Return an error if the value is not written successfully.
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+
+    /// Set the value of a key for `ttl`, the key is gone once it expires.
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()>;
+
+    /// Get the value of a key.
+    /// Return an error if the value is not read successfully.
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+
+    /// Remove the given key.
+    /// Return an error if the key does not exist, or value is not read successfully.
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()>;
+
+    /// Apply the operations of `batch` in order, either all of them are
+    /// written or none is.
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    /// Set the value of `key` to `new`, or remove it if `new` is `None`,
+    /// only if its current value is `expected`, `None` meaning the key
+    /// doesn't exist. Returns whether the value was swapped.
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool>;
+
+    /// `compare_and_swap_bytes` setting `new` for `ttl`, the key is gone
+    /// once it expires.
+    fn compare_and_swap_bytes_with_ttl(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<bool>;
+
+    /// Iterate over the key/value pairs whose key is in `range`, in key
+    /// order. Writes made during the scan may or may not be seen.
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<ScanBytes>;
+
+    /// Write the buffered writes to disk and sync them, so that they
+    /// survive a power loss whatever the sync policy.
+    fn sync(&self) -> Result<()>;
+
+    /// Iterate over the key/value pairs whose key starts with `prefix`, in
+    /// key order.
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>) -> Result<ScanBytes> {
+        let scan = self.scan_bytes(prefix.clone()..)?;
+        Ok(Box::new(scan.take_while(move |item| match item {
+            Ok((key, _)) => key.starts_with(&prefix),
+            Err(_) => true,
+        })))
+    }
+
+    /// Iterate over the keys in `range`, in order, without reading their
+    /// values when the engine can tell them apart.
+    fn scan_keys<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<ScanKeys> {
+        Ok(Box::new(
+            self.scan_bytes(range)?.map(|item| item.map(|(key, _)| key)),
+        ))
+    }
+
+    /// Iterate over the keys starting with `prefix`, in order, without
+    /// reading their values when the engine can tell them apart.
+    fn scan_prefix_keys(&self, prefix: Vec<u8>) -> Result<ScanKeys> {
+        let scan = self.scan_keys(prefix.clone()..)?;
+        Ok(Box::new(scan.take_while(move |item| match item {
+            Ok(key) => key.starts_with(&prefix),
+            Err(_) => true,
+        })))
+    }
+
     /// Set the value of a string key to a string.
     /// Return an error if the value is not written successfully.
-    fn set(&self, key: String, value: String) -> Result<()>;
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Set the value of a string key to a string for `ttl`, the key is
+    /// gone once it expires.
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_bytes_with_ttl(key.into_bytes(), value.into_bytes(), ttl)
+    }
 
     /// Get the value of a string key.
     /// Return an error if the value is not read successfully.
-    fn get(&self, key: String) -> Result<Option<String>>;
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
 
     /// Remove the given string key.
     /// Return an error if the key does not exist, or value is not read successfully.
-    fn remove(&self, key: String) -> Result<()>;
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// `compare_and_swap_bytes` for strings.
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.compare_and_swap_bytes(
+            key.into_bytes(),
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )
+    }
+
+    /// Set the value of `key` only if it doesn't exist yet. Returns whether
+    /// the value was set.
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        self.compare_and_swap(key, None, Some(value))
+    }
+
+    /// `scan_bytes` for strings.
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<Scan> {
+        let bounds = (
+            range.start_bound().map(|key| key.clone().into_bytes()),
+            range.end_bound().map(|key| key.clone().into_bytes()),
+        );
+        Ok(Box::new(self.scan_bytes(bounds)?.map(decode_pair)))
+    }
+
+    /// `scan_prefix_bytes` for strings.
+    fn scan_prefix(&self, prefix: String) -> Result<Scan> {
+        Ok(Box::new(
+            self.scan_prefix_bytes(prefix.into_bytes())?
+                .map(decode_pair),
+        ))
+    }
+}
+
+fn decode_pair(item: Result<(Vec<u8>, Vec<u8>)>) -> Result<(String, String)> {
+    let (key, value) = item?;
+    Ok((String::from_utf8(key)?, String::from_utf8(value)?))
+}
+
+/// Whether a range can't hold any key. Ranges with the start after the end
+/// are empty rather than invalid.
+pub(crate) fn is_empty_range<T: Ord, R: RangeBounds<T>>(range: &R) -> bool {
+    match (range.start_bound(), range.end_bound()) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end))
+        | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        _ => false,
+    }
 }
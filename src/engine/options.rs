@@ -0,0 +1,111 @@
+//! Options of KvStore
+
+use std::time::Duration;
+
+/// When writes are synced to disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Flush writes to the OS, they survive a crash of the process but may
+    /// be lost on power loss.
+    Flush,
+
+    /// Sync every write to disk before it is acknowledged.
+    Always,
+
+    /// Every write is on disk before it is acknowledged, but writers
+    /// arriving at the same time share a single sync.
+    GroupCommit,
+
+    /// Sync in the background at the given interval, a power loss loses at
+    /// most the writes of the last interval.
+    Interval(Duration),
+}
+
+/// Options to open a KvStore with, see `KvStore::open_with`.
+///
+/// ```no_run
+/// use kvs::{KvStore, KvStoreOptions, SyncPolicy};
+///
+/// let options = KvStoreOptions::new()
+///     .segment_size(64 * 1024 * 1024)
+///     .sync_policy(SyncPolicy::Always)
+///     .create_dir(true);
+/// let store = KvStore::open_with("/tmp/kvs", options)?;
+/// # Ok::<(), kvs::engine::EngineError>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    pub(crate) segment_size: u64,
+    pub(crate) compaction_ratio: f64,
+    pub(crate) compaction_min_bytes: u64,
+    pub(crate) sync_policy: SyncPolicy,
+    pub(crate) read_buffer_size: usize,
+    pub(crate) write_buffer_size: usize,
+    pub(crate) create_dir: bool,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> KvStoreOptions {
+        KvStoreOptions {
+            segment_size: 10 * 1024,
+            compaction_ratio: 0.5,
+            compaction_min_bytes: 5 * 10 * 1024,
+            sync_policy: SyncPolicy::Flush,
+            read_buffer_size: 8 * 1024,
+            write_buffer_size: 8 * 1024,
+            create_dir: false,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// Options `KvStore::open` uses.
+    pub fn new() -> KvStoreOptions {
+        KvStoreOptions::default()
+    }
+
+    /// Size after which the active file is sealed and a new one is
+    /// started, 10 KiB by default.
+    pub fn segment_size(mut self, bytes: u64) -> KvStoreOptions {
+        self.segment_size = bytes;
+        self
+    }
+
+    /// Share of stale bytes in the log files that triggers a compaction,
+    /// 0.5 by default.
+    pub fn compaction_ratio(mut self, ratio: f64) -> KvStoreOptions {
+        self.compaction_ratio = ratio;
+        self
+    }
+
+    /// Stale bytes needed before a compaction is triggered at all,
+    /// 50 KiB by default.
+    pub fn compaction_min_bytes(mut self, bytes: u64) -> KvStoreOptions {
+        self.compaction_min_bytes = bytes;
+        self
+    }
+
+    /// When writes are synced to disk, `SyncPolicy::Flush` by default.
+    pub fn sync_policy(mut self, policy: SyncPolicy) -> KvStoreOptions {
+        self.sync_policy = policy;
+        self
+    }
+
+    /// Buffer size used to scan log files, 8 KiB by default.
+    pub fn read_buffer_size(mut self, bytes: usize) -> KvStoreOptions {
+        self.read_buffer_size = bytes;
+        self
+    }
+
+    /// Buffer size of the active file writer, 8 KiB by default.
+    pub fn write_buffer_size(mut self, bytes: usize) -> KvStoreOptions {
+        self.write_buffer_size = bytes;
+        self
+    }
+
+    /// Create the directory if it doesn't exist, off by default.
+    pub fn create_dir(mut self, create: bool) -> KvStoreOptions {
+        self.create_dir = create;
+        self
+    }
+}
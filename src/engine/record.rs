@@ -0,0 +1,603 @@
+//! On-disk record format of KvStore log files
+//!
+//! Every log file written by this version starts with an 8 byte header,
+//! 4 bytes magic followed by a big-endian u32 format version. Each record
+//! after the header is a big-endian u32 CRC32 checksum, a big-endian u32
+//! payload length and the bincode encoded `Entry`, keys and values as raw
+//! bytes. The checksum covers the
+//! length and the payload, so a torn or corrupted record is detected
+//! instead of being decoded.
+//!
+//! The top bit of the length marks a batch record, whose payload is a run
+//! of records framed as above. The outer checksum covers all of them, so a
+//! batch is replayed whole or not at all, while each inner record can still
+//! be read on its own.
+//!
+//! Files without the header are logs written by older versions, one
+//! serde_json encoded `Entry` per line. Version 1 files hold records
+//! without the checksum, a big-endian u32 payload length and the payload.
+//! Both are still readable, but never appended to.
+//!
+//! Sealed data files and compact files may have a hint file next to them,
+//! `data-<stamp>.hint` for `data-<stamp>.log`. It starts with its own
+//! header and holds a record per entry of the data file with the key and
+//! the position of its record, framed like log records, so the keydir can
+//! be rebuilt without reading the values.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, prelude::*, BufReader, BufWriter, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::engine::kvs::{EngineError, Entry, Result};
+
+/// Magic bytes at the beginning of every binary log file
+pub const MAGIC: [u8; 4] = *b"KVSL";
+
+/// Current version of the binary log format
+pub const VERSION: u32 = 3;
+
+/// First version of the binary log format, its records have no checksum
+const V1: u32 = 1;
+
+/// Oldest version of the binary log format with checksummed records, it
+/// has no batch records
+const MIN_VERSION: u32 = 2;
+
+/// Length of the file header
+pub const HEADER_LEN: u64 = 8;
+
+/// Length of the checksum and length prefix of each record
+const RECORD_HEADER_LEN: u64 = 8;
+
+/// Length of the length prefix of version 1 records
+const LEN_PREFIX: u64 = 4;
+
+/// Flag in the length prefix of batch records
+const BATCH_FLAG: u32 = 1 << 31;
+
+/// Magic bytes at the beginning of every hint file
+pub const HINT_MAGIC: [u8; 4] = *b"KVSH";
+
+/// Current version of the hint file format
+pub const HINT_VERSION: u32 = 2;
+
+/// Format of a log file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Line delimited serde_json, written by older versions
+    Json,
+
+    /// Length-prefixed bincode records behind a versioned header
+    Binary,
+
+    /// Length-prefixed bincode records without checksum, written by
+    /// version 1
+    BinaryV1,
+}
+
+/// Position of a record inside a log file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordPos {
+    /// Offset of the record
+    pub offset: u64,
+
+    /// Length of the record, excluding the newline of json records
+    pub len: u64,
+}
+
+/// Hint entry, locates the record of a key inside its data file
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Hint {
+    /// The key is set by the record at `offset`
+    Set {
+        /// Key
+        key: Vec<u8>,
+        /// Offset of the record
+        offset: u64,
+        /// Length of the record
+        len: u64,
+    },
+
+    /// The key is removed by a tombstone of `len` bytes
+    Remove {
+        /// Key
+        key: Vec<u8>,
+        /// Length of the tombstone record
+        len: u64,
+    },
+
+    /// The key is set by the record at `offset` until `expires_at`
+    SetWithTtl {
+        /// Key
+        key: Vec<u8>,
+        /// Offset of the record
+        offset: u64,
+        /// Length of the record
+        len: u64,
+        /// Expiration time, in milliseconds since the unix epoch
+        expires_at: u64,
+    },
+}
+
+impl Hint {
+    /// Hint of the record of `entry` at `pos`.
+    pub fn new(entry: &Entry, pos: RecordPos) -> Hint {
+        match entry {
+            Entry::Set { key, .. } => Hint::Set {
+                key: key.clone(),
+                offset: pos.offset,
+                len: pos.len,
+            },
+            Entry::Remove { key } => Hint::Remove {
+                key: key.clone(),
+                len: pos.len,
+            },
+            Entry::SetWithTtl {
+                key, expires_at, ..
+            } => Hint::SetWithTtl {
+                key: key.clone(),
+                offset: pos.offset,
+                len: pos.len,
+                expires_at: *expires_at,
+            },
+        }
+    }
+}
+
+/// `Entry` of the older json logs, which only held text
+#[derive(Deserialize)]
+enum JsonEntry {
+    Set { key: String, value: String },
+    Remove { key: String },
+}
+
+impl From<JsonEntry> for Entry {
+    fn from(entry: JsonEntry) -> Entry {
+        match entry {
+            JsonEntry::Set { key, value } => Entry::Set {
+                key: key.into_bytes(),
+                value: value.into_bytes(),
+            },
+            JsonEntry::Remove { key } => Entry::Remove {
+                key: key.into_bytes(),
+            },
+        }
+    }
+}
+
+/// Write the file header, the file should be empty.
+pub fn write_header<W: Write>(writer: &mut W) -> Result<()> {
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&VERSION.to_be_bytes())?;
+    Ok(())
+}
+
+/// Detect the format of a log file, `None` if the file is empty.
+pub fn detect_format(file: &mut File) -> Result<Option<LogFormat>> {
+    let file_len = file.metadata()?.len();
+    if file_len == 0 {
+        return Ok(None);
+    }
+
+    let mut header = [0; HEADER_LEN as usize];
+    file.seek(SeekFrom::Start(0))?;
+    let n = read_full(file, &mut header)?;
+    if n < HEADER_LEN as usize {
+        // crashed while writing the header of a new file
+        if MAGIC.starts_with(&header[..n.min(MAGIC.len())]) {
+            return Err(EngineError::Corrupted(0));
+        }
+        return Ok(Some(LogFormat::Json));
+    }
+    if header[..4] != MAGIC {
+        return Ok(Some(LogFormat::Json));
+    }
+
+    let mut version = [0; 4];
+    version.copy_from_slice(&header[4..]);
+    let version = u32::from_be_bytes(version);
+    if version == V1 {
+        return Ok(Some(LogFormat::BinaryV1));
+    }
+    if !(MIN_VERSION..=VERSION).contains(&version) {
+        return Err(EngineError::UnsupportedFormat(version));
+    }
+
+    Ok(Some(LogFormat::Binary))
+}
+
+/// Encode an entry as a checksummed, length-prefixed record.
+pub fn encode(entry: &Entry) -> Result<Vec<u8>> {
+    frame(&bincode::serialize(entry)?, 0)
+}
+
+/// Encode entries as a single batch record. Returns the positions of the
+/// inner records, relative to the start of the batch record.
+pub fn encode_batch(entries: &[Entry]) -> Result<(Vec<u8>, Vec<RecordPos>)> {
+    let mut payload = Vec::new();
+    let mut positions = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let record = encode(entry)?;
+        positions.push(RecordPos {
+            offset: RECORD_HEADER_LEN + payload.len() as u64,
+            len: record.len() as u64,
+        });
+        payload.extend_from_slice(&record);
+    }
+
+    Ok((frame(&payload, BATCH_FLAG)?, positions))
+}
+
+/// Prefix a payload with its checksum and length, or'ed with `flags`.
+/// The length must leave the flag bits clear.
+fn frame(payload: &[u8], flags: u32) -> Result<Vec<u8>> {
+    if payload.len() as u64 >= BATCH_FLAG as u64 {
+        return Err(EngineError::Unknown(anyhow::anyhow!(
+            "record of {} bytes is too large",
+            payload.len()
+        )));
+    }
+
+    let mut buf = Vec::with_capacity(RECORD_HEADER_LEN as usize + payload.len());
+    buf.extend_from_slice(&[0; 4]);
+    buf.extend_from_slice(&(payload.len() as u32 | flags).to_be_bytes());
+    buf.extend_from_slice(payload);
+    let crc = crc32fast::hash(&buf[4..]);
+    buf[..4].copy_from_slice(&crc.to_be_bytes());
+    Ok(buf)
+}
+
+/// Split the first framed payload off `bytes`, `None` if it is torn or
+/// its checksum doesn't match.
+fn unframe(bytes: &[u8]) -> Option<(&[u8], &[u8])> {
+    if bytes.len() < RECORD_HEADER_LEN as usize {
+        return None;
+    }
+    let crc = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    let len = record_len(bytes);
+    let end = RECORD_HEADER_LEN as usize + len;
+    if bytes.len() < end || crc32fast::hash(&bytes[4..end]) != crc {
+        return None;
+    }
+    Some((&bytes[RECORD_HEADER_LEN as usize..end], &bytes[end..]))
+}
+
+/// Payload length in the prefix of a record, without the flags.
+fn record_len(header: &[u8]) -> usize {
+    let len = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+    (len & !BATCH_FLAG) as usize
+}
+
+fn is_batch(header: &[u8]) -> bool {
+    header[4] & (BATCH_FLAG >> 24) as u8 != 0
+}
+
+/// Decode a record read from `RecordPos`, `offset` is only used to report
+/// corruption.
+pub fn decode(format: LogFormat, offset: u64, bytes: &[u8]) -> Result<Entry> {
+    let entry = match format {
+        LogFormat::Json => serde_json::from_slice::<JsonEntry>(bytes)
+            .map_err(|_| EngineError::Corrupted(offset))?
+            .into(),
+        LogFormat::Binary => match unframe(bytes) {
+            Some((payload, [])) if !is_batch(bytes) => {
+                bincode::deserialize(payload).map_err(|_| EngineError::Corrupted(offset))?
+            }
+            _ => return Err(EngineError::Corrupted(offset)),
+        },
+        LogFormat::BinaryV1 => match bytes.split_at_checked(LEN_PREFIX as usize) {
+            Some((len, payload)) if len == (payload.len() as u32).to_be_bytes() => {
+                bincode::deserialize(payload).map_err(|_| EngineError::Corrupted(offset))?
+            }
+            _ => return Err(EngineError::Corrupted(offset)),
+        },
+    };
+    Ok(entry)
+}
+
+/// Read the record at `pos` of a log file.
+pub fn read_entry(file: &File, format: LogFormat, pos: RecordPos) -> Result<Entry> {
+    let mut buf = vec![0; pos.len as usize];
+    read_exact_at(file, &mut buf, pos.offset)?;
+    decode(format, pos.offset, &buf)
+}
+
+/// Read the record at `pos` of a log file as a binary record, ready to be
+/// copied to another log file.
+pub fn read_record(file: &File, format: LogFormat, pos: RecordPos) -> Result<Vec<u8>> {
+    let mut buf = vec![0; pos.len as usize];
+    read_exact_at(file, &mut buf, pos.offset)?;
+
+    match format {
+        LogFormat::Json | LogFormat::BinaryV1 => encode(&decode(format, pos.offset, &buf)?),
+        LogFormat::Binary => match unframe(&buf) {
+            Some((_, [])) if !is_batch(&buf) => Ok(buf),
+            _ => Err(EngineError::Corrupted(pos.offset)),
+        },
+    }
+}
+
+/// Scan all records of a log file, returning hints of the records in
+/// order. `None` if the file is empty.
+pub fn scan_hints(file: File, buffer_size: usize) -> Result<Option<(LogFormat, Vec<Hint>)>> {
+    let entries = match LogIter::new(file, buffer_size)? {
+        Some(entries) => entries,
+        None => return Ok(None),
+    };
+
+    let format = entries.format();
+    let mut hints = Vec::new();
+    for item in entries {
+        let (pos, entry) = item?;
+        hints.push(Hint::new(&entry, pos));
+    }
+
+    Ok(Some((format, hints)))
+}
+
+/// Path of the hint file of a data file
+pub fn hint_path(log_path: &Path) -> PathBuf {
+    log_path.with_extension("hint")
+}
+
+/// Write the hint file of a data file.
+///
+/// The hints are written to a temporary file first, so that a crash never
+/// leaves a partial hint file behind.
+pub fn write_hints(log_path: &Path, hints: &[Hint]) -> Result<()> {
+    let path = hint_path(log_path);
+    let tmp_path = path.with_extension("hint.tmp");
+
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    writer.write_all(&HINT_MAGIC)?;
+    writer.write_all(&HINT_VERSION.to_be_bytes())?;
+    for hint in hints {
+        writer.write_all(&frame(&bincode::serialize(hint)?, 0)?)?;
+    }
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+
+    std::fs::rename(tmp_path, path)?;
+    Ok(())
+}
+
+/// Read the hint file of a data file, `None` if there is no usable one.
+pub fn read_hints(log_path: &Path) -> Result<Option<Vec<Hint>>> {
+    let bytes = match std::fs::read(hint_path(log_path)) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    if bytes.len() < HEADER_LEN as usize
+        || bytes[..4] != HINT_MAGIC
+        || bytes[4..8] != HINT_VERSION.to_be_bytes()
+    {
+        return Ok(None);
+    }
+
+    let mut hints = Vec::new();
+    let mut rest = &bytes[HEADER_LEN as usize..];
+    while !rest.is_empty() {
+        let (payload, next) = match unframe(rest) {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+        match bincode::deserialize(payload) {
+            Ok(hint) => hints.push(hint),
+            Err(_) => return Ok(None),
+        }
+        rest = next;
+    }
+
+    Ok(Some(hints))
+}
+
+/// Positional read that leaves the cursor of the file alone, so a file
+/// handle can be shared by concurrent readers.
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Read until `buf` is full or the reader hits EOF.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match reader.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(m) => n += m,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+/// Iterate over all entries of a log file.
+///
+/// A torn or corrupted record yields `EngineError::Corrupted` with the
+/// offset of the record, the iterator should not be used afterwards.
+pub struct LogIter {
+    reader: BufReader<File>,
+    format: LogFormat,
+    pos: u64,
+    file_len: u64,
+    // entries of the batch record being replayed
+    batch: VecDeque<(RecordPos, Entry)>,
+}
+
+impl LogIter {
+    /// Create iterator for a log file reading through a buffer of
+    /// `buffer_size` bytes, `None` if the file is empty.
+    pub fn new(mut file: File, buffer_size: usize) -> Result<Option<LogIter>> {
+        let format = match detect_format(&mut file)? {
+            Some(format) => format,
+            None => return Ok(None),
+        };
+
+        let pos = match format {
+            LogFormat::Json => 0,
+            LogFormat::Binary | LogFormat::BinaryV1 => HEADER_LEN,
+        };
+        file.seek(SeekFrom::Start(pos))?;
+        let file_len = file.metadata()?.len();
+
+        Ok(Some(LogIter {
+            reader: BufReader::with_capacity(buffer_size, file),
+            format,
+            pos,
+            file_len,
+            batch: VecDeque::new(),
+        }))
+    }
+
+    /// Format of the underlying file
+    pub fn format(&self) -> LogFormat {
+        self.format
+    }
+
+    fn next_json(&mut self) -> Result<Option<(RecordPos, Entry)>> {
+        let mut line = Vec::new();
+        let n = self.reader.read_until(b'\n', &mut line)?;
+        if n == 0 {
+            return Ok(None);
+        }
+
+        let pos = RecordPos {
+            offset: self.pos,
+            len: line.strip_suffix(b"\n").unwrap_or(&line).len() as u64,
+        };
+        let entry = decode(LogFormat::Json, pos.offset, &line)?;
+        self.pos += n as u64;
+
+        Ok(Some((pos, entry)))
+    }
+
+    fn next_v1(&mut self) -> Result<Option<(RecordPos, Entry)>> {
+        let offset = self.pos;
+        let mut buf = vec![0; LEN_PREFIX as usize];
+        match read_full(&mut self.reader, &mut buf)? {
+            0 => return Ok(None),
+            n if n < buf.len() => return Err(EngineError::Corrupted(offset)),
+            _ => {}
+        }
+        let len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+        if offset + LEN_PREFIX + len as u64 > self.file_len {
+            return Err(EngineError::Corrupted(offset));
+        }
+
+        buf.resize(LEN_PREFIX as usize + len, 0);
+        if read_full(&mut self.reader, &mut buf[LEN_PREFIX as usize..])? < len {
+            return Err(EngineError::Corrupted(offset));
+        }
+
+        let pos = RecordPos {
+            offset,
+            len: buf.len() as u64,
+        };
+        let entry = decode(LogFormat::BinaryV1, offset, &buf)?;
+        self.pos += pos.len;
+
+        Ok(Some((pos, entry)))
+    }
+
+    fn next_binary(&mut self) -> Result<Option<(RecordPos, Entry)>> {
+        if let Some(next) = self.batch.pop_front() {
+            return Ok(Some(next));
+        }
+
+        let offset = self.pos;
+        let mut buf = vec![0; RECORD_HEADER_LEN as usize];
+        match read_full(&mut self.reader, &mut buf)? {
+            0 => return Ok(None),
+            n if n < buf.len() => return Err(EngineError::Corrupted(offset)),
+            _ => {}
+        }
+        let len = record_len(&buf);
+        if offset + RECORD_HEADER_LEN + len as u64 > self.file_len {
+            return Err(EngineError::Corrupted(offset));
+        }
+
+        buf.resize(RECORD_HEADER_LEN as usize + len, 0);
+        if read_full(&mut self.reader, &mut buf[RECORD_HEADER_LEN as usize..])? < len {
+            return Err(EngineError::Corrupted(offset));
+        }
+
+        let pos = RecordPos {
+            offset,
+            len: buf.len() as u64,
+        };
+        if is_batch(&buf) {
+            self.batch = decode_batch(offset, &buf)?;
+            self.pos += pos.len;
+            return self.next_binary();
+        }
+        let entry = decode(LogFormat::Binary, offset, &buf)?;
+        self.pos += pos.len;
+
+        Ok(Some((pos, entry)))
+    }
+}
+
+/// Decode all inner records of the batch record at `offset`, any bad inner
+/// record makes the whole batch corrupted.
+fn decode_batch(offset: u64, bytes: &[u8]) -> Result<VecDeque<(RecordPos, Entry)>> {
+    let mut rest = match unframe(bytes) {
+        Some((payload, [])) => payload,
+        _ => return Err(EngineError::Corrupted(offset)),
+    };
+
+    let mut entries = VecDeque::new();
+    let mut inner_offset = offset + RECORD_HEADER_LEN;
+    while !rest.is_empty() {
+        let (payload, next) = unframe(rest).ok_or(EngineError::Corrupted(offset))?;
+        if is_batch(rest) {
+            return Err(EngineError::Corrupted(offset));
+        }
+        let entry = bincode::deserialize(payload).map_err(|_| EngineError::Corrupted(offset))?;
+        let pos = RecordPos {
+            offset: inner_offset,
+            len: (rest.len() - next.len()) as u64,
+        };
+        entries.push_back((pos, entry));
+        inner_offset += pos.len;
+        rest = next;
+    }
+
+    Ok(entries)
+}
+
+impl Iterator for LogIter {
+    type Item = Result<(RecordPos, Entry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = match self.format {
+            LogFormat::Json => self.next_json(),
+            LogFormat::Binary => self.next_binary(),
+            LogFormat::BinaryV1 => self.next_v1(),
+        };
+        next.transpose()
+    }
+}
@@ -1,54 +1,316 @@
 //! sled engine
 
+use std::ops::RangeBounds;
 use std::path::PathBuf;
+use std::time::Duration;
 
 use anyhow::anyhow;
-use sled;
+use sled::transaction::{ConflictableTransactionError, TransactionError, Transactional};
+use sled::IVec;
 
-use crate::engine::KvsEngine;
+use crate::engine::kvs::{expiration_time, now_millis};
+use crate::engine::{
+    is_empty_range, BatchOp, KvsEngine, ScanBytes, ScanKeys, SyncPolicy, WriteBatch,
+};
 use crate::kvs::EngineError;
 use crate::Result;
 
+/// Name of the tree holding the expiration time of keys set with a ttl
+const EXPIRY_TREE: &str = "kvs-expiry";
 
 #[derive(Clone)]
 pub struct SledEngine {
     inner: sled::Db,
+    // key -> expiration time, big-endian milliseconds since the unix epoch
+    expiry: sled::Tree,
+    // flush every write before it is acknowledged
+    flush_writes: bool,
 }
 
 impl SledEngine {
-    /// Open a Sled database
+    /// Open a Sled database, syncing writes like `SyncPolicy::Flush` in
+    /// the background.
     pub fn open(path: impl Into<PathBuf>) -> Result<SledEngine> {
-        let db = sled::open(path.into()).map_err(|e| anyhow!(e))?;
+        SledEngine::open_with(path, SyncPolicy::Flush)
+    }
+
+    /// Open a Sled database syncing writes as told by `policy`.
+    ///
+    /// sled keeps writes in memory until it flushes them to disk. With
+    /// `SyncPolicy::Always` and `SyncPolicy::GroupCommit` every write is
+    /// flushed before it is acknowledged, the other policies leave it to
+    /// sled's background flush and a killed process loses the writes since
+    /// the last one.
+    pub fn open_with(path: impl Into<PathBuf>, policy: SyncPolicy) -> Result<SledEngine> {
+        let mut config = sled::Config::new().path(path.into());
+        let flush_writes = match policy {
+            SyncPolicy::Interval(interval) => {
+                let millis = u64::try_from(interval.as_millis()).unwrap_or(u64::MAX);
+                config = config.flush_every_ms(Some(millis.max(1)));
+                false
+            }
+            SyncPolicy::Flush => false,
+            SyncPolicy::Always | SyncPolicy::GroupCommit => true,
+        };
+        let db = config.open().map_err(|e| anyhow!(e))?;
+        let expiry = db.open_tree(EXPIRY_TREE).map_err(|e| anyhow!(e))?;
+
+        Ok(SledEngine {
+            inner: db,
+            expiry,
+            flush_writes,
+        })
+    }
+
+    /// Whether `key` has expired, an expired key is removed on the way.
+    fn expired(&self, key: &[u8]) -> Result<bool> {
+        let expires_at = match self.expiry.get(key).map_err(|e| anyhow!(e))? {
+            Some(expires_at) => expires_at,
+            None => return Ok(false),
+        };
+        if !is_expired(&expires_at) {
+            return Ok(false);
+        }
+
+        // unless the key was written again meanwhile
+        self.transaction(|db, expiry| {
+            if expiry.get(key)?.as_ref() == Some(&expires_at) {
+                db.remove(key)?;
+                expiry.remove(key)?;
+            }
+            Ok(())
+        })?;
+        Ok(true)
+    }
+
+    /// Run `f` in a transaction over the values and their expiration times.
+    fn transaction<F, T>(&self, f: F) -> Result<T>
+    where
+        F: Fn(
+            &sled::transaction::TransactionalTree,
+            &sled::transaction::TransactionalTree,
+        ) -> std::result::Result<T, ConflictableTransactionError<()>>,
+    {
+        let result = (&*self.inner, &self.expiry)
+            .transaction(|(db, expiry)| f(db, expiry))
+            .map_err(|e: TransactionError<()>| anyhow!("sled transaction failed: {:?}", e))?;
+        if self.flush_writes {
+            self.flush()?;
+        }
+        Ok(result)
+    }
+
+    /// Write the pending updates to disk, sled otherwise only does it in
+    /// the background and a killed process loses them.
+    fn flush(&self) -> Result<()> {
+        self.inner.flush().map_err(|e| anyhow!(e))?;
+        Ok(())
+    }
+
+    /// `compare_and_swap_bytes` setting `new` until `expires_at`, or for
+    /// good if `None`.
+    fn compare_and_swap_until(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+        expires_at: Option<u64>,
+    ) -> Result<bool> {
+        self.transaction(|db, expiry| {
+            // an expired value counts as absent
+            let current = match expiry.get(key.as_slice())? {
+                Some(expires_at) if is_expired(&expires_at) => None,
+                _ => db.get(key.as_slice())?,
+            };
+            if current.as_deref() != expected.as_deref() {
+                return Ok(false);
+            }
+
+            match &new {
+                Some(value) => db.insert(key.as_slice(), value.as_slice())?,
+                None => db.remove(key.as_slice())?,
+            };
+            match expires_at {
+                Some(expires_at) => expiry.insert(key.as_slice(), &expires_at.to_be_bytes())?,
+                None => expiry.remove(key.as_slice())?,
+            };
+            Ok(true)
+        })
+    }
+
+    /// Scanned pair, `None` if the key has expired.
+    fn live_pair(&self, item: sled::Result<(IVec, IVec)>) -> Option<Result<(Vec<u8>, Vec<u8>)>> {
+        let (key, value) = match item {
+            Ok(pair) => pair,
+            Err(e) => return Some(Err(anyhow!(e).into())),
+        };
+        match self.expired(&key) {
+            Ok(true) => None,
+            Ok(false) => Some(Ok((key.to_vec(), value.to_vec()))),
+            Err(e) => Some(Err(e)),
+        }
+    }
 
-        Ok(SledEngine { inner: db })
+    /// Key of a scanned pair, `None` if the key has expired.
+    fn live_key(&self, item: sled::Result<(IVec, IVec)>) -> Option<Result<Vec<u8>>> {
+        let key = match item {
+            Ok((key, _)) => key,
+            Err(e) => return Some(Err(anyhow!(e).into())),
+        };
+        match self.expired(&key) {
+            Ok(true) => None,
+            Ok(false) => Some(Ok(key.to_vec())),
+            Err(e) => Some(Err(e)),
+        }
     }
 }
 
 impl KvsEngine for SledEngine {
-    fn set(&self, key: String, value: String) -> Result<()> {
-        let _ = self
-            .inner
-            .insert(key.as_bytes(), value.as_bytes())
-            .map_err(|e| anyhow!(e))?;
-        Ok(())
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        // drop the expiration time of a previous `set_with_ttl`
+        self.transaction(|db, expiry| {
+            db.insert(key.as_slice(), value.as_slice())?;
+            expiry.remove(key.as_slice())?;
+            Ok(())
+        })
+    }
+
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let expires_at = expiration_time(ttl);
+        self.transaction(|db, expiry| {
+            db.insert(key.as_slice(), value.as_slice())?;
+            expiry.insert(key.as_slice(), &expires_at.to_be_bytes())?;
+            Ok(())
+        })
     }
 
-    fn get(&self, key: String) -> Result<Option<String>> {
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if self.expired(&key)? {
+            return Ok(None);
+        }
+
         let result = self
             .inner
             .get(key)
             .map_err(|e| anyhow!(e))?
-            .map(|iv| String::from_utf8_lossy(&iv).to_string());
+            .map(|iv| iv.to_vec());
 
         Ok(result)
     }
 
-    fn remove(&self, key: String) -> Result<()> {
-        let _ = self
-            .inner
-            .remove(key.clone())
-            .map_err(|e| anyhow!(e))?
-            .ok_or(EngineError::NotFound(key))?;
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        let removed = self.transaction(|db, expiry| {
+            let expired = expiry
+                .remove(key.as_slice())?
+                .is_some_and(|e| is_expired(&e));
+            Ok(db.remove(key.as_slice())?.is_some() && !expired)
+        })?;
+        if !removed {
+            return Err(EngineError::not_found(&key));
+        }
         Ok(())
     }
+
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()> {
+        let mut sled_batch = sled::Batch::default();
+        let mut expiry_batch = sled::Batch::default();
+        for op in batch {
+            match op {
+                BatchOp::Set { key, value } => {
+                    expiry_batch.remove(key.as_slice());
+                    sled_batch.insert(key, value);
+                }
+                BatchOp::Remove { key } => {
+                    expiry_batch.remove(key.as_slice());
+                    sled_batch.remove(key);
+                }
+            }
+        }
+        self.transaction(|db, expiry| {
+            db.apply_batch(&sled_batch)?;
+            expiry.apply_batch(&expiry_batch)?;
+            Ok(())
+        })
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        self.compare_and_swap_until(key, expected, new, None)
+    }
+
+    fn compare_and_swap_bytes_with_ttl(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<bool> {
+        let expires_at = expiration_time(ttl);
+        self.compare_and_swap_until(key, expected, Some(new), Some(expires_at))
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<ScanBytes> {
+        if is_empty_range(&range) {
+            return Ok(Box::new(std::iter::empty()));
+        }
+
+        let bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+        let engine = self.clone();
+        Ok(Box::new(
+            self.inner
+                .range(bounds)
+                .filter_map(move |item| engine.live_pair(item)),
+        ))
+    }
+
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>) -> Result<ScanBytes> {
+        let engine = self.clone();
+        Ok(Box::new(
+            self.inner
+                .scan_prefix(prefix)
+                .filter_map(move |item| engine.live_pair(item)),
+        ))
+    }
+
+    fn scan_keys<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<ScanKeys> {
+        if is_empty_range(&range) {
+            return Ok(Box::new(std::iter::empty()));
+        }
+
+        let bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+        let engine = self.clone();
+        Ok(Box::new(
+            self.inner
+                .range(bounds)
+                .filter_map(move |item| engine.live_key(item)),
+        ))
+    }
+
+    fn scan_prefix_keys(&self, prefix: Vec<u8>) -> Result<ScanKeys> {
+        let engine = self.clone();
+        Ok(Box::new(
+            self.inner
+                .scan_prefix(prefix)
+                .filter_map(move |item| engine.live_key(item)),
+        ))
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.flush()
+    }
+}
+
+/// Whether an expiration time read from the expiry tree is past.
+fn is_expired(expires_at: &[u8]) -> bool {
+    decode_millis(expires_at) as u128 <= now_millis()
+}
+
+fn decode_millis(bytes: &[u8]) -> u64 {
+    let mut millis = [0; 8];
+    millis.copy_from_slice(&bytes[..8]);
+    u64::from_be_bytes(millis)
 }
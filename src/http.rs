@@ -0,0 +1,257 @@
+//! HTTP/JSON gateway to a storage engine
+//!
+//! - `GET /keys/{key}` replies the raw value
+//! - `PUT /keys/{key}` sets the value to the raw request body
+//! - `DELETE /keys/{key}` removes the key
+//! - `GET /keys?prefix={prefix}&limit={limit}` replies
+//!   `{"keys": [...], "truncated": false}`, the first `limit` keys starting
+//!   with the prefix in key order, 1000 by default. `truncated` tells
+//!   whether more keys were left out.
+//! - `GET /health` replies `{"status": "ok"}`
+//!
+//! Keys are percent-decoded from the path and the query, so any bytes may
+//! be used. Listed keys are percent-encoded, but for letters, digits and
+//! `-._~`, ready to be put back in a path. Errors are replied as
+//! `{"error": {"code": "not_found", "message": "..."}}` with a matching
+//! status code.
+
+use std::io::{self, Read};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::Arc;
+
+use log::{debug, error};
+use percent_encoding::{percent_decode, percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde_json::json;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::engine::{EngineError, KvsEngine};
+use crate::protocol::MAX_FRAME_LEN;
+use crate::thread_pool::ThreadPool;
+
+type HttpResponse = Response<io::Cursor<Vec<u8>>>;
+
+/// Keys listed when the request gives no limit
+const DEFAULT_LIMIT: usize = 1000;
+
+/// Bytes percent-encoded in listed keys, all but the unreserved ones
+const KEY_ESCAPES: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// HTTP gateway serving requests with a shared engine.
+///
+/// ```no_run
+/// use std::net::TcpListener;
+/// use kvs::http::HttpGateway;
+/// use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+/// use kvs::KvStore;
+///
+/// let engine = KvStore::open(std::env::current_dir()?)?;
+/// let gateway = HttpGateway::new(engine, TcpListener::bind("127.0.0.1:8080")?)?;
+/// gateway.run(SharedQueueThreadPool::new(4)?);
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub struct HttpGateway<E: KvsEngine> {
+    engine: E,
+    server: Arc<Server>,
+}
+
+/// Stops a running `HttpGateway` from another thread.
+#[derive(Clone)]
+pub struct HttpStopper(Arc<Server>);
+
+impl HttpStopper {
+    /// Stop accepting requests, `HttpGateway::run` returns once the
+    /// requests being served are replied.
+    pub fn stop(&self) {
+        self.0.unblock();
+    }
+}
+
+impl<E: KvsEngine> HttpGateway<E> {
+    /// Gateway accepting connections on `listener`.
+    pub fn new(engine: E, listener: TcpListener) -> crate::Result<HttpGateway<E>> {
+        let server = Server::from_listener(listener, None)
+            .map_err(|e| EngineError::Unknown(anyhow::anyhow!(e)))?;
+        Ok(HttpGateway {
+            engine,
+            server: Arc::new(server),
+        })
+    }
+
+    /// Address the gateway listens on
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.server.server_addr().to_ip()
+    }
+
+    /// Handle to stop the gateway
+    pub fn stopper(&self) -> HttpStopper {
+        HttpStopper(Arc::clone(&self.server))
+    }
+
+    /// Serve requests, each one as a job of `pool`, until stopped.
+    pub fn run<P: ThreadPool>(self, pool: P) {
+        for request in self.server.incoming_requests() {
+            let engine = self.engine.clone();
+            pool.spawn(move || {
+                debug!("http request: {} {}", request.method(), request.url());
+                if let Err(e) = handle_request(&engine, request) {
+                    error!("replying http request failed: {:?}", e);
+                }
+            });
+        }
+        pool.shutdown();
+    }
+}
+
+fn handle_request<E: KvsEngine>(engine: &E, mut request: Request) -> io::Result<()> {
+    let response = route(engine, &mut request);
+    request.respond(response)
+}
+
+fn route<E: KvsEngine>(engine: &E, request: &mut Request) -> HttpResponse {
+    let url = request.url().to_owned();
+    let (path, query) = match url.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (url.as_str(), None),
+    };
+
+    let method = request.method().clone();
+    match (path, path.strip_prefix("/keys/")) {
+        ("/health", _) => match method {
+            Method::Get | Method::Head => json_response(200, json!({ "status": "ok" })),
+            _ => method_not_allowed("GET"),
+        },
+        ("/keys", _) => match method {
+            Method::Get => list(engine, query.unwrap_or_default()),
+            _ => method_not_allowed("GET"),
+        },
+        (_, Some(key)) => {
+            let key: Vec<u8> = percent_decode(key.as_bytes()).collect();
+            if key.is_empty() {
+                return error_response(400, "invalid_key", "empty key");
+            }
+            match method {
+                Method::Get => match engine.get_bytes(key.clone()) {
+                    Ok(Some(value)) => Response::from_data(value)
+                        .with_header(content_type("application/octet-stream")),
+                    Ok(None) => engine_error(EngineError::not_found(&key)),
+                    Err(e) => engine_error(e),
+                },
+                Method::Put => match read_body(request) {
+                    Ok(value) => match engine.set_bytes(key, value) {
+                        Ok(()) => Response::from_data(Vec::new()).with_status_code(204),
+                        Err(e) => engine_error(e),
+                    },
+                    Err(response) => response,
+                },
+                Method::Delete => match engine.remove_bytes(key) {
+                    Ok(()) => Response::from_data(Vec::new()).with_status_code(204),
+                    Err(e) => engine_error(e),
+                },
+                _ => method_not_allowed("GET, PUT, DELETE"),
+            }
+        }
+        _ => error_response(404, "not_found", format!("no route for {}", path)),
+    }
+}
+
+/// `GET /keys?prefix=&limit=`
+fn list<E: KvsEngine>(engine: &E, query: &str) -> HttpResponse {
+    let mut prefix = Vec::new();
+    let mut limit = DEFAULT_LIMIT;
+    for (name, value) in query.split('&').filter_map(|param| param.split_once('=')) {
+        match name {
+            "prefix" => prefix = decode_query(value),
+            "limit" => match value.parse() {
+                Ok(n) if n > 0 => limit = n,
+                _ => {
+                    return error_response(400, "invalid_limit", "limit must be a positive integer")
+                }
+            },
+            _ => {}
+        }
+    }
+
+    let keys = engine.scan_prefix_keys(prefix).and_then(|scan| {
+        // one more key tells whether the list is truncated
+        scan.take(limit.saturating_add(1))
+            .map(|item| item.map(|key| percent_encode(&key, KEY_ESCAPES).to_string()))
+            .collect::<crate::Result<Vec<_>>>()
+    });
+    match keys {
+        Ok(mut keys) => {
+            let truncated = keys.len() > limit;
+            keys.truncate(limit);
+            json_response(200, json!({ "keys": keys, "truncated": truncated }))
+        }
+        Err(e) => engine_error(e),
+    }
+}
+
+/// Body of a request, up to `MAX_FRAME_LEN` bytes.
+fn read_body(request: &mut Request) -> Result<Vec<u8>, HttpResponse> {
+    let mut body = Vec::new();
+    let read = request
+        .as_reader()
+        .take(MAX_FRAME_LEN as u64 + 1)
+        .read_to_end(&mut body);
+    match read {
+        Ok(_) if body.len() > MAX_FRAME_LEN as usize => Err(error_response(
+            413,
+            "too_large",
+            format!("value is over {} bytes", MAX_FRAME_LEN),
+        )),
+        Ok(_) => Ok(body),
+        Err(e) => Err(error_response(400, "invalid_body", e.to_string())),
+    }
+}
+
+/// Status code and error code of an engine failure
+fn status(e: &EngineError) -> (u16, &'static str) {
+    match e {
+        EngineError::NotFound(_) => (404, "not_found"),
+        EngineError::Utf8(_) => (400, "invalid_utf8"),
+        EngineError::Corrupted(_) | EngineError::UnsupportedFormat(_) => (500, "corrupted"),
+        EngineError::Protocol(_) | EngineError::Server { .. } => (502, "bad_gateway"),
+        EngineError::Io(_)
+        | EngineError::Serde(_)
+        | EngineError::Bincode(_)
+        | EngineError::Unknown(_) => (500, "internal"),
+    }
+}
+
+fn engine_error(e: EngineError) -> HttpResponse {
+    let (status, code) = status(&e);
+    error_response(status, code, e.to_string())
+}
+
+fn error_response(status: u16, code: &str, message: impl Into<String>) -> HttpResponse {
+    let message = message.into();
+    json_response(
+        status,
+        json!({ "error": { "code": code, "message": message } }),
+    )
+}
+
+fn method_not_allowed(allow: &str) -> HttpResponse {
+    error_response(405, "method_not_allowed", "method not allowed")
+        .with_header(Header::from_bytes(&b"Allow"[..], allow.as_bytes()).unwrap())
+}
+
+fn json_response(status: u16, body: serde_json::Value) -> HttpResponse {
+    Response::from_data(body.to_string().into_bytes())
+        .with_status_code(status)
+        .with_header(content_type("application/json"))
+}
+
+fn content_type(value: &str) -> Header {
+    Header::from_bytes(&b"Content-Type"[..], value.as_bytes()).unwrap()
+}
+
+/// Query parameter value, `+` standing for a space
+fn decode_query(value: &str) -> Vec<u8> {
+    percent_decode(value.replace('+', " ").as_bytes()).collect()
+}
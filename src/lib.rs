@@ -1,12 +1,24 @@
 //! KvStore library
 
+pub mod client;
 pub mod engine;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod protocol;
+pub mod resp;
+pub mod server;
 
 /// thread pool
 pub mod thread_pool;
 
 pub use engine::kvs;
 pub use engine::KvStore;
+pub use engine::KvStoreOptions;
 pub use engine::KvsEngine;
 pub use engine::Result;
+pub use engine::Scan;
+pub use engine::ScanBytes;
+pub use engine::ScanKeys;
 pub use engine::SledEngine;
+pub use engine::SyncPolicy;
+pub use engine::WriteBatch;
@@ -0,0 +1,747 @@
+//! Wire protocol between kvs-server and its clients
+//!
+//! A connection starts with a handshake. The client sends `KVSP` followed
+//! by the lowest and the highest protocol version it speaks, as big-endian
+//! u32. The server answers `KVSP` followed by the version used for the rest
+//! of the connection, the highest one both sides speak, or 0 before closing
+//! the connection if there is none.
+//!
+//! Then each request and each reply is a frame, a big-endian u32 length
+//! followed by that many bytes:
+//!
+//! - a request frame holds a big-endian u64 request id chosen by the
+//!   client, an opcode byte and the fields of the request
+//! - a reply frame holds the id of the request it answers and a status
+//!   byte, 0 followed by the response on success, an `ErrorCode` followed
+//!   by a message otherwise
+//!
+//! A server too busy to serve a connection replies a `Busy` error to no
+//! request, with id 0, right after the handshake and closes the
+//! connection.
+//!
+//! Requests may be pipelined, they are replied in order. Byte strings are
+//! a big-endian u32 length followed by the bytes, optional ones are behind
+//! a byte 0 for none or 1 for some.
+
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+use crate::engine::{BatchOp, EngineError, WriteBatch};
+use crate::Result;
+
+/// First bytes of both sides of the handshake
+pub const MAGIC: [u8; 4] = *b"KVSP";
+
+/// Highest protocol version spoken by this version
+pub const VERSION: u32 = 1;
+
+/// Lowest protocol version spoken by this version
+pub const MIN_VERSION: u32 = 1;
+
+/// Largest request frame accepted by the server
+pub const MAX_FRAME_LEN: u32 = 64 * 1024 * 1024;
+
+const ID_LEN: u32 = 8;
+
+/// Request to kvs-server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    /// Get the value of a key, opcode `g`
+    Get {
+        /// Key
+        key: Vec<u8>,
+    },
+    /// Set the value of a key, opcode `s`
+    Set {
+        /// Key
+        key: Vec<u8>,
+        /// Value
+        value: Vec<u8>,
+    },
+    /// Set the value of a key until its ttl is over, opcode `t`, the ttl is
+    /// encoded as u64 milliseconds
+    SetWithTtl {
+        /// Key
+        key: Vec<u8>,
+        /// Value
+        value: Vec<u8>,
+        /// Time to live
+        ttl: Duration,
+    },
+    /// Remove a key, opcode `r`
+    Remove {
+        /// Key
+        key: Vec<u8>,
+    },
+    /// Pairs with keys from `start` up to `end`, excluded, opcode `c`
+    Scan {
+        /// First key
+        start: Vec<u8>,
+        /// End of the range, excluded, none for no end
+        end: Option<Vec<u8>>,
+    },
+    /// Pairs with keys starting with a prefix, opcode `p`
+    ScanPrefix {
+        /// Prefix
+        prefix: Vec<u8>,
+    },
+    /// Apply a write batch, opcode `b`, encoded as a u32 count of operations
+    /// then for each one `s` with a key and a value or `r` with a key
+    Batch(WriteBatch),
+    /// Compare and swap the value of a key, opcode `w`
+    CompareAndSwap {
+        /// Key
+        key: Vec<u8>,
+        /// Expected value, none if the key should not exist
+        expected: Option<Vec<u8>>,
+        /// New value, none to remove the key
+        new: Option<Vec<u8>>,
+    },
+    /// Statistics of the server, opcode `i`, replied as pairs of names and
+    /// decimal values
+    Stats,
+}
+
+/// Successful reply to a request, the first byte tells its kind
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    /// The write is done, kind 0
+    Done,
+    /// The value of a key, none if the key doesn't exist, kind 1
+    Value(Option<Vec<u8>>),
+    /// Pairs of a scan in key order, kind 2, encoded as a u32 count of
+    /// pairs then the key and the value of each pair
+    Pairs(Vec<(Vec<u8>, Vec<u8>)>),
+    /// Whether a compare and swap took place, kind 3
+    Swapped(bool),
+}
+
+/// Reason of a failed request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The key doesn't exist
+    NotFound = 1,
+    /// The request is malformed
+    InvalidRequest = 2,
+    /// The storage engine failed
+    Engine = 3,
+    /// The request frame is over `MAX_FRAME_LEN`
+    TooLarge = 4,
+    /// The request is not known to this server
+    Unsupported = 5,
+    /// The server is too busy to serve the connection
+    Busy = 6,
+}
+
+impl ErrorCode {
+    fn from_u8(code: u8) -> Option<ErrorCode> {
+        let code = match code {
+            1 => ErrorCode::NotFound,
+            2 => ErrorCode::InvalidRequest,
+            3 => ErrorCode::Engine,
+            4 => ErrorCode::TooLarge,
+            5 => ErrorCode::Unsupported,
+            6 => ErrorCode::Busy,
+            _ => return None,
+        };
+        Some(code)
+    }
+}
+
+/// Failed reply to a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorReply {
+    /// Reason
+    pub code: ErrorCode,
+    /// Human readable message
+    pub message: String,
+}
+
+impl ErrorReply {
+    /// Error reply with the given code
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> ErrorReply {
+        ErrorReply {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<EngineError> for ErrorReply {
+    fn from(e: EngineError) -> ErrorReply {
+        let code = match e {
+            EngineError::NotFound(_) => ErrorCode::NotFound,
+            _ => ErrorCode::Engine,
+        };
+        ErrorReply::new(code, e.to_string())
+    }
+}
+
+impl From<ErrorReply> for EngineError {
+    fn from(e: ErrorReply) -> EngineError {
+        EngineError::Server {
+            code: e.code,
+            message: e.message,
+        }
+    }
+}
+
+/// Outcome of a request
+pub type ReplyResult = std::result::Result<Response, ErrorReply>;
+
+/// Client side of the handshake, returns the version of the connection.
+pub fn handshake<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> Result<u32> {
+    writer.write_all(&hello())?;
+    writer.flush()?;
+
+    let mut answer = [0; 8];
+    reader.read_exact(&mut answer)?;
+    check_answer(answer)
+}
+
+/// Server side of the handshake, returns the version of the connection.
+pub fn accept_handshake<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> Result<u32> {
+    let mut hello = [0; 12];
+    reader.read_exact(&mut hello)?;
+    let (answer, version) = negotiate(hello)?;
+    writer.write_all(&answer)?;
+    writer.flush()?;
+    version
+}
+
+/// Write a frame holding `payload`, fails if its length doesn't fit the
+/// u32 of the frame.
+pub fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    writer.write_all(&frame_len(payload)?.to_be_bytes())?;
+    writer.write_all(payload)
+}
+
+/// Write a request frame.
+pub fn write_request<W: Write>(writer: &mut W, id: u64, request: &Request) -> io::Result<()> {
+    write_frame(writer, &request_payload(id, request))
+}
+
+/// Read the next request frame, `None` once the client closed the
+/// connection. A request that can't be served comes with the error to
+/// reply, the stream is still usable for the next requests.
+pub fn read_request<R: Read>(
+    reader: &mut R,
+) -> io::Result<Option<(u64, std::result::Result<Request, ErrorReply>)>> {
+    let len = match read_u32(reader) {
+        Ok(len) => len,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if len < ID_LEN {
+        discard(reader, len)?;
+        return Ok(Some((0, Err(no_id()))));
+    }
+
+    let id = read_u64(reader)?;
+    if len > MAX_FRAME_LEN {
+        discard(reader, len - ID_LEN)?;
+        return Ok(Some((id, Err(too_large(len)))));
+    }
+
+    let mut body = vec![0; (len - ID_LEN) as usize];
+    reader.read_exact(&mut body)?;
+    Ok(Some((id, Request::decode(&body))))
+}
+
+/// Write the reply frame to the request `id`.
+pub fn write_reply<W: Write>(writer: &mut W, id: u64, reply: &ReplyResult) -> io::Result<()> {
+    write_frame(writer, &reply_payload(id, reply))
+}
+
+/// Read a reply frame, returns the id of the request it answers.
+pub fn read_reply<R: Read>(reader: &mut R) -> Result<(u64, ReplyResult)> {
+    let len = check_reply_len(read_u32(reader)?)?;
+    let mut buf = vec![0; len as usize];
+    reader.read_exact(&mut buf)?;
+    decode_reply(&buf)
+}
+
+/// The same functions over tokio streams
+#[cfg(feature = "async")]
+pub(crate) mod tokio_io {
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+    use super::*;
+
+    pub(crate) async fn handshake<R, W>(reader: &mut R, writer: &mut W) -> Result<u32>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        writer.write_all(&hello()).await?;
+        writer.flush().await?;
+
+        let mut answer = [0; 8];
+        reader.read_exact(&mut answer).await?;
+        check_answer(answer)
+    }
+
+    pub(crate) async fn accept_handshake<R, W>(reader: &mut R, writer: &mut W) -> Result<u32>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut hello = [0; 12];
+        reader.read_exact(&mut hello).await?;
+        let (answer, version) = negotiate(hello)?;
+        writer.write_all(&answer).await?;
+        writer.flush().await?;
+        version
+    }
+
+    async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+        writer.write_u32(frame_len(payload)?).await?;
+        writer.write_all(payload).await
+    }
+
+    pub(crate) async fn write_request<W: AsyncWrite + Unpin>(
+        writer: &mut W,
+        id: u64,
+        request: &Request,
+    ) -> io::Result<()> {
+        write_frame(writer, &request_payload(id, request)).await
+    }
+
+    pub(crate) async fn read_request<R: AsyncRead + Unpin>(
+        reader: &mut R,
+    ) -> io::Result<Option<(u64, std::result::Result<Request, ErrorReply>)>> {
+        let len = match reader.read_u32().await {
+            Ok(len) => len,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if len < ID_LEN {
+            discard(reader, len).await?;
+            return Ok(Some((0, Err(no_id()))));
+        }
+
+        let id = reader.read_u64().await?;
+        if len > MAX_FRAME_LEN {
+            discard(reader, len - ID_LEN).await?;
+            return Ok(Some((id, Err(too_large(len)))));
+        }
+
+        let mut body = vec![0; (len - ID_LEN) as usize];
+        reader.read_exact(&mut body).await?;
+        Ok(Some((id, Request::decode(&body))))
+    }
+
+    pub(crate) async fn write_reply<W: AsyncWrite + Unpin>(
+        writer: &mut W,
+        id: u64,
+        reply: &ReplyResult,
+    ) -> io::Result<()> {
+        write_frame(writer, &reply_payload(id, reply)).await
+    }
+
+    pub(crate) async fn read_reply<R: AsyncRead + Unpin>(
+        reader: &mut R,
+    ) -> Result<(u64, ReplyResult)> {
+        let len = check_reply_len(reader.read_u32().await?)?;
+        let mut buf = vec![0; len as usize];
+        reader.read_exact(&mut buf).await?;
+        decode_reply(&buf)
+    }
+
+    async fn discard<R: AsyncRead + Unpin>(reader: &mut R, len: u32) -> io::Result<()> {
+        let skipped = tokio::io::copy(&mut reader.take(len as u64), &mut tokio::io::sink()).await?;
+        if skipped < len as u64 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
+}
+
+/// First message of the client, the versions it speaks
+fn hello() -> [u8; 12] {
+    let mut hello = [0; 12];
+    hello[..4].copy_from_slice(&MAGIC);
+    hello[4..8].copy_from_slice(&MIN_VERSION.to_be_bytes());
+    hello[8..].copy_from_slice(&VERSION.to_be_bytes());
+    hello
+}
+
+/// Version chosen by the server in its answer to `hello`.
+fn check_answer(answer: [u8; 8]) -> Result<u32> {
+    if answer[..4] != MAGIC {
+        return Err(protocol_error("not a kvs-server"));
+    }
+    match u32::from_be_bytes([answer[4], answer[5], answer[6], answer[7]]) {
+        0 => Err(protocol_error(
+            "no protocol version in common with the server",
+        )),
+        version => Ok(version),
+    }
+}
+
+/// Answer to the `hello` of a client, to be sent even if no version is
+/// spoken by both sides.
+fn negotiate(hello: [u8; 12]) -> Result<([u8; 8], Result<u32>)> {
+    if hello[..4] != MAGIC {
+        return Err(protocol_error("not a kvs client"));
+    }
+    let min = u32::from_be_bytes([hello[4], hello[5], hello[6], hello[7]]);
+    let max = u32::from_be_bytes([hello[8], hello[9], hello[10], hello[11]]);
+
+    let version = max.min(VERSION);
+    let supported = version >= min && version >= MIN_VERSION;
+    let mut answer = [0; 8];
+    answer[..4].copy_from_slice(&MAGIC);
+    answer[4..].copy_from_slice(&(if supported { version } else { 0 }).to_be_bytes());
+
+    if !supported {
+        let e = protocol_error(&format!("unsupported protocol versions {} to {}", min, max));
+        return Ok((answer, Err(e)));
+    }
+    Ok((answer, Ok(version)))
+}
+
+fn request_payload(id: u64, request: &Request) -> Vec<u8> {
+    let mut buf = id.to_be_bytes().to_vec();
+    request.encode(&mut buf);
+    buf
+}
+
+/// Payload of the reply to the request `id`, a reply over `MAX_FRAME_LEN`
+/// is replaced by a `TooLarge` error. The lengths in an oversized reply
+/// may wrap, it is never sent.
+fn reply_payload(id: u64, reply: &ReplyResult) -> Vec<u8> {
+    let mut buf = id.to_be_bytes().to_vec();
+    match reply {
+        Ok(response) => {
+            buf.push(0);
+            response.encode(&mut buf);
+        }
+        Err(e) => {
+            buf.push(e.code as u8);
+            put_bytes(&mut buf, e.message.as_bytes());
+        }
+    }
+    if buf.len() > MAX_FRAME_LEN as usize {
+        let e = ErrorReply::new(
+            ErrorCode::TooLarge,
+            format!(
+                "reply of {} bytes is over {} bytes",
+                buf.len(),
+                MAX_FRAME_LEN
+            ),
+        );
+        return reply_payload(id, &Err(e));
+    }
+    buf
+}
+
+/// Length of a frame holding `payload`
+fn frame_len(payload: &[u8]) -> io::Result<u32> {
+    u32::try_from(payload.len()).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("frame of {} bytes is too large", payload.len()),
+        )
+    })
+}
+
+/// Fail on replies the server can't send, before allocating them.
+fn check_reply_len(len: u32) -> Result<u32> {
+    if len > MAX_FRAME_LEN {
+        return Err(protocol_error(&format!(
+            "reply of {} bytes is over {} bytes",
+            len, MAX_FRAME_LEN
+        )));
+    }
+    Ok(len)
+}
+
+fn decode_reply(buf: &[u8]) -> Result<(u64, ReplyResult)> {
+    let mut decoder = Decoder(buf);
+    let reply = (|| {
+        let id = decoder.u64()?;
+        let reply = match decoder.u8()? {
+            0 => Ok(Response::decode(&mut decoder)?),
+            code => {
+                let code = ErrorCode::from_u8(code).ok_or(())?;
+                let message = String::from_utf8_lossy(&decoder.bytes()?).into_owned();
+                Err(ErrorReply { code, message })
+            }
+        };
+        decoder.finish()?;
+        Ok((id, reply))
+    })();
+    reply.map_err(|()| protocol_error("malformed reply"))
+}
+
+fn no_id() -> ErrorReply {
+    ErrorReply::new(ErrorCode::InvalidRequest, "request frame without an id")
+}
+
+fn too_large(len: u32) -> ErrorReply {
+    ErrorReply::new(
+        ErrorCode::TooLarge,
+        format!("request of {} bytes is over {} bytes", len, MAX_FRAME_LEN),
+    )
+}
+
+impl Request {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Request::Get { key } => {
+                buf.push(b'g');
+                put_bytes(buf, key);
+            }
+            Request::Set { key, value } => {
+                buf.push(b's');
+                put_bytes(buf, key);
+                put_bytes(buf, value);
+            }
+            Request::SetWithTtl { key, value, ttl } => {
+                buf.push(b't');
+                put_bytes(buf, key);
+                put_bytes(buf, value);
+                let millis = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+                buf.extend_from_slice(&millis.to_be_bytes());
+            }
+            Request::Remove { key } => {
+                buf.push(b'r');
+                put_bytes(buf, key);
+            }
+            Request::Scan { start, end } => {
+                buf.push(b'c');
+                put_bytes(buf, start);
+                put_option(buf, end.as_deref());
+            }
+            Request::ScanPrefix { prefix } => {
+                buf.push(b'p');
+                put_bytes(buf, prefix);
+            }
+            Request::Batch(batch) => {
+                buf.push(b'b');
+                buf.extend_from_slice(&(batch.len() as u32).to_be_bytes());
+                for op in batch.ops() {
+                    match op {
+                        BatchOp::Set { key, value } => {
+                            buf.push(b's');
+                            put_bytes(buf, key);
+                            put_bytes(buf, value);
+                        }
+                        BatchOp::Remove { key } => {
+                            buf.push(b'r');
+                            put_bytes(buf, key);
+                        }
+                    }
+                }
+            }
+            Request::CompareAndSwap { key, expected, new } => {
+                buf.push(b'w');
+                put_bytes(buf, key);
+                put_option(buf, expected.as_deref());
+                put_option(buf, new.as_deref());
+            }
+            Request::Stats => buf.push(b'i'),
+        }
+    }
+
+    fn decode(body: &[u8]) -> std::result::Result<Request, ErrorReply> {
+        let opcode = match body.first() {
+            Some(opcode) => *opcode,
+            None => return Err(ErrorReply::new(ErrorCode::InvalidRequest, "empty request")),
+        };
+        let mut decoder = Decoder(&body[1..]);
+        let request = (|| {
+            let request = match opcode {
+                b'g' => Request::Get {
+                    key: decoder.bytes()?,
+                },
+                b's' => Request::Set {
+                    key: decoder.bytes()?,
+                    value: decoder.bytes()?,
+                },
+                b't' => Request::SetWithTtl {
+                    key: decoder.bytes()?,
+                    value: decoder.bytes()?,
+                    ttl: Duration::from_millis(decoder.u64()?),
+                },
+                b'r' => Request::Remove {
+                    key: decoder.bytes()?,
+                },
+                b'c' => Request::Scan {
+                    start: decoder.bytes()?,
+                    end: decoder.option()?,
+                },
+                b'p' => Request::ScanPrefix {
+                    prefix: decoder.bytes()?,
+                },
+                b'b' => {
+                    let mut batch = WriteBatch::new();
+                    for _ in 0..decoder.u32()? {
+                        match decoder.u8()? {
+                            b's' => batch.set(decoder.bytes()?, decoder.bytes()?),
+                            b'r' => batch.remove(decoder.bytes()?),
+                            _ => return Err(()),
+                        }
+                    }
+                    Request::Batch(batch)
+                }
+                b'w' => Request::CompareAndSwap {
+                    key: decoder.bytes()?,
+                    expected: decoder.option()?,
+                    new: decoder.option()?,
+                },
+                b'i' => Request::Stats,
+                _ => return Ok(None),
+            };
+            decoder.finish()?;
+            Ok(Some(request))
+        })();
+
+        match request {
+            Ok(Some(request)) => Ok(request),
+            Ok(None) => Err(ErrorReply::new(
+                ErrorCode::Unsupported,
+                format!("unsupported opcode `{:#04x}`", opcode),
+            )),
+            Err(()) => Err(ErrorReply::new(
+                ErrorCode::InvalidRequest,
+                "malformed request",
+            )),
+        }
+    }
+}
+
+impl Response {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Response::Done => buf.push(0),
+            Response::Value(value) => {
+                buf.push(1);
+                put_option(buf, value.as_deref());
+            }
+            Response::Pairs(pairs) => {
+                buf.push(2);
+                buf.extend_from_slice(&(pairs.len() as u32).to_be_bytes());
+                for (key, value) in pairs {
+                    put_bytes(buf, key);
+                    put_bytes(buf, value);
+                }
+            }
+            Response::Swapped(swapped) => {
+                buf.push(3);
+                buf.push(*swapped as u8);
+            }
+        }
+    }
+
+    fn decode(decoder: &mut Decoder<'_>) -> std::result::Result<Response, ()> {
+        let response = match decoder.u8()? {
+            0 => Response::Done,
+            1 => Response::Value(decoder.option()?),
+            2 => {
+                let count = decoder.u32()?;
+                let mut pairs = Vec::new();
+                for _ in 0..count {
+                    pairs.push((decoder.bytes()?, decoder.bytes()?));
+                }
+                Response::Pairs(pairs)
+            }
+            3 => Response::Swapped(decoder.u8()? != 0),
+            _ => return Err(()),
+        };
+        Ok(response)
+    }
+}
+
+/// Reads the fields of a frame, failing on truncated ones
+struct Decoder<'a>(&'a [u8]);
+
+impl Decoder<'_> {
+    fn take(&mut self, len: usize) -> std::result::Result<&[u8], ()> {
+        if self.0.len() < len {
+            return Err(());
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> std::result::Result<u8, ()> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> std::result::Result<u32, ()> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_be_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> std::result::Result<u64, ()> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    fn bytes(&mut self) -> std::result::Result<Vec<u8>, ()> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn option(&mut self) -> std::result::Result<Option<Vec<u8>>, ()> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(self.bytes()?)),
+            _ => Err(()),
+        }
+    }
+
+    /// Fail if some bytes were not read.
+    fn finish(&self) -> std::result::Result<(), ()> {
+        if self.0.is_empty() {
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+fn put_option(buf: &mut Vec<u8>, bytes: Option<&[u8]>) {
+    match bytes {
+        Some(bytes) => {
+            buf.push(1);
+            put_bytes(buf, bytes);
+        }
+        None => buf.push(0),
+    }
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+/// Skip the rest of a frame that is not decoded.
+fn discard<R: Read>(reader: &mut R, len: u32) -> io::Result<()> {
+    let skipped = io::copy(&mut reader.take(len as u64), &mut io::sink())?;
+    if skipped < len as u64 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+fn protocol_error(message: &str) -> EngineError {
+    EngineError::Protocol(message.to_owned())
+}
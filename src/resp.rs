@@ -0,0 +1,425 @@
+//! Redis RESP2 protocol, spoken by kvs-server with `--protocol resp`
+//!
+//! Commands are arrays of bulk strings, or inline commands separated by
+//! spaces for telnet sessions. The supported commands are `GET`, `SET`
+//! with `EX`, `PX` and `NX`, `DEL`, `EXISTS`, `SCAN` with `MATCH` and
+//! `COUNT`, `PING`, `INFO` and `QUIT`.
+//!
+//! `SCAN` cursors are the last key scanned, in hex, the next page starts
+//! after it whatever was written meanwhile.
+
+use std::io::{self, BufRead, Read, Write};
+use std::ops::Bound;
+use std::time::Duration;
+
+use crate::engine::{EngineError, KvsEngine};
+use crate::protocol::MAX_FRAME_LEN;
+
+/// Most arguments in a command
+const MAX_ARGS: usize = 1024 * 1024;
+
+/// Longest inline command or length line
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+/// RESP2 value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    /// Simple string, `+OK`
+    Simple(String),
+    /// Error, `-ERR message`
+    Error(String),
+    /// Integer, `:1`
+    Integer(i64),
+    /// Bulk string, `$-1` for none
+    Bulk(Option<Vec<u8>>),
+    /// Array of values
+    Array(Vec<Value>),
+}
+
+impl Value {
+    fn ok() -> Value {
+        Value::Simple("OK".to_owned())
+    }
+
+    fn error(message: impl AsRef<str>) -> Value {
+        Value::Error(format!("ERR {}", message.as_ref()))
+    }
+}
+
+impl From<EngineError> for Value {
+    fn from(e: EngineError) -> Value {
+        Value::error(e.to_string())
+    }
+}
+
+/// Read the next command, `None` once the client closed the connection.
+/// Malformed commands fail with `io::ErrorKind::InvalidData`, the
+/// connection can't be used anymore.
+pub fn read_command<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<Vec<u8>>>> {
+    loop {
+        let line = match read_line(reader)? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+
+        if line.first() != Some(&b'*') {
+            // inline command, empty lines are skipped
+            let args: Vec<Vec<u8>> = line
+                .split(|b| b.is_ascii_whitespace())
+                .filter(|arg| !arg.is_empty())
+                .map(<[u8]>::to_vec)
+                .collect();
+            if args.is_empty() {
+                continue;
+            }
+            return Ok(Some(args));
+        }
+
+        let count = parse_len(&line[1..], MAX_ARGS, "multibulk length")?;
+        // the arguments announced may never come
+        let mut args = Vec::with_capacity(count.min(1024));
+        for _ in 0..count {
+            let line = read_line(reader)?.ok_or_else(truncated)?;
+            if line.first() != Some(&b'$') {
+                return Err(invalid(format!(
+                    "expected '$', got '{}'",
+                    line.first().map_or(' ', |b| *b as char)
+                )));
+            }
+            let len = parse_len(&line[1..], MAX_FRAME_LEN as usize, "bulk length")?;
+
+            let mut arg = vec![0; len + 2];
+            reader.read_exact(&mut arg)?;
+            if !arg.ends_with(b"\r\n") {
+                return Err(invalid("bulk string without CRLF"));
+            }
+            arg.truncate(len);
+            args.push(arg);
+        }
+        if !args.is_empty() {
+            return Ok(Some(args));
+        }
+    }
+}
+
+/// Write a value.
+pub fn write_value<W: Write>(writer: &mut W, value: &Value) -> io::Result<()> {
+    match value {
+        Value::Simple(s) => write!(writer, "+{}\r\n", s),
+        Value::Error(e) => write!(writer, "-{}\r\n", e),
+        Value::Integer(n) => write!(writer, ":{}\r\n", n),
+        Value::Bulk(None) => writer.write_all(b"$-1\r\n"),
+        Value::Bulk(Some(bytes)) => {
+            write!(writer, "${}\r\n", bytes.len())?;
+            writer.write_all(bytes)?;
+            writer.write_all(b"\r\n")
+        }
+        Value::Array(values) => {
+            write!(writer, "*{}\r\n", values.len())?;
+            for value in values {
+                write_value(writer, value)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Run a command against the engine, failures are replied as errors.
+pub fn execute<E: KvsEngine>(engine: &E, command: Vec<Vec<u8>>) -> Value {
+    let mut args = command.into_iter();
+    let name = match args.next() {
+        Some(name) => String::from_utf8_lossy(&name).to_lowercase(),
+        None => return Value::error("empty command"),
+    };
+    let args: Vec<Vec<u8>> = args.collect();
+
+    let arity = match name.as_str() {
+        "get" => args.len() == 1,
+        "set" => args.len() >= 2,
+        "del" | "exists" | "scan" => !args.is_empty(),
+        "ping" => args.len() <= 1,
+        "info" => true,
+        "quit" => true,
+        _ => {
+            return Value::error(format!("unknown command '{}'", name));
+        }
+    };
+    if !arity {
+        return Value::error(format!("wrong number of arguments for '{}' command", name));
+    }
+
+    let reply = match name.as_str() {
+        "get" => get(engine, args),
+        "set" => set(engine, args),
+        "del" => del(engine, args),
+        "exists" => exists(engine, args),
+        "scan" => scan(engine, args),
+        "ping" => Ok(match args.into_iter().next() {
+            Some(message) => Value::Bulk(Some(message)),
+            None => Value::Simple("PONG".to_owned()),
+        }),
+        "info" => Ok(info()),
+        _ => Ok(Value::ok()),
+    };
+    reply.unwrap_or_else(Value::from)
+}
+
+fn get<E: KvsEngine>(engine: &E, args: Vec<Vec<u8>>) -> crate::Result<Value> {
+    let key = args.into_iter().next().unwrap_or_default();
+    Ok(Value::Bulk(engine.get_bytes(key)?))
+}
+
+/// `SET key value [EX seconds | PX milliseconds] [NX]`
+fn set<E: KvsEngine>(engine: &E, args: Vec<Vec<u8>>) -> crate::Result<Value> {
+    let mut args = args.into_iter();
+    let key = args.next().unwrap_or_default();
+    let value = args.next().unwrap_or_default();
+
+    let mut ttl = None;
+    let mut nx = false;
+    while let Some(option) = args.next() {
+        let option = String::from_utf8_lossy(&option).to_lowercase();
+        match option.as_str() {
+            "nx" => nx = true,
+            "ex" | "px" if ttl.is_none() => {
+                let n = match args.next().as_deref().map(parse_integer) {
+                    Some(Some(n)) => n,
+                    Some(None) => return Ok(not_an_integer()),
+                    None => return Ok(Value::error("syntax error")),
+                };
+                if n <= 0 {
+                    return Ok(Value::error("invalid expire time in 'set' command"));
+                }
+                ttl = Some(match option.as_str() {
+                    "ex" => Duration::from_secs(n as u64),
+                    _ => Duration::from_millis(n as u64),
+                });
+            }
+            _ => return Ok(Value::error("syntax error")),
+        }
+    }
+
+    if nx {
+        let set = match ttl {
+            Some(ttl) => engine.compare_and_swap_bytes_with_ttl(key, None, value, ttl)?,
+            None => engine.compare_and_swap_bytes(key, None, Some(value))?,
+        };
+        return Ok(if set { Value::ok() } else { Value::Bulk(None) });
+    }
+    match ttl {
+        Some(ttl) => engine.set_bytes_with_ttl(key, value, ttl)?,
+        None => engine.set_bytes(key, value)?,
+    }
+    Ok(Value::ok())
+}
+
+fn del<E: KvsEngine>(engine: &E, args: Vec<Vec<u8>>) -> crate::Result<Value> {
+    let mut removed = 0;
+    for key in args {
+        match engine.remove_bytes(key) {
+            Ok(()) => removed += 1,
+            Err(EngineError::NotFound(_)) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(Value::Integer(removed))
+}
+
+fn exists<E: KvsEngine>(engine: &E, args: Vec<Vec<u8>>) -> crate::Result<Value> {
+    let mut found = 0;
+    for key in args {
+        if engine.get_bytes(key)?.is_some() {
+            found += 1;
+        }
+    }
+    Ok(Value::Integer(found))
+}
+
+/// `SCAN cursor [MATCH pattern] [COUNT count]`
+fn scan<E: KvsEngine>(engine: &E, args: Vec<Vec<u8>>) -> crate::Result<Value> {
+    let mut args = args.into_iter();
+    let start = match args.next().as_deref() {
+        Some(b"0") => Bound::Unbounded,
+        Some(cursor) => match decode_hex(cursor) {
+            Some(last_key) => Bound::Excluded(last_key),
+            None => return Ok(Value::error("invalid cursor")),
+        },
+        None => return Ok(Value::error("invalid cursor")),
+    };
+
+    let mut pattern = None;
+    let mut count = 10;
+    while let Some(option) = args.next() {
+        match (option.to_ascii_lowercase().as_slice(), args.next()) {
+            (b"match", Some(p)) => pattern = Some(p),
+            (b"count", Some(n)) => match parse_integer(&n) {
+                Some(n) if n > 0 => count = n as usize,
+                Some(_) => return Ok(Value::error("syntax error")),
+                None => return Ok(not_an_integer()),
+            },
+            _ => return Ok(Value::error("syntax error")),
+        }
+    }
+
+    let mut scan = engine.scan_keys((start, Bound::Unbounded))?;
+    let mut keys = Vec::new();
+    let mut last_key = None;
+    for item in scan.by_ref().take(count) {
+        let key = item?;
+        if pattern.as_ref().is_none_or(|p| glob_match(p, &key)) {
+            keys.push(Value::Bulk(Some(key.clone())));
+        }
+        last_key = Some(key);
+    }
+    let next = match (scan.next(), last_key) {
+        (Some(_), Some(last_key)) => encode_hex(&last_key),
+        _ => b"0".to_vec(),
+    };
+
+    Ok(Value::Array(vec![
+        Value::Bulk(Some(next)),
+        Value::Array(keys),
+    ]))
+}
+
+fn info() -> Value {
+    let info = format!(
+        "# Server\r\nkvs_version:{}\r\nresp_version:2\r\n",
+        env!("CARGO_PKG_VERSION")
+    );
+    Value::Bulk(Some(info.into_bytes()))
+}
+
+fn encode_hex(bytes: &[u8]) -> Vec<u8> {
+    bytes
+        .iter()
+        .flat_map(|b| format!("{:02x}", b).into_bytes())
+        .collect()
+}
+
+fn decode_hex(hex: &[u8]) -> Option<Vec<u8>> {
+    let pairs = hex.chunks_exact(2);
+    if !pairs.remainder().is_empty() {
+        return None;
+    }
+    pairs
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+fn not_an_integer() -> Value {
+    Value::error("value is not an integer or out of range")
+}
+
+fn parse_integer(bytes: &[u8]) -> Option<i64> {
+    std::str::from_utf8(bytes).ok()?.parse().ok()
+}
+
+/// Whether `s` matches the glob-style `pattern`, with `*`, `?`, `[...]`
+/// classes and `\` escapes as in Redis.
+fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    // pattern position after the last `*` and the position in `s` it
+    // matched up to
+    let mut star = None;
+    while i < s.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            p += 1;
+            star = Some((p, i));
+            continue;
+        }
+        if let Some(len) = match_one(&pattern[p..], s[i]) {
+            p += len;
+            i += 1;
+            continue;
+        }
+        match star {
+            Some((star_p, star_i)) => {
+                p = star_p;
+                i = star_i + 1;
+                star = Some((star_p, star_i + 1));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|b| *b == b'*')
+}
+
+/// Length of the pattern element at the start of `pattern` if it matches
+/// the byte `c`.
+fn match_one(pattern: &[u8], c: u8) -> Option<usize> {
+    match *pattern.first()? {
+        b'?' => Some(1),
+        b'\\' if pattern.len() > 1 => (pattern[1] == c).then_some(2),
+        b'[' => {
+            let mut p = 1;
+            let negate = pattern.get(p) == Some(&b'^');
+            if negate {
+                p += 1;
+            }
+            let mut matched = false;
+            while p < pattern.len() && pattern[p] != b']' {
+                if pattern[p] == b'\\' && p + 1 < pattern.len() {
+                    matched |= pattern[p + 1] == c;
+                    p += 2;
+                } else if p + 2 < pattern.len() && pattern[p + 1] == b'-' && pattern[p + 2] != b']'
+                {
+                    let (lo, hi) = (
+                        pattern[p].min(pattern[p + 2]),
+                        pattern[p].max(pattern[p + 2]),
+                    );
+                    matched |= lo <= c && c <= hi;
+                    p += 3;
+                } else {
+                    matched |= pattern[p] == c;
+                    p += 1;
+                }
+            }
+            // an unterminated class runs to the end of the pattern
+            let len = (p + 1).min(pattern.len());
+            (matched != negate).then_some(len)
+        }
+        b => (b == c).then_some(1),
+    }
+}
+
+/// Line without its CRLF, `None` at the end of the stream.
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    let read = reader
+        .by_ref()
+        .take(MAX_LINE_LEN as u64 + 1)
+        .read_until(b'\n', &mut line)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if line.last() != Some(&b'\n') {
+        return Err(if line.len() > MAX_LINE_LEN {
+            invalid("too big inline request")
+        } else {
+            truncated()
+        });
+    }
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(bytes: &[u8], max: usize, what: &str) -> io::Result<usize> {
+    match parse_integer(bytes) {
+        Some(len) if len >= 0 && len as usize <= max => Ok(len as usize),
+        // a null array or bulk string stands for no argument
+        Some(-1) if what == "multibulk length" => Ok(0),
+        _ => Err(invalid(format!("invalid {}", what))),
+    }
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+fn truncated() -> io::Error {
+    io::ErrorKind::UnexpectedEof.into()
+}
@@ -0,0 +1,208 @@
+use std::future::{self, Future};
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, error, info, warn};
+use tokio::io::{AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{watch, Semaphore};
+use tokio::task::JoinSet;
+
+use super::execute;
+use crate::engine::KvsEngine;
+use crate::kvs::EngineError;
+use crate::protocol::{tokio_io, ErrorCode, ErrorReply, ReplyResult, Request};
+use crate::Result;
+
+/// Pause after a failed accept, running out of file descriptors lasts until
+/// some connections are closed
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Async kvs-server running on a tokio runtime.
+///
+/// Connections are served by tasks, the engine calls run on the blocking
+/// threads of the runtime, at most `blocking_threads` of them at once.
+///
+/// ```no_run
+/// # async fn run() -> kvs::Result<()> {
+/// use kvs::server::KvsServer;
+/// use kvs::KvStore;
+/// use tokio::net::TcpListener;
+///
+/// let listener = TcpListener::bind("127.0.0.1:4000").await?;
+/// let engine = KvStore::open(std::env::current_dir()?)?;
+/// KvsServer::new(engine).blocking_threads(8).run(listener).await
+/// # }
+/// ```
+pub struct KvsServer<E: KvsEngine> {
+    engine: E,
+    blocking_threads: usize,
+    drain_timeout: Duration,
+}
+
+impl<E: KvsEngine> KvsServer<E> {
+    /// Server of `engine`, running up to 16 engine calls at once.
+    pub fn new(engine: E) -> KvsServer<E> {
+        KvsServer {
+            engine,
+            blocking_threads: 16,
+            drain_timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// Run up to `threads` engine calls at once, 16 by default.
+    pub fn blocking_threads(mut self, threads: usize) -> KvsServer<E> {
+        self.blocking_threads = threads.max(1);
+        self
+    }
+
+    /// Give the open connections up to `timeout` to finish their requests
+    /// on shutdown, 10 seconds by default.
+    pub fn drain_timeout(mut self, timeout: Duration) -> KvsServer<E> {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Accept connections forever.
+    pub async fn run(self, listener: TcpListener) -> Result<()> {
+        self.run_until(listener, future::pending()).await
+    }
+
+    /// Accept connections until `shutdown` completes, then stop reading
+    /// requests and wait for the engine calls in flight.
+    ///
+    /// A failed accept, too many open files for one, doesn't stop the
+    /// server: the error is logged and the next connection is accepted
+    /// after a short pause.
+    pub async fn run_until(
+        self,
+        listener: TcpListener,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<()> {
+        let permits = Arc::new(Semaphore::new(self.blocking_threads));
+        let (stop_tx, stop_rx) = watch::channel(false);
+        let mut connections = JoinSet::new();
+        tokio::pin!(shutdown);
+
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let stream = match accepted {
+                        Ok((stream, _)) => stream,
+                        Err(e) => {
+                            warn!("accepting connection failed: {}", e);
+                            tokio::time::sleep(ACCEPT_BACKOFF).await;
+                            continue;
+                        }
+                    };
+                    let engine = self.engine.clone();
+                    let permits = Arc::clone(&permits);
+                    let stop = stop_rx.clone();
+                    connections.spawn(async move {
+                        if let Err(e) = handle_client(engine, permits, stream, stop).await {
+                            error!("serving client failed: {:?}", e);
+                        }
+                    });
+                }
+                // reap the closed connections
+                Some(_) = connections.join_next(), if !connections.is_empty() => {}
+                _ = &mut shutdown => break,
+            }
+        }
+        info!("stopped accepting connections");
+
+        let _ = stop_tx.send(true);
+        let drain = async { while connections.join_next().await.is_some() {} };
+        if tokio::time::timeout(self.drain_timeout, drain)
+            .await
+            .is_err()
+        {
+            warn!(
+                "{} connections still open after {:?}, closing them",
+                connections.len(),
+                self.drain_timeout
+            );
+            connections.shutdown().await;
+        }
+
+        // the engine calls of closed connections may still be running
+        let _ = permits
+            .acquire_many(self.blocking_threads as u32)
+            .await
+            .expect("semaphore is never closed");
+        Ok(())
+    }
+}
+
+/// Serve the requests of a client until it closes the connection or the
+/// server stops, replies to pipelined requests are sent together once all
+/// of them are read.
+async fn handle_client<E: KvsEngine>(
+    engine: E,
+    permits: Arc<Semaphore>,
+    stream: TcpStream,
+    mut stop: watch::Receiver<bool>,
+) -> Result<()> {
+    stream.set_nodelay(true)?;
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+
+    let version = match tokio_io::accept_handshake(&mut reader, &mut writer).await {
+        Ok(version) => version,
+        // closed without a handshake, checking that the server is up
+        Err(EngineError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    debug!("protocol version: {}", version);
+
+    loop {
+        if reader.buffer().is_empty() {
+            writer.flush().await?;
+        }
+
+        let next = tokio::select! {
+            next = tokio_io::read_request(&mut reader) => next?,
+            _ = stop.wait_for(|stop| *stop) => None,
+        };
+        let (id, request) = match next {
+            Some(request) => request,
+            None => {
+                writer.flush().await?;
+                return Ok(());
+            }
+        };
+        let reply = match request {
+            Ok(request) => execute_blocking(engine.clone(), &permits, request).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = &reply {
+            debug!("request {} failed: {:?}", id, e);
+        }
+        tokio_io::write_reply(&mut writer, id, &reply).await?;
+    }
+}
+
+/// Run a request on a blocking thread once a permit is available.
+async fn execute_blocking<E: KvsEngine>(
+    engine: E,
+    permits: &Arc<Semaphore>,
+    request: Request,
+) -> ReplyResult {
+    let permit = Arc::clone(permits)
+        .acquire_owned()
+        .await
+        .expect("semaphore is never closed");
+    let call = tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        execute(&engine, request)
+    });
+    match call.await {
+        Ok(reply) => reply,
+        Err(e) => Err(ErrorReply::new(
+            ErrorCode::Engine,
+            format!("request failed: {}", e),
+        )),
+    }
+}
@@ -0,0 +1,110 @@
+//! Server side of the kvs-server protocol
+
+#[cfg(feature = "async")]
+mod async_server;
+
+#[cfg(feature = "async")]
+pub use async_server::KvsServer;
+
+use std::ops::Bound;
+use std::time::Duration;
+
+use log::debug;
+
+use crate::engine::KvsEngine;
+use crate::protocol::{ReplyResult, Request, Response};
+use crate::thread_pool::{LatencyHistogram, PoolMetrics};
+
+/// Run a request against the engine.
+pub fn execute<E: KvsEngine>(engine: &E, request: Request) -> ReplyResult {
+    debug!("request: {:?}", request);
+
+    let response = match request {
+        Request::Get { key } => Response::Value(engine.get_bytes(key)?),
+        Request::Set { key, value } => {
+            engine.set_bytes(key, value)?;
+            Response::Done
+        }
+        Request::SetWithTtl { key, value, ttl } => {
+            engine.set_bytes_with_ttl(key, value, ttl)?;
+            Response::Done
+        }
+        Request::Remove { key } => {
+            engine.remove_bytes(key)?;
+            Response::Done
+        }
+        Request::Scan { start, end } => {
+            let end = match end {
+                Some(end) => Bound::Excluded(end),
+                None => Bound::Unbounded,
+            };
+            let scan = engine.scan_bytes((Bound::Included(start), end))?;
+            Response::Pairs(scan.collect::<crate::Result<_>>()?)
+        }
+        Request::ScanPrefix { prefix } => {
+            let scan = engine.scan_prefix_bytes(prefix)?;
+            Response::Pairs(scan.collect::<crate::Result<_>>()?)
+        }
+        Request::Batch(batch) => {
+            engine.apply_batch(batch)?;
+            Response::Done
+        }
+        Request::CompareAndSwap { key, expected, new } => {
+            Response::Swapped(engine.compare_and_swap_bytes(key, expected, new)?)
+        }
+        Request::Stats => stats(None),
+    };
+
+    Ok(response)
+}
+
+/// Reply to `Request::Stats`, with the metrics of the pool serving the
+/// connections if any. Latencies are in microseconds, quantiles are the
+/// upper bound of their histogram bucket.
+///
+/// kvs-server only has the metrics of the shared-queue pool, the other
+/// pools and the async server reply the version alone. Each job of the
+/// pool serves the requests a client sent so far, one unless they are
+/// pipelined: the run latency is how long they take to serve and the queue
+/// latency how long they wait for a thread once read.
+pub fn stats(metrics: Option<&PoolMetrics>) -> Response {
+    let mut stats = vec![("version".to_owned(), env!("CARGO_PKG_VERSION").to_owned())];
+    if let Some(metrics) = metrics {
+        let counts = [
+            ("threads", metrics.threads as u64),
+            ("queue_depth", metrics.queue_depth as u64),
+            ("active_workers", metrics.active_workers as u64),
+            ("jobs_completed", metrics.jobs_completed),
+            ("panics", metrics.panics),
+        ];
+        for (name, count) in counts {
+            stats.push((format!("pool_{}", name), count.to_string()));
+        }
+
+        let histograms = [
+            ("queue", &metrics.queue_latency),
+            ("run", &metrics.run_latency),
+        ];
+        for (name, histogram) in histograms {
+            for (quantile, q) in [("p50", 0.5), ("p99", 0.99)] {
+                let name = format!("pool_{}_latency_{}_us", name, quantile);
+                stats.push((name, micros(histogram, q)));
+            }
+        }
+    }
+
+    let pairs = stats
+        .into_iter()
+        .map(|(name, value)| (name.into_bytes(), value.into_bytes()))
+        .collect();
+    Response::Pairs(pairs)
+}
+
+/// Quantile `q` of `histogram` in microseconds, 0 if empty
+fn micros(histogram: &LatencyHistogram, q: f64) -> String {
+    match histogram.quantile(q) {
+        Some(Duration::MAX) => "inf".to_owned(),
+        Some(latency) => latency.as_micros().to_string(),
+        None => "0".to_owned(),
+    }
+}
@@ -0,0 +1,69 @@
+use crossbeam::channel::{self, Receiver, TryRecvError};
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+
+use thiserror::Error;
+
+/// Error of a job joined through its `JobHandle`
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum JoinError {
+    /// The job panicked, with the panic message
+    #[error("job panicked: {0}")]
+    Panicked(String),
+    /// The pool dropped the job without running it
+    #[error("job dropped before it ran")]
+    Cancelled,
+}
+
+/// Handle to a job spawned with `ThreadPool::spawn_with_handle`, to wait
+/// for its result or poll it.
+pub struct JobHandle<T> {
+    rx: Receiver<Result<T, JoinError>>,
+}
+
+impl<T> JobHandle<T> {
+    /// Wait for the job to finish, returns what it returned.
+    pub fn join(self) -> Result<T, JoinError> {
+        self.rx.recv().unwrap_or(Err(JoinError::Cancelled))
+    }
+
+    /// Result of the job if it finished or was dropped, the handle back
+    /// while it is queued or running.
+    pub fn try_join(self) -> Result<Result<T, JoinError>, JobHandle<T>> {
+        match self.rx.try_recv() {
+            Ok(result) => Ok(result),
+            Err(TryRecvError::Empty) => Err(self),
+            Err(TryRecvError::Disconnected) => Ok(Err(JoinError::Cancelled)),
+        }
+    }
+}
+
+/// Job sending the result of `job` to the returned handle. A panic is
+/// sent too, then resumed for the pool to handle it like any other.
+pub(crate) fn with_handle<F, T>(job: F) -> (impl FnOnce() + Send + 'static, JobHandle<T>)
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (tx, rx) = channel::bounded(1);
+    let job = move || match panic::catch_unwind(AssertUnwindSafe(job)) {
+        Ok(value) => {
+            let _ = tx.send(Ok(value));
+        }
+        Err(payload) => {
+            let _ = tx.send(Err(JoinError::Panicked(panic_message(&*payload))));
+            panic::resume_unwind(payload);
+        }
+    };
+    (job, JobHandle { rx })
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        (*message).to_owned()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic payload".to_owned()
+    }
+}
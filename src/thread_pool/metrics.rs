@@ -0,0 +1,137 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Buckets of a `LatencyHistogram`
+const BUCKETS: usize = 32;
+
+/// Activity of a thread pool, see `ThreadPool::metrics`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PoolMetrics {
+    /// Running threads
+    pub threads: u32,
+    /// Jobs waiting for a thread
+    pub queue_depth: usize,
+    /// Threads running a job
+    pub active_workers: usize,
+    /// Jobs run to the end or to a panic
+    pub jobs_completed: u64,
+    /// Jobs that panicked
+    pub panics: u64,
+    /// Time the jobs waited for a thread
+    pub queue_latency: LatencyHistogram,
+    /// Time the jobs ran
+    pub run_latency: LatencyHistogram,
+}
+
+/// Durations counted in buckets, bucket `i` holds the durations under
+/// 2^i microseconds and not in the previous buckets, the last one holds
+/// any longer duration.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    counts: Vec<u64>,
+}
+
+impl LatencyHistogram {
+    /// Number of durations counted
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Upper bound and count of each bucket, `Duration::MAX` for the last
+    /// one.
+    pub fn buckets(&self) -> impl Iterator<Item = (Duration, u64)> + '_ {
+        self.counts
+            .iter()
+            .enumerate()
+            .map(|(i, count)| (upper_bound(i), *count))
+    }
+
+    /// Upper bound of the bucket holding the `quantile`, from 0 to 1, of the
+    /// durations, `None` if none is counted.
+    pub fn quantile(&self, quantile: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = ((quantile.clamp(0.0, 1.0) * count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        self.buckets().find_map(|(bound, n)| {
+            seen += n;
+            (seen >= rank).then_some(bound)
+        })
+    }
+}
+
+fn upper_bound(bucket: usize) -> Duration {
+    if bucket == BUCKETS - 1 {
+        Duration::MAX
+    } else {
+        Duration::from_micros(1 << bucket)
+    }
+}
+
+/// Histogram updated by the threads of a pool
+pub(crate) struct Histogram([AtomicU64; BUCKETS]);
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram(std::array::from_fn(|_| AtomicU64::new(0)))
+    }
+}
+
+impl Histogram {
+    fn record(&self, duration: Duration) {
+        let bits = u128::BITS - duration.as_micros().leading_zeros();
+        let bucket = (bits as usize).min(BUCKETS - 1);
+        self.0[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> LatencyHistogram {
+        LatencyHistogram {
+            counts: self.0.iter().map(|n| n.load(Ordering::Relaxed)).collect(),
+        }
+    }
+}
+
+/// Counters updated by the threads of a pool
+#[derive(Default)]
+pub(crate) struct Counters {
+    active: AtomicUsize,
+    completed: AtomicU64,
+    panics: AtomicU64,
+    queue_latency: Histogram,
+    run_latency: Histogram,
+}
+
+impl Counters {
+    /// Run `job` queued at `queued`, returns false if it panicked.
+    pub(crate) fn run(&self, queued: Instant, job: impl FnOnce()) -> bool {
+        let started = Instant::now();
+        self.queue_latency.record(started - queued);
+        self.active.fetch_add(1, Ordering::Relaxed);
+        let finished = panic::catch_unwind(AssertUnwindSafe(job)).is_ok();
+        self.active.fetch_sub(1, Ordering::Relaxed);
+        self.run_latency.record(started.elapsed());
+
+        if !finished {
+            self.panics.fetch_add(1, Ordering::Relaxed);
+        }
+        self.completed.fetch_add(1, Ordering::Relaxed);
+        finished
+    }
+
+    /// Metrics of a pool of `threads` threads with `queue_depth` jobs
+    /// waiting.
+    pub(crate) fn snapshot(&self, threads: u32, queue_depth: usize) -> PoolMetrics {
+        PoolMetrics {
+            threads,
+            queue_depth,
+            active_workers: self.active.load(Ordering::Relaxed),
+            jobs_completed: self.completed.load(Ordering::Relaxed),
+            panics: self.panics.load(Ordering::Relaxed),
+            queue_latency: self.queue_latency.snapshot(),
+            run_latency: self.run_latency.snapshot(),
+        }
+    }
+}
@@ -1,10 +1,26 @@
+mod job_handle;
+mod metrics;
 mod naive_thread_pool;
+#[cfg(feature = "rayon")]
+mod rayon_thread_pool;
 mod shared_queue_thread_pool;
+mod work_stealing_thread_pool;
 
+pub use job_handle::{JobHandle, JoinError};
+pub use metrics::{LatencyHistogram, PoolMetrics};
 pub use naive_thread_pool::NaiveThreadPool;
-pub use shared_queue_thread_pool::SharedQueueThreadPool;
+#[cfg(feature = "rayon")]
+pub use rayon_thread_pool::RayonThreadPool;
+pub use shared_queue_thread_pool::{PoolMonitor, PoolOptions, QueuePolicy, SharedQueueThreadPool};
+pub use work_stealing_thread_pool::WorkStealingThreadPool;
 
 use anyhow::Result;
+use thiserror::Error;
+
+/// Error of `ThreadPool::try_spawn`, the pool is too busy to take the job
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("thread pool is busy, job rejected")]
+pub struct Rejected;
 
 /// thread pool
 pub trait ThreadPool {
@@ -17,4 +33,55 @@ pub trait ThreadPool {
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static;
+
+    /// Spawn a job returning a value, the handle waits for it. A panic of
+    /// the job is returned as an error by the handle.
+    ///
+    /// ```
+    /// use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+    ///
+    /// let pool = SharedQueueThreadPool::new(4)?;
+    /// let handles: Vec<_> = (0..4u64)
+    ///     .map(|i| pool.spawn_with_handle(move || i * i))
+    ///     .collect();
+    /// let squares = handles
+    ///     .into_iter()
+    ///     .map(|handle| handle.join())
+    ///     .collect::<Result<Vec<_>, _>>()?;
+    /// assert_eq!(squares, [0, 1, 4, 9]);
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    fn spawn_with_handle<F, T>(&self, job: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (job, handle) = job_handle::with_handle(job);
+        self.spawn(job);
+        handle
+    }
+
+    /// Spawn a job unless the pool is too busy to take it, the job is then
+    /// dropped without running. Pools without a bounded queue take any job.
+    fn try_spawn<F>(&self, job: F) -> std::result::Result<(), Rejected>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.spawn(job);
+        Ok(())
+    }
+
+    /// Current activity of the pool, for pools keeping track of it.
+    fn metrics(&self) -> Option<PoolMetrics> {
+        None
+    }
+
+    /// Wait for the spawned jobs to finish and stop the threads, pools
+    /// that can't wait for their jobs only stop taking new ones.
+    fn shutdown(self)
+    where
+        Self: Sized,
+    {
+        drop(self);
+    }
 }
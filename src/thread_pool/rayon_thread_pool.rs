@@ -0,0 +1,67 @@
+use std::sync::{Arc, Condvar, Mutex};
+
+use anyhow::Result;
+use log::warn;
+
+use crate::thread_pool::ThreadPool;
+
+/// Pool of threads run by rayon.
+///
+/// A panicking job doesn't take its thread down. Dropping the pool waits
+/// for the spawned jobs to finish.
+pub struct RayonThreadPool {
+    pool: rayon::ThreadPool,
+    // number of jobs not finished yet
+    pending: Arc<(Mutex<usize>, Condvar)>,
+}
+
+/// Counts a job as finished on drop, even if it panicked
+struct Pending(Arc<(Mutex<usize>, Condvar)>);
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        let (pending, finished) = &*self.0;
+        *pending.lock().unwrap() -= 1;
+        finished.notify_all();
+    }
+}
+
+impl ThreadPool for RayonThreadPool {
+    fn new(threads: u32) -> Result<RayonThreadPool> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads as usize)
+            .thread_name(|_| "kvs-pool".to_owned())
+            .panic_handler(|_| warn!("job panicked in thread {:?}", std::thread::current().id()))
+            .build()?;
+
+        Ok(RayonThreadPool {
+            pool,
+            pending: Arc::new((Mutex::new(0), Condvar::new())),
+        })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        *self.pending.0.lock().unwrap() += 1;
+        let pending = Pending(Arc::clone(&self.pending));
+        self.pool.spawn(move || {
+            let _pending = pending;
+            job();
+        });
+    }
+}
+
+impl Drop for RayonThreadPool {
+    fn drop(&mut self) {
+        // a job dropping the pool can't wait for itself
+        if self.pool.current_thread_index().is_some() {
+            return;
+        }
+        let (pending, finished) = &*self.pending;
+        let _finished = finished
+            .wait_while(pending.lock().unwrap(), |pending| *pending > 0)
+            .unwrap();
+    }
+}
@@ -1,69 +1,400 @@
-use crossbeam::channel::{self, Receiver, Sender};
+use crossbeam::channel::{self, select, Receiver, RecvError, Sender, TrySendError};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use log::debug;
 use log::warn;
 
-use crate::thread_pool::ThreadPool;
+use crate::thread_pool::metrics::Counters;
+use crate::thread_pool::{PoolMetrics, Rejected, ThreadPool};
 
 enum ThreadPoolMessage {
-    RunJob(Box<dyn FnOnce() + Send + 'static>),
+    // with the time it was queued
+    RunJob(Box<dyn FnOnce() + Send + 'static>, Instant),
     Shutdown,
 }
 
+/// What `spawn` does with a job when the queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueuePolicy {
+    /// Wait for a free place in the queue
+    Block,
+    /// Drop the job
+    Reject,
+    /// Drop the oldest queued job to make place for the new one
+    DropOldest,
+}
+
+/// Options to build a SharedQueueThreadPool with, see
+/// `SharedQueueThreadPool::with_options`.
+///
+/// ```
+/// use kvs::thread_pool::{PoolOptions, QueuePolicy, SharedQueueThreadPool};
+///
+/// let options = PoolOptions::new()
+///     .capacity(1024)
+///     .policy(QueuePolicy::Reject)
+///     .max_threads(16);
+/// let pool = SharedQueueThreadPool::with_options(4, options)?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct PoolOptions {
+    capacity: Option<usize>,
+    policy: QueuePolicy,
+    max_threads: Option<u32>,
+    idle_timeout: Duration,
+}
+
+impl Default for PoolOptions {
+    fn default() -> PoolOptions {
+        PoolOptions {
+            capacity: None,
+            policy: QueuePolicy::Block,
+            max_threads: None,
+            idle_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+impl PoolOptions {
+    /// Default options
+    pub fn new() -> PoolOptions {
+        PoolOptions::default()
+    }
+
+    /// Queue at most `capacity` jobs not taken by a thread yet, unbounded
+    /// by default. With 0 a job is only taken if a thread is free.
+    pub fn capacity(mut self, capacity: usize) -> PoolOptions {
+        self.capacity = Some(capacity);
+        self
+    }
+
+    /// What `spawn` does when the queue is full, `QueuePolicy::Block` by
+    /// default.
+    pub fn policy(mut self, policy: QueuePolicy) -> PoolOptions {
+        self.policy = policy;
+        self
+    }
+
+    /// Start threads over the minimum while jobs are queued, up to `max`,
+    /// the minimum by default.
+    pub fn max_threads(mut self, max: u32) -> PoolOptions {
+        self.max_threads = Some(max);
+        self
+    }
+
+    /// Stop the threads over the minimum once idle for `timeout`, a minute
+    /// by default.
+    pub fn idle_timeout(mut self, timeout: Duration) -> PoolOptions {
+        self.idle_timeout = timeout;
+        self
+    }
+}
+
+/// Pool of threads taking jobs from a shared queue.
+///
+/// The pool keeps a minimum number of threads and starts more, up to a
+/// maximum, while jobs are waiting for one. Threads over the minimum stop
+/// once idle for a while. A panicking job doesn't take its thread down.
+/// Dropping the pool waits for the queued jobs to finish and joins the
+/// threads.
 pub struct SharedQueueThreadPool {
     tx: Sender<ThreadPoolMessage>,
-    thread_handles: Vec<thread::JoinHandle<()>>,
+    policy: QueuePolicy,
+    workers: Arc<Workers>,
 }
 
+/// Reads the metrics of a `SharedQueueThreadPool`, see
+/// `SharedQueueThreadPool::monitor`.
 #[derive(Clone)]
-struct TaskReceiver(Receiver<ThreadPoolMessage>);
+pub struct PoolMonitor(Arc<Workers>);
 
-impl Drop for TaskReceiver {
-    fn drop(&mut self) {
-        if thread::panicking() {
-            let rx = self.clone();
-            if let Err(e) = thread::Builder::new().spawn(move || run_task(rx)) {
-                warn!("run task failed, {:?}", e);
+impl PoolMonitor {
+    /// Current metrics of the pool
+    pub fn metrics(&self) -> PoolMetrics {
+        let threads = self.0.state.lock().unwrap().threads;
+        self.0.counters.snapshot(threads, self.0.jobs.len())
+    }
+}
+
+/// State shared by the pool and its threads
+struct Workers {
+    jobs: Receiver<ThreadPoolMessage>,
+    // one message per thread to stop after a `resize`
+    retire_tx: Sender<()>,
+    retire_rx: Receiver<()>,
+    idle_timeout: Duration,
+    state: Mutex<State>,
+    counters: Counters,
+}
+
+struct State {
+    min: u32,
+    max: u32,
+    // running threads, and those waiting for a job
+    threads: u32,
+    idle: u32,
+    // no thread stops but on a `Shutdown` message once set
+    shutdown: bool,
+    handles: Vec<thread::JoinHandle<()>>,
+}
+
+/// What woke an idle thread up
+enum Wake {
+    Message(Result<ThreadPoolMessage, RecvError>),
+    Retire,
+    Timeout,
+}
+
+impl Workers {
+    /// Start a thread, counted in `state`.
+    fn start_thread(self: &Arc<Self>, state: &mut State) -> Result<()> {
+        let workers = Arc::clone(self);
+        let handle = thread::Builder::new()
+            .name("kvs-pool".to_owned())
+            .spawn(move || run_task(workers))?;
+        state.threads += 1;
+        state.handles.retain(|handle| !handle.is_finished());
+        state.handles.push(handle);
+        Ok(())
+    }
+}
+
+fn run_task(workers: Arc<Workers>) {
+    loop {
+        workers.state.lock().unwrap().idle += 1;
+        let wake = select! {
+            recv(workers.jobs) -> message => Wake::Message(message),
+            recv(workers.retire_rx) -> _ => Wake::Retire,
+            default(workers.idle_timeout) => Wake::Timeout,
+        };
+
+        let mut state = workers.state.lock().unwrap();
+        state.idle -= 1;
+        match wake {
+            Wake::Message(Ok(ThreadPoolMessage::RunJob(job, queued))) => {
+                drop(state);
+                if !workers.counters.run(queued, job) {
+                    warn!("job panicked in thread {:?}", thread::current().id());
+                }
+            }
+            Wake::Message(Ok(ThreadPoolMessage::Shutdown)) => {
+                state.threads -= 1;
+                return;
+            }
+            Wake::Message(Err(e)) => {
+                debug!("thread pool exits: {:?}", e);
+                state.threads -= 1;
+                return;
             }
+            Wake::Retire if !state.shutdown && state.threads > state.max => {
+                debug!("thread pool shrinks to {} threads", state.threads - 1);
+                state.threads -= 1;
+                return;
+            }
+            Wake::Timeout if !state.shutdown && state.threads > state.min => {
+                debug!("idle thread stops, {} threads left", state.threads - 1);
+                state.threads -= 1;
+                return;
+            }
+            Wake::Retire | Wake::Timeout => {}
         }
     }
 }
 
-fn run_task(receiver: TaskReceiver) {
-    loop {
-        match receiver.0.recv() {
-            Ok(msg) => match msg {
-                ThreadPoolMessage::RunJob(job) => job(),
-                ThreadPoolMessage::Shutdown => return,
-            },
-            Err(e) => debug!("thread pool exits: {:?}", e),
+fn check_bounds(min: u32, max: u32) -> Result<()> {
+    if min > max {
+        return Err(anyhow!(
+            "thread pool minimum of {} threads over its maximum of {}",
+            min,
+            max
+        ));
+    }
+    Ok(())
+}
+
+impl SharedQueueThreadPool {
+    /// Pool of at least `threads` threads with the given options.
+    pub fn with_options(threads: u32, options: PoolOptions) -> Result<SharedQueueThreadPool> {
+        let max = options.max_threads.unwrap_or(threads);
+        check_bounds(threads, max)?;
+
+        let (tx, rx) = match options.capacity {
+            Some(capacity) => channel::bounded(capacity),
+            None => channel::unbounded(),
+        };
+        let (retire_tx, retire_rx) = channel::unbounded();
+        let workers = Arc::new(Workers {
+            jobs: rx,
+            retire_tx,
+            retire_rx,
+            idle_timeout: options.idle_timeout,
+            state: Mutex::new(State {
+                min: threads,
+                max,
+                threads: 0,
+                idle: 0,
+                shutdown: false,
+                handles: Vec::new(),
+            }),
+            counters: Counters::default(),
+        });
+        {
+            let mut state = workers.state.lock().unwrap();
+            for _ in 0..threads {
+                workers.start_thread(&mut state)?;
+            }
+        }
+
+        Ok(SharedQueueThreadPool {
+            tx,
+            policy: options.policy,
+            workers,
+        })
+    }
+
+    /// Change the bounds of the number of threads. Threads are started up
+    /// to the new minimum right away, those over the new maximum stop once
+    /// done with their job.
+    pub fn resize(&self, min: u32, max: u32) -> Result<()> {
+        check_bounds(min, max)?;
+        let mut state = self.workers.state.lock().unwrap();
+        state.min = min;
+        state.max = max;
+        while state.threads < min {
+            self.workers.start_thread(&mut state)?;
+        }
+        for _ in max..state.threads {
+            let _ = self.workers.retire_tx.send(());
+        }
+        Ok(())
+    }
+
+    /// Number of running threads
+    pub fn threads(&self) -> u32 {
+        self.workers.state.lock().unwrap().threads
+    }
+
+    /// Handle reading the metrics of the pool from anywhere, its jobs
+    /// included.
+    pub fn monitor(&self) -> PoolMonitor {
+        PoolMonitor(Arc::clone(&self.workers))
+    }
+
+    /// Start a thread if the jobs queued would leave none waiting.
+    fn grow(&self) {
+        let mut state = self.workers.state.lock().unwrap();
+        if state.idle as usize <= self.tx.len() && state.threads < state.max {
+            if let Err(e) = self.workers.start_thread(&mut state) {
+                warn!("starting a thread pool thread failed: {:?}", e);
+            }
+        }
+    }
+
+    /// Queue `message` if there is place, or if the policy makes some.
+    fn try_spawn_message(&self, message: ThreadPoolMessage) -> Result<(), Rejected> {
+        match self.policy {
+            QueuePolicy::DropOldest => self.push_dropping_oldest(message),
+            QueuePolicy::Block | QueuePolicy::Reject => {
+                self.tx.try_send(message).map_err(|_| Rejected)
+            }
+        }
+    }
+
+    /// Queue `message`, dropping the oldest queued jobs while the queue is
+    /// full.
+    fn push_dropping_oldest(&self, mut message: ThreadPoolMessage) -> Result<(), Rejected> {
+        loop {
+            match self.tx.try_send(message) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Full(_)) if self.tx.capacity() == Some(0) => {
+                    return Err(Rejected)
+                }
+                Err(TrySendError::Full(rejected)) => {
+                    if self.workers.jobs.try_recv().is_ok() {
+                        warn!("thread pool queue is full, dropped the oldest job");
+                    }
+                    message = rejected;
+                }
+                Err(TrySendError::Disconnected(_)) => return Err(Rejected),
+            }
         }
     }
 }
 
 impl ThreadPool for SharedQueueThreadPool {
     fn new(threads: u32) -> Result<SharedQueueThreadPool> {
-        let (tx, rx) = channel::unbounded();
-        let mut thread_handles = Vec::new();
-        for _ in 0..threads {
-            let task_receiver = TaskReceiver(rx.clone());
-            let handle = thread::spawn(move || {
-                run_task(task_receiver);
-            });
-            thread_handles.push(handle);
-        }
-
-        Ok(SharedQueueThreadPool { tx, thread_handles })
+        SharedQueueThreadPool::with_options(threads, PoolOptions::default())
     }
 
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        if let Err(e) = self.tx.send(ThreadPoolMessage::RunJob(Box::new(job))) {
-            warn!("send RunJob failed: {:?}", e);
+        self.grow();
+        let message = ThreadPoolMessage::RunJob(Box::new(job), Instant::now());
+        match self.policy {
+            QueuePolicy::Block => {
+                if let Err(e) = self.tx.send(message) {
+                    warn!("send RunJob failed: {:?}", e);
+                }
+            }
+            QueuePolicy::Reject | QueuePolicy::DropOldest => {
+                if self.try_spawn_message(message).is_err() {
+                    debug!("thread pool queue is full, job rejected");
+                }
+            }
+        }
+    }
+
+    fn try_spawn<F>(&self, job: F) -> Result<(), Rejected>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.grow();
+        self.try_spawn_message(ThreadPoolMessage::RunJob(Box::new(job), Instant::now()))
+    }
+
+    fn metrics(&self) -> Option<PoolMetrics> {
+        Some(self.monitor().metrics())
+    }
+}
+
+impl Drop for SharedQueueThreadPool {
+    fn drop(&mut self) {
+        // queued after the jobs, each thread takes one once the jobs are
+        // done. A job dropping the pool doesn't take one, its thread exits
+        // once the queue is closed.
+        let current = thread::current().id();
+        let (threads, handles) = {
+            let mut state = self.workers.state.lock().unwrap();
+            state.shutdown = true;
+            let on_pool = state
+                .handles
+                .iter()
+                .any(|handle| handle.thread().id() == current);
+            (
+                state.threads - on_pool as u32,
+                std::mem::take(&mut state.handles),
+            )
+        };
+        for _ in 0..threads {
+            if let Err(e) = self.tx.send(ThreadPoolMessage::Shutdown) {
+                warn!("send Shutdown failed: {:?}", e);
+            }
+        }
+
+        for handle in handles {
+            // a job dropping the pool can't wait for its own thread
+            if handle.thread().id() == current {
+                continue;
+            }
+            if handle.join().is_err() {
+                warn!("thread pool thread panicked");
+            }
         }
     }
 }
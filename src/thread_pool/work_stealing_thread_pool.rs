@@ -0,0 +1,177 @@
+use crossbeam::deque::{Injector, Steal, Stealer, Worker};
+use std::cell::RefCell;
+use std::iter;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{self, AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use anyhow::Result;
+use log::warn;
+
+use crate::thread_pool::ThreadPool;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Pool of threads each with a deque of jobs, stealing from the others
+/// when theirs is empty.
+///
+/// Jobs spawned from outside the pool go to a shared queue the threads
+/// take batches from, jobs spawned by a job go to the deque of its thread.
+/// A panicking job doesn't take its thread down. Dropping the pool waits
+/// for the queued jobs to finish and joins the threads.
+pub struct WorkStealingThreadPool {
+    shared: Arc<Shared>,
+    thread_handles: Vec<thread::JoinHandle<()>>,
+}
+
+struct Shared {
+    injector: Injector<Job>,
+    stealers: Vec<Stealer<Job>>,
+    // idle threads wait on `wake` while `sleeping` is counted
+    sleeping: AtomicUsize,
+    lock: Mutex<()>,
+    wake: Condvar,
+    shutdown: AtomicBool,
+}
+
+thread_local! {
+    // deque of the pool thread running on this thread, with its pool
+    static LOCAL: RefCell<Option<(*const Shared, Worker<Job>)>> = const { RefCell::new(None) };
+}
+
+impl Shared {
+    fn push(self: &Arc<Self>, job: Job) {
+        let job = LOCAL.with(|local| match &*local.borrow() {
+            Some((pool, worker)) if *pool == Arc::as_ptr(self) => {
+                worker.push(job);
+                None
+            }
+            _ => Some(job),
+        });
+        if let Some(job) = job {
+            self.injector.push(job);
+        }
+
+        // pairs with the fence of an idle thread, either it sees the job or
+        // the job is pushed after it counted itself sleeping
+        atomic::fence(Ordering::SeqCst);
+        if self.sleeping.load(Ordering::SeqCst) > 0 {
+            let _lock = self.lock.lock().unwrap();
+            self.wake.notify_one();
+        }
+    }
+
+    /// Next job for the thread owning `local`, from its deque, else from
+    /// the shared queue, else from the other threads.
+    fn find_job(&self, local: &Worker<Job>) -> Option<Job> {
+        local.pop().or_else(|| {
+            iter::repeat_with(|| {
+                self.injector
+                    .steal_batch_and_pop(local)
+                    .or_else(|| self.stealers.iter().map(Stealer::steal).collect())
+            })
+            .find(|steal| !steal.is_retry())
+            .and_then(Steal::success)
+        })
+    }
+}
+
+fn run_worker(shared: Arc<Shared>, local: Worker<Job>) {
+    LOCAL.with(|cell| *cell.borrow_mut() = Some((Arc::as_ptr(&shared), local)));
+
+    loop {
+        let job = LOCAL.with(|cell| {
+            let cell = cell.borrow();
+            let (_, local) = cell.as_ref().unwrap();
+            shared.find_job(local)
+        });
+        match job {
+            Some(job) => {
+                if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                    warn!("job panicked in thread {:?}", thread::current().id());
+                }
+            }
+            None => {
+                let lock = shared.lock.lock().unwrap();
+                shared.sleeping.fetch_add(1, Ordering::SeqCst);
+                atomic::fence(Ordering::SeqCst);
+
+                let idle = LOCAL.with(|cell| {
+                    let cell = cell.borrow();
+                    let (_, local) = cell.as_ref().unwrap();
+                    local.is_empty()
+                        && shared.injector.is_empty()
+                        && shared.stealers.iter().all(Stealer::is_empty)
+                });
+                if idle && shared.shutdown.load(Ordering::SeqCst) {
+                    shared.sleeping.fetch_sub(1, Ordering::SeqCst);
+                    break;
+                }
+                if idle {
+                    drop(shared.wake.wait(lock).unwrap());
+                }
+                shared.sleeping.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    LOCAL.with(|cell| cell.borrow_mut().take());
+}
+
+impl ThreadPool for WorkStealingThreadPool {
+    fn new(threads: u32) -> Result<WorkStealingThreadPool> {
+        let workers: Vec<_> = (0..threads).map(|_| Worker::new_fifo()).collect();
+        let shared = Arc::new(Shared {
+            injector: Injector::new(),
+            stealers: workers.iter().map(Worker::stealer).collect(),
+            sleeping: AtomicUsize::new(0),
+            lock: Mutex::new(()),
+            wake: Condvar::new(),
+            shutdown: AtomicBool::new(false),
+        });
+
+        let mut thread_handles = Vec::new();
+        for worker in workers {
+            let shared = Arc::clone(&shared);
+            let handle = thread::Builder::new()
+                .name("kvs-pool".to_owned())
+                .spawn(move || run_worker(shared, worker))?;
+            thread_handles.push(handle);
+        }
+
+        Ok(WorkStealingThreadPool {
+            shared,
+            thread_handles,
+        })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.shared.push(Box::new(job));
+    }
+}
+
+impl Drop for WorkStealingThreadPool {
+    fn drop(&mut self) {
+        // the threads exit once no job is left
+        {
+            let _lock = self.shared.lock.lock().unwrap();
+            self.shared.shutdown.store(true, Ordering::SeqCst);
+            self.shared.wake.notify_all();
+        }
+
+        let current = thread::current().id();
+        for handle in self.thread_handles.drain(..) {
+            // a job dropping the pool can't wait for its own thread
+            if handle.thread().id() == current {
+                continue;
+            }
+            if handle.join().is_err() {
+                warn!("thread pool thread panicked");
+            }
+        }
+    }
+}
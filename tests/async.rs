@@ -0,0 +1,173 @@
+#![cfg(feature = "async")]
+
+use assert_cmd::prelude::*;
+use kvs::client::{AsyncKvsClient, KvsClient};
+use kvs::kvs::EngineError;
+use kvs::server::KvsServer;
+use kvs::{KvStore, KvsEngine, Result, SledEngine, WriteBatch};
+use std::process::Command;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::net::TcpListener;
+
+mod common;
+
+use common::start_server;
+
+// Every request of the protocol goes through the async server and client
+#[tokio::test(flavor = "multi_thread")]
+async fn async_server_and_client() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::new(KvStore::open(temp_dir.path())?).blocking_threads(2);
+    tokio::spawn(server.run(listener));
+
+    let mut client = AsyncKvsClient::connect(addr).await?;
+    assert_eq!(client.version(), kvs::protocol::VERSION);
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert_eq!(
+        client.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(client.get("key2".to_owned()).await?, None);
+
+    client
+        .set_bytes(b"bin\xff".to_vec(), vec![0, 159, 146, 150])
+        .await?;
+    assert_eq!(
+        client.get_bytes(b"bin\xff".to_vec()).await?,
+        Some(vec![0, 159, 146, 150])
+    );
+
+    client.remove("key1".to_owned()).await?;
+    assert!(matches!(
+        client.remove("key1".to_owned()).await,
+        Err(EngineError::NotFound(_))
+    ));
+
+    client
+        .set_with_ttl("ttl".to_owned(), "v".to_owned(), Duration::from_millis(50))
+        .await?;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(client.get("ttl".to_owned()).await?, None);
+
+    let mut batch = WriteBatch::new();
+    batch.set("a1", "1");
+    batch.set("a2", "2");
+    batch.set("b1", "3");
+    client.apply_batch(batch).await?;
+    assert!(
+        client
+            .compare_and_swap("a1".to_owned(), Some("1".to_owned()), Some("10".to_owned()))
+            .await?
+    );
+    assert!(
+        !client
+            .compare_and_swap("a1".to_owned(), Some("1".to_owned()), None)
+            .await?
+    );
+
+    assert_eq!(
+        client.scan_prefix_bytes(b"a".to_vec()).await?,
+        vec![
+            (b"a1".to_vec(), b"10".to_vec()),
+            (b"a2".to_vec(), b"2".to_vec())
+        ]
+    );
+    let pairs = client
+        .scan_bytes(b"a2".to_vec(), Some(b"b2".to_vec()))
+        .await?;
+    assert_eq!(pairs.len(), 2);
+
+    Ok(())
+}
+
+// Hundreds of connections are open at once, more than the blocking threads
+#[tokio::test(flavor = "multi_thread")]
+async fn async_server_many_connections() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::new(SledEngine::open(temp_dir.path())?).blocking_threads(4);
+    tokio::spawn(server.run(listener));
+
+    let mut clients = Vec::new();
+    for _ in 0..200 {
+        clients.push(AsyncKvsClient::connect(addr).await?);
+    }
+
+    let mut tasks = Vec::new();
+    for (i, mut client) in clients.into_iter().enumerate() {
+        tasks.push(tokio::spawn(async move {
+            let key = format!("key{}", i);
+            client.set(key.clone(), format!("value{}", i)).await?;
+            client.get(key).await
+        }));
+    }
+    for (i, task) in tasks.into_iter().enumerate() {
+        assert_eq!(task.await.unwrap()?, Some(format!("value{}", i)));
+    }
+
+    Ok(())
+}
+
+// Connections are closed and the engine calls finished once run_until returns
+#[tokio::test(flavor = "multi_thread")]
+async fn async_server_graceful_shutdown() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let server = KvsServer::new(KvStore::open(temp_dir.path())?)
+        .drain_timeout(Duration::from_secs(10))
+        .run_until(listener, async {
+            let _ = stopped.await;
+        });
+    let server = tokio::spawn(server);
+
+    let mut client = AsyncKvsClient::connect(addr).await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    let mut idle = AsyncKvsClient::connect(addr).await?;
+
+    stop.send(()).unwrap();
+    // the idle connection doesn't hold the server up to the drain timeout
+    tokio::time::timeout(Duration::from_secs(2), server)
+        .await
+        .expect("server still running")
+        .unwrap()?;
+
+    assert!(idle.get("key1".to_owned()).await.is_err());
+    assert!(client.get("key1".to_owned()).await.is_err());
+    assert!(AsyncKvsClient::connect(addr).await.is_err());
+    assert_eq!(
+        KvStore::open(temp_dir.path())?.get("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+
+    Ok(())
+}
+
+// kvs-server --async speaks the same protocol as the blocking server
+#[test]
+fn kvs_server_async_flag() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let server = start_server(&temp_dir, &["--engine", "kvs", "--async"]);
+    let addr = server.addr();
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.remove("key1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key2", "value2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
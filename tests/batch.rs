@@ -0,0 +1,113 @@
+use kvs::{KvStore, KvStoreOptions, KvsEngine, Result, SledEngine, WriteBatch};
+use std::fs::{self, OpenOptions};
+use tempfile::TempDir;
+
+fn check_batch<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch.set("key1".to_owned(), "value3".to_owned());
+    batch.remove("key2".to_owned());
+    batch.set("key3".to_owned(), "value4".to_owned());
+    batch.remove("key3".to_owned());
+    batch.set("key4".to_owned(), "value5".to_owned());
+    // removing a missing key is skipped
+    batch.remove("key5".to_owned());
+    engine.apply_batch(batch)?;
+
+    assert_eq!(engine.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    assert_eq!(engine.get("key3".to_owned())?, None);
+    assert_eq!(engine.get("key4".to_owned())?, Some("value5".to_owned()));
+    assert_eq!(engine.get("key5".to_owned())?, None);
+
+    engine.apply_batch(WriteBatch::new())?;
+
+    Ok(())
+}
+
+#[test]
+fn batch_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_batch(KvStore::open(temp_dir.path())?)?;
+
+    // Open from disk again and check persistent data
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.get("key4".to_owned())?, Some("value5".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn batch_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_batch(SledEngine::open(temp_dir.path())?)
+}
+
+// A batch torn by a crash is dropped as a whole
+#[test]
+fn recover_torn_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key0".to_owned(), "value0".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    for i in 1..10 {
+        batch.set(format!("key{}", i), format!("value{}", i));
+    }
+    batch.remove("key0".to_owned());
+    store.apply_batch(batch)?;
+    drop(store);
+
+    // cut the batch record after its first inner records
+    let path = temp_dir.path().join("db.log");
+    let len = fs::metadata(&path)?.len();
+    let file = OpenOptions::new().write(true).open(&path)?;
+    file.set_len(len - 100)?;
+    drop(file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.recovery_report().is_some());
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    for i in 1..10 {
+        assert_eq!(store.get(format!("key{}", i))?, None);
+    }
+
+    Ok(())
+}
+
+// Batch records are replayed from sealed files and their hints, and their
+// records survive compaction
+#[test]
+fn batch_across_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .segment_size(1024)
+        .compaction_min_bytes(4 * 1024);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for iter in 0..100 {
+        let mut batch = WriteBatch::new();
+        for key_id in 0..10 {
+            batch.set(format!("key{}", key_id), format!("{}", iter));
+        }
+        batch.remove(format!("key{}", iter % 10));
+        store.apply_batch(batch)?;
+    }
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..10 {
+        let expected = if key_id == 9 {
+            None
+        } else {
+            Some("99".to_owned())
+        };
+        assert_eq!(store.get(format!("key{}", key_id))?, expected);
+    }
+
+    Ok(())
+}
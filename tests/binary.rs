@@ -0,0 +1,93 @@
+use kvs::kvs::EngineError;
+use kvs::{KvStore, KvStoreOptions, KvsEngine, Result, SledEngine, WriteBatch};
+use tempfile::TempDir;
+
+fn check_binary_round_trip<E: KvsEngine>(engine: E) -> Result<()> {
+    let key = vec![0xff, 0x00, b'k'];
+    let value = vec![0x80, 0x00, 0xfe, 0xff];
+    engine.set_bytes(key.clone(), value.clone())?;
+    assert_eq!(engine.get_bytes(key.clone())?, Some(value.clone()));
+
+    // the string methods refuse values that are not text
+    engine.set_bytes(b"text".to_vec(), value.clone())?;
+    assert!(matches!(
+        engine.get("text".to_owned()),
+        Err(EngineError::Utf8(_))
+    ));
+
+    let mut batch = WriteBatch::new();
+    batch.set(vec![0xff, 0x01], vec![0x00]);
+    batch.remove(b"text".to_vec());
+    engine.apply_batch(batch)?;
+    assert_eq!(engine.get_bytes(b"text".to_vec())?, None);
+
+    let pairs = engine
+        .scan_prefix_bytes(vec![0xff])?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        pairs,
+        vec![(key.clone(), value.clone()), (vec![0xff, 0x01], vec![0x00])]
+    );
+
+    assert!(engine.compare_and_swap_bytes(key.clone(), Some(value), Some(vec![0xc3, 0x28]))?);
+    engine.remove_bytes(vec![0xff, 0x01])?;
+    assert!(matches!(
+        engine.remove_bytes(vec![0xff, 0x01]),
+        Err(EngineError::NotFound(_))
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn binary_round_trip_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_binary_round_trip(KvStore::open(temp_dir.path())?)?;
+
+    // Open from disk again and check persistent data
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_bytes(vec![0xff, 0x00, b'k'])?,
+        Some(vec![0xc3, 0x28])
+    );
+    assert_eq!(store.get_bytes(vec![0xff, 0x01])?, None);
+
+    Ok(())
+}
+
+#[test]
+fn binary_round_trip_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_binary_round_trip(SledEngine::open(temp_dir.path())?)
+}
+
+// Binary values survive compaction, which rewrites them to a compact file
+#[test]
+fn binary_values_survive_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .segment_size(4096)
+        .compaction_min_bytes(16 * 1024);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+
+    for iter in 0..1000_u32 {
+        for key_id in 0..100_u32 {
+            store.set_bytes(key_id.to_be_bytes().to_vec(), iter.to_le_bytes().to_vec())?;
+        }
+    }
+    drop(store);
+    let compacted = std::fs::read_dir(temp_dir.path())?
+        .filter_map(|entry| entry.ok())
+        .any(|entry| entry.file_name().to_string_lossy().starts_with("compact-"));
+    assert!(compacted, "No compaction detected");
+
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100_u32 {
+        assert_eq!(
+            store.get_bytes(key_id.to_be_bytes().to_vec())?,
+            Some(999_u32.to_le_bytes().to_vec())
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,103 @@
+use kvs::{KvStore, KvsEngine, Result, SledEngine};
+use std::thread;
+use tempfile::TempDir;
+
+fn check_compare_and_swap<E: KvsEngine>(engine: E) -> Result<()> {
+    // absent key
+    assert!(!engine.compare_and_swap(
+        "key1".to_owned(),
+        Some("value1".to_owned()),
+        Some("value2".to_owned())
+    )?);
+    assert!(engine.compare_and_swap("key1".to_owned(), None, Some("value1".to_owned()))?);
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // mismatch leaves the value alone
+    assert!(!engine.compare_and_swap(
+        "key1".to_owned(),
+        Some("other".to_owned()),
+        Some("value2".to_owned())
+    )?);
+    assert!(!engine.compare_and_swap("key1".to_owned(), None, Some("value2".to_owned()))?);
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    assert!(engine.compare_and_swap(
+        "key1".to_owned(),
+        Some("value1".to_owned()),
+        Some("value2".to_owned())
+    )?);
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    // swap to none removes the key
+    assert!(engine.compare_and_swap("key1".to_owned(), Some("value2".to_owned()), None)?);
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert!(engine.compare_and_swap("key1".to_owned(), None, None)?);
+
+    assert!(engine.set_if_absent("key2".to_owned(), "value1".to_owned())?);
+    assert!(!engine.set_if_absent("key2".to_owned(), "value2".to_owned())?);
+    assert_eq!(engine.get("key2".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Concurrent read-modify-write loops lose no increment
+fn check_concurrent_increments<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.set("counter".to_owned(), "0".to_owned())?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let engine = engine.clone();
+            thread::spawn(move || {
+                for _ in 0..50 {
+                    loop {
+                        let current = engine.get("counter".to_owned()).unwrap().unwrap();
+                        let next = (current.parse::<u32>().unwrap() + 1).to_string();
+                        if engine
+                            .compare_and_swap("counter".to_owned(), Some(current), Some(next))
+                            .unwrap()
+                        {
+                            break;
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    assert_eq!(engine.get("counter".to_owned())?, Some("400".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn compare_and_swap_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_compare_and_swap(KvStore::open(temp_dir.path())?)?;
+
+    // Open from disk again and check persistent data
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn compare_and_swap_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_compare_and_swap(SledEngine::open(temp_dir.path())?)
+}
+
+#[test]
+fn concurrent_compare_and_swap_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_concurrent_increments(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn concurrent_compare_and_swap_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_concurrent_increments(SledEngine::open(temp_dir.path())?)
+}
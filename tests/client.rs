@@ -0,0 +1,424 @@
+use assert_cmd::prelude::*;
+use kvs::client::{ClientOptions, Connection, KvsClient};
+use kvs::kvs::EngineError;
+use kvs::protocol::{self, ErrorCode, ReplyResult, Request, Response};
+use kvs::Result;
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+mod common;
+
+use common::{free_addr, kvs_server, start_server, Server};
+
+fn set(key: &str, value: &str) -> Request {
+    Request::Set {
+        key: key.as_bytes().to_vec(),
+        value: value.as_bytes().to_vec(),
+    }
+}
+
+fn get(key: &str) -> Request {
+    Request::Get {
+        key: key.as_bytes().to_vec(),
+    }
+}
+
+fn remove(key: &str) -> Request {
+    Request::Remove {
+        key: key.as_bytes().to_vec(),
+    }
+}
+
+fn value(value: &str) -> ReplyResult {
+    Ok(Response::Value(Some(value.as_bytes().to_vec())))
+}
+
+// Thousands of requests go over one connection before any reply is read
+#[test]
+fn client_pipelined_requests() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let server = start_server(&temp_dir, &["--engine", "kvs"]);
+    let addr = server.addr();
+
+    let mut conn = Connection::connect(addr)?;
+    assert_eq!(conn.version(), protocol::VERSION);
+    for i in 0..2000 {
+        conn.send(&set(&format!("key{}", i), &format!("value{}", i)))?;
+    }
+    for i in 0..2000 {
+        conn.send(&get(&format!("key{}", i)))?;
+    }
+    assert_eq!(conn.pending(), 4000);
+
+    for _ in 0..2000 {
+        assert_eq!(conn.recv()?, Ok(Response::Done));
+    }
+    for i in 0..2000 {
+        assert_eq!(conn.recv()?, value(&format!("value{}", i)));
+    }
+    assert_eq!(conn.pending(), 0);
+
+    Ok(())
+}
+
+// A failed request leaves the connection usable for the next ones
+#[test]
+fn client_connection_survives_errors() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let server = start_server(&temp_dir, &["--engine", "kvs"]);
+    let addr = server.addr();
+
+    let mut conn = Connection::connect(addr)?;
+    conn.send(&remove("key1"))?;
+    conn.send(&get("key1"))?;
+    conn.send(&set("key1", "value1"))?;
+    conn.send(&remove("key1"))?;
+    conn.send(&get("key1"))?;
+
+    let not_found = conn.recv()?.unwrap_err();
+    assert_eq!(not_found.code, ErrorCode::NotFound);
+    assert!(not_found.message.contains("Key not found"));
+    assert_eq!(conn.recv()?, Ok(Response::Value(None)));
+    assert_eq!(conn.recv()?, Ok(Response::Done));
+    assert_eq!(conn.recv()?, Ok(Response::Done));
+    assert_eq!(conn.recv()?, Ok(Response::Value(None)));
+
+    // requests one at a time on the same connection
+    for i in 0..10 {
+        conn.send(&set("key2", &format!("{}", i)))?;
+        assert_eq!(conn.recv()?, Ok(Response::Done));
+    }
+    conn.send(&get("key2"))?;
+    assert_eq!(conn.recv()?, value("9"));
+
+    Ok(())
+}
+
+fn check_engine_opened_once(engine: &str) -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = free_addr();
+    let server = Server::spawn(
+        kvs_server(&temp_dir, &addr)
+            .args(["--engine", engine])
+            .stderr(Stdio::piped()),
+        &addr,
+    );
+
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let addr = addr.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..10 {
+                    let key = format!("key{}-{}", thread_id, i);
+                    let mut conn = Connection::connect(addr.as_str())?;
+                    conn.send(&set(&key, "value"))?;
+                    conn.send(&get(&key))?;
+                    assert_eq!(conn.recv()?, Ok(Response::Done));
+                    assert_eq!(conn.recv()?, value("value"));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    let res = handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .collect::<Result<Vec<_>>>();
+
+    let output = server.kill();
+    res?;
+
+    let log = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(log.matches("opened storage engine").count(), 1, "{}", log);
+    Ok(())
+}
+
+// 80 connections share the engine opened at startup
+#[test]
+fn engine_opened_once_kvs() -> Result<()> {
+    check_engine_opened_once("kvs")
+}
+
+#[test]
+fn engine_opened_once_sled() -> Result<()> {
+    check_engine_opened_once("sled")
+}
+
+#[test]
+fn kvs_client_access_server() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let server = start_server(&temp_dir, &["--engine", "kvs"]);
+    let addr = server.addr();
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set_bytes(b"key2".to_vec(), vec![0xff, 0x00])?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get_bytes(b"key2".to_vec())?, Some(vec![0xff, 0x00]));
+    assert_eq!(client.get("key3".to_owned())?, None);
+
+    // values that are not text fail the string methods
+    assert!(matches!(
+        client.get("key2".to_owned()),
+        Err(EngineError::Utf8(_))
+    ));
+    client.remove("key1".to_owned())?;
+    assert!(matches!(
+        client.remove("key1".to_owned()),
+        Err(EngineError::NotFound(key)) if key == "key1"
+    ));
+
+    assert!(client.compare_and_swap("key1".to_owned(), None, Some("value2".to_owned()))?);
+    assert_eq!(
+        client.scan_prefix_bytes(b"key".to_vec())?,
+        vec![
+            (b"key1".to_vec(), b"value2".to_vec()),
+            (b"key2".to_vec(), vec![0xff, 0x00])
+        ]
+    );
+
+    Ok(())
+}
+
+// Gets and sets are sent again on a new connection after a server restart
+#[test]
+fn kvs_client_reconnects() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = free_addr();
+    let server = Server::spawn(
+        kvs_server(&temp_dir, &addr).args(["--engine", "kvs"]),
+        &addr,
+    );
+
+    let mut client = KvsClient::connect(addr.as_str())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+
+    drop(server);
+    let _server = Server::spawn(
+        kvs_server(&temp_dir, &addr).args(["--engine", "kvs"]),
+        &addr,
+    );
+
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// A server that never replies fails the request once the timeout is over
+#[test]
+fn kvs_client_timeout() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handle = thread::spawn(move || {
+        // hold the connection open without a word
+        let (stream, _) = listener.accept().unwrap();
+        thread::sleep(Duration::from_secs(2));
+        drop(stream);
+    });
+
+    let options = ClientOptions::new().timeout(Duration::from_millis(200));
+    let start = Instant::now();
+    let res = KvsClient::connect_with(addr, options);
+    assert!(matches!(res, Err(EngineError::Io(_))));
+    assert!(start.elapsed() < Duration::from_secs(1));
+
+    handle.join().unwrap();
+}
+
+// Requests over the queue capacity are told the server is busy
+#[test]
+fn kvs_client_server_busy() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let server = start_server(
+        &temp_dir,
+        &[
+            "--engine",
+            "kvs",
+            "--threads",
+            "4",
+            "--max-threads",
+            "4",
+            "--queue-capacity",
+            "0",
+            "--queue-policy",
+            "reject",
+        ],
+    );
+    let addr = server.addr();
+
+    // a thread waits for the rest of each handshake
+    let stalled = (0..4)
+        .map(|_| {
+            let mut stream = TcpStream::connect(addr)?;
+            stream.write_all(&protocol::MAGIC)?;
+            Ok(stream)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    thread::sleep(Duration::from_millis(100));
+
+    // no retry to hide a reused connection
+    let options = ClientOptions::new().retries(0);
+    let mut client = KvsClient::connect_with(addr, options)?;
+    match client.get("key1".to_owned()) {
+        Err(EngineError::Server { code, message }) => {
+            assert_eq!(code, ErrorCode::Busy);
+            assert_eq!(message, "server busy");
+        }
+        res => panic!("expected a busy server, got {:?}", res),
+    }
+
+    // served again once the threads are free, on a new connection
+    drop(stalled);
+    thread::sleep(Duration::from_millis(100));
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Idle connections hold no thread, whatever the pool
+#[test]
+fn kvs_client_idle_connections() -> Result<()> {
+    for pool in ["shared-queue", "naive", "work-stealing"] {
+        let temp_dir = TempDir::new().unwrap();
+        let args = ["--engine", "kvs", "--pool", pool, "--threads", "2"];
+        let server = start_server(&temp_dir, &args);
+        let addr = server.addr();
+
+        let mut idle = (0..32)
+            .map(|_| Connection::connect(addr))
+            .collect::<Result<Vec<_>>>()?;
+
+        let options = ClientOptions::new().timeout(Duration::from_secs(1));
+        let mut client = KvsClient::connect_with(addr, options)?;
+        client.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+        // and are served when they speak up
+        for conn in &mut idle {
+            conn.send(&get("key1"))?;
+        }
+        for conn in &mut idle {
+            assert_eq!(conn.recv()?, value("value1"));
+        }
+
+        // only the shared-queue pool reports its metrics
+        let stats = client.stats()?;
+        let pool_stats = stats.iter().any(|(name, _)| name.starts_with("pool_"));
+        assert_eq!(pool_stats, pool == "shared-queue");
+    }
+    Ok(())
+}
+
+// Connections that never handshake don't hold up the other clients
+#[test]
+fn kvs_client_busy_silent_connections() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let args = ["--engine", "kvs", "--pool", "naive", "--threads", "1"];
+    let server = start_server(&temp_dir, &args);
+    let addr = server.addr();
+
+    let conn = Connection::connect(addr)?;
+    let silent = (0..3)
+        .map(|_| TcpStream::connect(addr))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    thread::sleep(Duration::from_millis(100));
+    drop(conn);
+    thread::sleep(Duration::from_millis(100));
+
+    let start = Instant::now();
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(start.elapsed() < Duration::from_millis(500));
+    drop(silent);
+    Ok(())
+}
+
+// Running out of file descriptors doesn't stop the server, it accepts
+// connections again once some are closed
+#[test]
+fn kvs_server_failed_accept() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = free_addr();
+    let mut command = kvs_server(&temp_dir, &addr);
+    command.args(["--engine", "kvs"]).stderr(Stdio::piped());
+    // SAFETY: setrlimit is async-signal-safe
+    unsafe {
+        command.pre_exec(|| {
+            let limit = libc::rlimit {
+                rlim_cur: 64,
+                rlim_max: 64,
+            };
+            if libc::setrlimit(libc::RLIMIT_NOFILE, &limit) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let server = Server::spawn(&mut command, &addr);
+
+    let conns = (0..64)
+        .map(|_| TcpStream::connect(&addr))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    thread::sleep(Duration::from_millis(300));
+    drop(conns);
+    thread::sleep(Duration::from_millis(300));
+
+    let mut client = KvsClient::connect(&addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(client);
+
+    let stderr = String::from_utf8(server.kill().stderr).unwrap();
+    assert!(stderr.contains("accepting connection failed"), "{}", stderr);
+    Ok(())
+}
+
+// Stats requests report the thread pool metrics
+#[test]
+fn kvs_client_stats() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let server = start_server(&temp_dir, &["--engine", "kvs"]);
+    let addr = server.addr();
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    let stats: HashMap<_, _> = client.stats()?.into_iter().collect();
+    assert_eq!(stats["version"], env!("CARGO_PKG_VERSION"));
+    // the stats request is a job running
+    assert!(stats["pool_active_workers"].parse::<u32>().unwrap() >= 1);
+    assert_eq!(stats["pool_panics"], "0");
+    assert!(stats["pool_threads"].parse::<u32>().unwrap() >= 1);
+    assert!(stats.contains_key("pool_queue_depth"));
+    assert!(stats.contains_key("pool_queue_latency_p99_us"));
+    assert!(stats.contains_key("pool_run_latency_p50_us"));
+    // the handshake and the set at least
+    let completed: u64 = stats["pool_jobs_completed"].parse().unwrap();
+    assert!(completed >= 2);
+
+    // each request is a job
+    for _ in 0..10 {
+        client.get("key1".to_owned())?;
+    }
+    let output = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["stats", "--addr", addr])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let line = stdout
+        .lines()
+        .find(|line| line.starts_with("pool_jobs_completed\t"))
+        .unwrap();
+    let now: u64 = line["pool_jobs_completed\t".len()..].parse().unwrap();
+    assert!(now >= completed + 10, "{}", stdout);
+    Ok(())
+}
@@ -0,0 +1,96 @@
+//! kvs-server fixture shared by the integration tests and benches
+
+#![allow(dead_code)]
+
+use std::io::Read;
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::process::{Child, Command, Output};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// Time given to a server to listen once started
+const START_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// kvs-server killed on drop, even if the test fails
+pub struct Server {
+    child: Option<Child>,
+    addr: String,
+}
+
+impl Server {
+    /// Spawn `command`, a kvs-server listening on `addr`, and wait for it
+    /// to listen.
+    pub fn spawn(command: &mut Command, addr: &str) -> Server {
+        let server = Server {
+            child: Some(command.spawn().unwrap()),
+            addr: addr.to_owned(),
+        };
+        wait_for_server(addr);
+        server
+    }
+
+    /// Address the server listens on
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    /// Kill the server, returns what it wrote to the piped outputs.
+    pub fn kill(mut self) -> Output {
+        let mut child = self.child.take().unwrap();
+        child.kill().expect("server exited before killed");
+        child.wait_with_output().unwrap()
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        if let Some(child) = &mut self.child {
+            let killed = child.kill();
+            child.wait().unwrap();
+            if !thread::panicking() {
+                killed.expect("server exited before killed");
+            }
+        }
+    }
+}
+
+/// Command running kvs-server on `addr` in `temp_dir`
+pub fn kvs_server(temp_dir: &TempDir, addr: &str) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_kvs-server"));
+    command.args(["--addr", addr]).current_dir(temp_dir);
+    command
+}
+
+/// Start kvs-server with `args` in `temp_dir`, on a free port.
+pub fn start_server(temp_dir: &TempDir, args: &[&str]) -> Server {
+    let addr = free_addr();
+    Server::spawn(kvs_server(temp_dir, &addr).args(args), &addr)
+}
+
+/// Local address on a port free right now, picked by the OS out of the
+/// ephemeral range, away from the fixed ports of the cli tests.
+pub fn free_addr() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+/// Wait for a server to accept connections on `addr`. The probing
+/// connection is closed by the server before this returns, it holds none
+/// of its threads.
+pub fn wait_for_server(addr: &str) {
+    let start = Instant::now();
+    loop {
+        match TcpStream::connect(addr) {
+            Ok(mut stream) => {
+                let _ = stream.shutdown(Shutdown::Write);
+                let _ = stream.read_to_end(&mut Vec::new());
+                return;
+            }
+            Err(e) if start.elapsed() > START_TIMEOUT => {
+                panic!("server not listening on {}: {}", addr, e)
+            }
+            Err(_) => thread::sleep(Duration::from_millis(10)),
+        }
+    }
+}
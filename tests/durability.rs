@@ -0,0 +1,138 @@
+use kvs::{KvStore, KvStoreOptions, KvsEngine, Result, SledEngine, SyncPolicy};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Write `count` keys, crash the store and return how many survived.
+fn survivors(policy: SyncPolicy, count: usize, wait: Duration) -> Result<usize> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .segment_size(1024 * 1024)
+        .sync_policy(policy);
+
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for i in 0..count {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    thread::sleep(wait);
+    store.simulate_crash()?;
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    let mut found = 0;
+    for i in 0..count {
+        if let Some(value) = store.get(format!("key{}", i))? {
+            assert_eq!(value, format!("value{}", i));
+            found += 1;
+        }
+    }
+    Ok(found)
+}
+
+// Unsynced writes of the active file are lost on a crash with `Flush`.
+#[test]
+fn crash_with_flush_policy() -> Result<()> {
+    assert_eq!(
+        survivors(SyncPolicy::Flush, 100, Duration::from_secs(0))?,
+        0
+    );
+    Ok(())
+}
+
+#[test]
+fn crash_with_always_policy() -> Result<()> {
+    assert_eq!(
+        survivors(SyncPolicy::Always, 100, Duration::from_secs(0))?,
+        100
+    );
+    Ok(())
+}
+
+#[test]
+fn crash_with_interval_policy() -> Result<()> {
+    let interval = Duration::from_millis(50);
+    assert_eq!(
+        survivors(SyncPolicy::Interval(interval), 100, interval * 4)?,
+        100
+    );
+    Ok(())
+}
+
+// Writes acknowledged under group commit survive a crash, even when
+// concurrent writers share syncs.
+#[test]
+fn crash_with_group_commit_policy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .segment_size(1024 * 1024)
+        .sync_policy(SyncPolicy::GroupCommit);
+
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    let mut handles = Vec::new();
+    for t in 0..8 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for i in 0..50 {
+                store
+                    .set(format!("key{}-{}", t, i), format!("value{}", i))
+                    .unwrap();
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    store.simulate_crash()?;
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for t in 0..8 {
+        for i in 0..50 {
+            assert_eq!(
+                store.get(format!("key{}-{}", t, i))?,
+                Some(format!("value{}", i))
+            );
+        }
+    }
+    Ok(())
+}
+
+// Sealed files are synced when the active file is rotated, so only the
+// tail of the log can be lost.
+#[test]
+fn crash_keeps_sealed_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().segment_size(1024);
+
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for i in 0..200 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.simulate_crash()?;
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    Ok(())
+}
+
+// sled only flushes in the background with `Interval`, the writes are kept
+// all the same
+#[test]
+fn sled_with_interval_policy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let policy = SyncPolicy::Interval(Duration::from_millis(10));
+    let engine = SledEngine::open_with(temp_dir.path(), policy)?;
+    for i in 0..100 {
+        engine.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    thread::sleep(Duration::from_millis(100));
+    drop(engine);
+
+    let engine = SledEngine::open(temp_dir.path())?;
+    for i in 0..100 {
+        assert_eq!(
+            engine.get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+
+    Ok(())
+}
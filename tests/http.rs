@@ -0,0 +1,163 @@
+#![cfg(feature = "http")]
+
+use kvs::client::KvsClient;
+use kvs::Result;
+use serde_json::{json, Value};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use tempfile::TempDir;
+
+mod common;
+
+use common::{free_addr, wait_for_server, Server};
+
+/// Start kvs-server with the gateway, returns it with the gateway address.
+fn start_server(temp_dir: &TempDir, engine: &str) -> (Server, String) {
+    let http = free_addr();
+    let server = common::start_server(temp_dir, &["--engine", engine, "--http", &http]);
+    wait_for_server(&http);
+    (server, http)
+}
+
+/// Raw HTTP/1.1 request, returns the status code and the body
+fn request(addr: &str, method: &str, path: &str, body: &[u8]) -> (u16, Vec<u8>) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        method,
+        path,
+        addr,
+        body.len()
+    )
+    .unwrap();
+    stream.write_all(body).unwrap();
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    let end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .expect("response without headers end");
+    let status = String::from_utf8_lossy(&response[9..12]).parse().unwrap();
+    (status, response[end + 4..].to_vec())
+}
+
+fn json_request(addr: &str, method: &str, path: &str) -> (u16, Value) {
+    let (status, body) = request(addr, method, path, b"");
+    (status, serde_json::from_slice(&body).unwrap())
+}
+
+#[test]
+fn http_gateway() -> Result<()> {
+    for engine in ["kvs", "sled"] {
+        let temp_dir = TempDir::new().unwrap();
+        let (server, http) = start_server(&temp_dir, engine);
+        let (addr, http) = (server.addr(), http.as_str());
+
+        assert_eq!(
+            json_request(http, "GET", "/health"),
+            (200, json!({ "status": "ok" }))
+        );
+
+        assert_eq!(request(http, "PUT", "/keys/key1", b"value1"), (204, vec![]));
+        assert_eq!(
+            request(http, "GET", "/keys/key1", b""),
+            (200, b"value1".to_vec())
+        );
+
+        // binary keys and values, percent-encoded in the path
+        assert_eq!(
+            request(http, "PUT", "/keys/bin%FF%2F", &[0, 159, 146, 150]),
+            (204, vec![])
+        );
+        assert_eq!(
+            request(http, "GET", "/keys/bin%ff%2f", b""),
+            (200, vec![0, 159, 146, 150])
+        );
+
+        // the engine is shared with the kvs protocol
+        let mut client = KvsClient::connect(addr)?;
+        assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+        client.set("key2".to_owned(), "value2".to_owned())?;
+        client.set("other".to_owned(), "value3".to_owned())?;
+
+        assert_eq!(
+            json_request(http, "GET", "/keys?prefix=key"),
+            (200, json!({ "keys": ["key1", "key2"], "truncated": false }))
+        );
+        assert_eq!(
+            json_request(http, "GET", "/keys?prefix=nothing"),
+            (200, json!({ "keys": [], "truncated": false }))
+        );
+        assert_eq!(
+            json_request(http, "GET", "/keys").1["keys"]
+                .as_array()
+                .unwrap()
+                .len(),
+            4
+        );
+        assert_eq!(
+            json_request(http, "GET", "/keys?prefix=key&limit=1"),
+            (200, json!({ "keys": ["key1"], "truncated": true }))
+        );
+        assert_eq!(
+            json_request(http, "GET", "/keys?limit=2&prefix=key"),
+            (200, json!({ "keys": ["key1", "key2"], "truncated": false }))
+        );
+
+        // binary keys are listed percent-encoded, as they go in a path
+        let (status, body) = json_request(http, "GET", "/keys?prefix=bin");
+        assert_eq!(status, 200);
+        assert_eq!(body["keys"], json!(["bin%FF%2F"]));
+        let listed = body["keys"][0].as_str().unwrap();
+        assert_eq!(
+            request(http, "GET", &format!("/keys/{}", listed), b""),
+            (200, vec![0, 159, 146, 150])
+        );
+        client.set_bytes(b"bin \n".to_vec(), b"value4".to_vec())?;
+        assert_eq!(
+            json_request(http, "GET", "/keys?prefix=bin%20").1["keys"],
+            json!(["bin%20%0A"])
+        );
+
+        assert_eq!(request(http, "DELETE", "/keys/key1", b""), (204, vec![]));
+        assert_eq!(client.get("key1".to_owned())?, None);
+
+        // errors
+        let (status, body) = json_request(http, "GET", "/keys/key1");
+        assert_eq!(status, 404);
+        assert_eq!(body["error"]["code"], "not_found");
+        assert!(body["error"]["message"].as_str().unwrap().contains("key1"));
+
+        let (status, body) = json_request(http, "DELETE", "/keys/key1");
+        assert_eq!(status, 404);
+        assert_eq!(body["error"]["code"], "not_found");
+
+        let (status, body) = json_request(http, "POST", "/keys/key1");
+        assert_eq!(status, 405);
+        assert_eq!(body["error"]["code"], "method_not_allowed");
+
+        for path in ["/keys", "/health"] {
+            let (status, body) = json_request(http, "DELETE", path);
+            assert_eq!(status, 405);
+            assert_eq!(body["error"]["code"], "method_not_allowed");
+        }
+
+        for limit in ["0", "-1", "many"] {
+            let (status, body) = json_request(http, "GET", &format!("/keys?limit={}", limit));
+            assert_eq!(status, 400);
+            assert_eq!(body["error"]["code"], "invalid_limit");
+        }
+
+        let (status, body) = json_request(http, "GET", "/nowhere");
+        assert_eq!(status, 404);
+        assert_eq!(body["error"]["code"], "not_found");
+
+        let (status, body) = json_request(http, "GET", "/keys/");
+        assert_eq!(status, 400);
+        assert_eq!(body["error"]["code"], "invalid_key");
+    }
+
+    Ok(())
+}
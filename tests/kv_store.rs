@@ -1,6 +1,8 @@
-use kvs::{KvStore, KvsEngine, Result};
-use std::sync::{Arc, Barrier};
+use kvs::{KvStore, KvStoreOptions, KvsEngine, Result, SyncPolicy};
+use std::fs;
+use std::sync::{mpsc, Arc, Barrier};
 use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -88,15 +90,13 @@ fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
 
+    // files may be removed by the background compaction while walking
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
-        let len: walkdir::Result<u64> = entries
-            .map(|res| {
-                res.and_then(|entry| entry.metadata())
-                    .map(|metadata| metadata.len())
-            })
-            .sum();
-        len.expect("fail to get directory size")
+        entries
+            .filter_map(|res| res.and_then(|entry| entry.metadata()).ok())
+            .map(|metadata| metadata.len())
+            .sum::<u64>()
     };
 
     let mut current_size = dir_size();
@@ -139,6 +139,11 @@ fn concurrent_set() -> Result<()> {
             store
                 .set(format!("key{}", i), format!("value{}", i))
                 .unwrap();
+            // reads see the write right away, while other threads write
+            assert_eq!(
+                store.get(format!("key{}", i)).unwrap(),
+                Some(format!("value{}", i))
+            );
             barrier.wait();
         });
     }
@@ -209,3 +214,613 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Reads don't wait for each other nor for the writer: every reader makes
+// progress while another thread holds the writer
+#[test]
+fn concurrent_get_scaling() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let held = store.hold_writer();
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || store.set("key0".to_owned(), "new".to_owned()).unwrap())
+    };
+
+    let threads = 8;
+    let barrier = Arc::new(Barrier::new(threads));
+    let (sender, receiver) = mpsc::channel();
+    for thread_id in 0..threads {
+        let (store, barrier, sender) = (store.clone(), barrier.clone(), sender.clone());
+        thread::spawn(move || {
+            barrier.wait();
+            for i in 1..1000 {
+                let key_id = (i * 7 + thread_id) % 999 + 1;
+                assert_eq!(
+                    store.get(format!("key{}", key_id)).unwrap(),
+                    Some(format!("value{}", key_id))
+                );
+            }
+            sender.send(thread_id).unwrap();
+        });
+    }
+    drop(sender);
+    for _ in 0..threads {
+        receiver
+            .recv_timeout(Duration::from_secs(10))
+            .expect("a reader waited for the writer or the other readers");
+    }
+    assert!(!writer.is_finished());
+
+    drop(held);
+    writer.join().unwrap();
+    assert_eq!(store.get("key0".to_owned())?, Some("new".to_owned()));
+
+    Ok(())
+}
+
+// Reads should keep going while a writer rotates and compacts the log
+#[test]
+fn concurrent_get_while_writing() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .segment_size(1024)
+        .compaction_min_bytes(4 * 1024);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || {
+            for i in 0..5000 {
+                store
+                    .set(format!("other{}", i % 50), format!("{}", i))
+                    .unwrap();
+            }
+        })
+    };
+    let readers: Vec<_> = (0..4)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for i in 0..5000 {
+                    let key_id = (i + thread_id) % 100;
+                    assert_eq!(
+                        store.get(format!("key{}", key_id)).unwrap(),
+                        Some(format!("value{}", key_id))
+                    );
+                }
+            })
+        })
+        .collect();
+    writer.join().unwrap();
+    for reader in readers {
+        reader.join().unwrap();
+    }
+
+    assert!(!files_with_extension(temp_dir.path(), "compact", "log").is_empty());
+
+    Ok(())
+}
+
+// Should read logs written in the old line-delimited json format,
+// while new writes go to a binary log
+#[test]
+fn read_legacy_json_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("db.log"),
+        concat!(
+            r#"{"Set":{"key":"key1","value":"value1"}}"#,
+            "\n",
+            r#"{"Set":{"key":"key2","value":"line1\nline2"}}"#,
+            "\n",
+            r#"{"Remove":{"key":"key1"}}"#,
+            "\n",
+        ),
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(
+        store.get("key2".to_owned())?,
+        Some("line1\nline2".to_owned())
+    );
+
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    let active_log = fs::read(temp_dir.path().join("db.log"))?;
+    assert_eq!(&active_log[..4], b"KVSL");
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(
+        store.get("key2".to_owned())?,
+        Some("line1\nline2".to_owned())
+    );
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Should read logs written in the first binary format, whose records have
+// no checksum
+#[test]
+fn read_v1_binary_log() -> Result<()> {
+    // bincode `Entry`: the variant index, then each field length-prefixed
+    fn v1_record(variant: u32, fields: &[&str]) -> Vec<u8> {
+        let mut payload = variant.to_le_bytes().to_vec();
+        for field in fields {
+            payload.extend_from_slice(&(field.len() as u64).to_le_bytes());
+            payload.extend_from_slice(field.as_bytes());
+        }
+        let mut record = (payload.len() as u32).to_be_bytes().to_vec();
+        record.extend_from_slice(&payload);
+        record
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut log = b"KVSL".to_vec();
+    log.extend_from_slice(&1u32.to_be_bytes());
+    log.extend_from_slice(&v1_record(0, &["key1", "value1"]));
+    log.extend_from_slice(&v1_record(0, &["key2", "value2"]));
+    log.extend_from_slice(&v1_record(1, &["key1"]));
+    fs::write(temp_dir.path().join("db.log"), log)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    let active_log = fs::read(temp_dir.path().join("db.log"))?;
+    assert_ne!(&active_log[4..8], &1u32.to_be_bytes());
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Should round-trip values that would break a line-based format
+#[test]
+fn binary_unfriendly_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let large = "x".repeat(64 * 1024);
+    store.set("newline".to_owned(), "a\nb\r\n".to_owned())?;
+    store.set("large".to_owned(), large.clone())?;
+    store.set("nul".to_owned(), "\0\u{7f}\u{1f600}".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get("newline".to_owned())?,
+        Some("a\nb\r\n".to_owned())
+    );
+    assert_eq!(store.get("large".to_owned())?, Some(large));
+    assert_eq!(
+        store.get("nul".to_owned())?,
+        Some("\0\u{7f}\u{1f600}".to_owned())
+    );
+
+    Ok(())
+}
+
+// Should drop a half-written record at the end of the active file
+// instead of refusing to open
+#[test]
+fn recover_torn_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // crash in the middle of writing key2
+    let active_log = temp_dir.path().join("db.log");
+    let len = fs::metadata(&active_log)?.len();
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&active_log)?
+        .set_len(len - 3)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    let report = store.recovery_report().expect("torn write not reported");
+    assert_eq!(report.offset + report.dropped_bytes, len - 3);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.recovery_report().is_none());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Should detect a corrupted record through its checksum
+#[test]
+fn recover_corrupted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // flip a bit in the value of key2
+    let active_log = temp_dir.path().join("db.log");
+    let mut bytes = fs::read(&active_log)?;
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0x01;
+    fs::write(&active_log, &bytes)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.recovery_report().is_some());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Should drop a half-written line at the end of an old json log
+#[test]
+fn recover_torn_legacy_json_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("db.log"),
+        concat!(
+            r#"{"Set":{"key":"key1","value":"value1"}}"#,
+            "\n",
+            r#"{"Set":{"key":"key2","val"#,
+        ),
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.recovery_report().is_some());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+fn files_with_extension(dir: &std::path::Path, prefix: &str, ext: &str) -> Vec<std::path::PathBuf> {
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|p| {
+            p.extension().is_some_and(|e| e == ext)
+                && p.file_name().unwrap().to_string_lossy().starts_with(prefix)
+        })
+        .collect()
+}
+
+// Should write hint files for sealed data files and build the index
+// from them on open
+#[test]
+fn open_from_hint_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("{:0>64}", i))?;
+    }
+    store.remove("key7".to_owned())?;
+    for i in 100..250 {
+        store.set(format!("key{}", i), format!("{:0>64}", i))?;
+    }
+    drop(store);
+
+    let data_files = files_with_extension(temp_dir.path(), "data", "log");
+    assert!(!data_files.is_empty());
+    for data_file in &data_files {
+        assert!(data_file.with_extension("hint").exists());
+    }
+
+    // garbage after the records of a sealed file would fail a full scan,
+    // so the store can only open if the hints are used
+    for data_file in &data_files {
+        let mut bytes = fs::read(data_file)?;
+        bytes.extend_from_slice(b"garbage");
+        fs::write(data_file, bytes)?;
+    }
+
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..250 {
+        let expected = if i == 7 {
+            None
+        } else {
+            Some(format!("{:0>64}", i))
+        };
+        assert_eq!(store.get(format!("key{}", i))?, expected);
+    }
+
+    Ok(())
+}
+
+// The hints of the records written to the active file before the store was
+// opened again are kept for its hint file
+#[test]
+fn hint_files_across_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().segment_size(4096);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for i in 0..20 {
+        store.set(format!("key{}", i), format!("{:0>64}", i))?;
+    }
+    drop(store);
+    assert!(files_with_extension(temp_dir.path(), "data", "log").is_empty());
+
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.remove("key7".to_owned())?;
+    for i in 20..100 {
+        store.set(format!("key{}", i), format!("{:0>64}", i))?;
+    }
+    drop(store);
+
+    // garbage after the records makes the hints the only way to open
+    let data_files = files_with_extension(temp_dir.path(), "data", "log");
+    assert!(!data_files.is_empty());
+    for data_file in &data_files {
+        let mut bytes = fs::read(data_file)?;
+        bytes.extend_from_slice(b"garbage");
+        fs::write(data_file, bytes)?;
+    }
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..100 {
+        let expected = if i == 7 {
+            None
+        } else {
+            Some(format!("{:0>64}", i))
+        };
+        assert_eq!(store.get(format!("key{}", i))?, expected);
+    }
+
+    Ok(())
+}
+
+// Should fall back to scanning a data file if its hint file is unusable
+#[test]
+fn open_with_corrupted_hint_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..200 {
+        store.set(format!("key{}", i), format!("{:0>64}", i))?;
+    }
+    drop(store);
+
+    let hint_files = files_with_extension(temp_dir.path(), "data", "hint");
+    assert!(!hint_files.is_empty());
+    for hint_file in &hint_files {
+        let mut bytes = fs::read(hint_file)?;
+        let len = bytes.len();
+        bytes.truncate(len - 1);
+        fs::write(hint_file, bytes)?;
+    }
+
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..200 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("{:0>64}", i)));
+    }
+
+    Ok(())
+}
+
+// Should keep serving reads and writes while sealed files are merged in
+// the background, without losing any of them
+#[test]
+fn compaction_with_concurrent_access() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "0".to_owned())?;
+    }
+
+    let mut handles = Vec::new();
+    for thread_id in 0..4 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for iter in 1..200 {
+                for key_id in (thread_id..100).step_by(4) {
+                    let key = format!("key{}", key_id);
+                    assert_eq!(
+                        store.get(key.clone()).unwrap(),
+                        Some(format!("{}", iter - 1))
+                    );
+                    store.set(key, format!("{}", iter)).unwrap();
+                }
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    assert!(!files_with_extension(temp_dir.path(), "compact", "log").is_empty());
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("199".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Removed keys should stay removed once their tombstones are compacted away
+#[test]
+fn compaction_drops_removed_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("removed{}", key_id), "value".to_owned())?;
+    }
+    for key_id in 0..100 {
+        store.remove(format!("removed{}", key_id))?;
+    }
+    for iter in 0..100 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+
+    drop(store);
+    assert!(!files_with_extension(temp_dir.path(), "compact", "log").is_empty());
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("removed{}", key_id))?, None);
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("99".to_owned()));
+    }
+
+    Ok(())
+}
+
+// A crash while compaction removes the merged files should neither bring
+// a removed key back nor leave the previous compact file behind
+#[test]
+fn crash_during_compaction() -> Result<()> {
+    let options = KvStoreOptions::new()
+        .segment_size(1024)
+        .compaction_min_bytes(u64::MAX);
+    for removed in 0..4 {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+        store.set("removed".to_owned(), "value".to_owned())?;
+        store.set("kept".to_owned(), "old".to_owned())?;
+        for i in 0..30 {
+            store.set(format!("key{}", i), format!("{:0>64}", i))?;
+        }
+        store.remove("removed".to_owned())?;
+        store.set("kept".to_owned(), "new".to_owned())?;
+        for i in 30..60 {
+            store.set(format!("key{}", i), format!("{:0>64}", i))?;
+        }
+        assert!(files_with_extension(temp_dir.path(), "data", "log").len() > removed);
+        store.simulate_crash_in_compaction(removed)?;
+
+        // the next compaction crashes before removing any file
+        let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+        assert_eq!(store.get("removed".to_owned())?, None);
+        assert_eq!(store.get("kept".to_owned())?, Some("new".to_owned()));
+        store.simulate_crash_in_compaction(0)?;
+        assert_eq!(
+            files_with_extension(temp_dir.path(), "compact", "log").len(),
+            2
+        );
+
+        let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+        assert_eq!(
+            files_with_extension(temp_dir.path(), "compact", "log").len(),
+            1
+        );
+        assert_eq!(
+            files_with_extension(temp_dir.path(), "compact", "hint").len(),
+            1
+        );
+        assert_eq!(store.get("removed".to_owned())?, None);
+        assert_eq!(store.get("kept".to_owned())?, Some("new".to_owned()));
+        for i in 0..60 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("{:0>64}", i)));
+        }
+    }
+
+    Ok(())
+}
+
+// Files sealed faster than one per millisecond are stamped ahead of the
+// clock, the files sealed later must still load after them
+#[test]
+fn file_order_with_stamps_ahead_of_clock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .segment_size(1024)
+        .compaction_min_bytes(u64::MAX);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for i in 0..30 {
+        store.set("key".to_owned(), format!("{:0>64}", i))?;
+    }
+    drop(store);
+
+    // as left by a burst of an hour of files
+    for path in fs::read_dir(temp_dir.path())? {
+        let path = path?.path();
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        if let Some(rest) = name.strip_prefix("data-") {
+            let (stamp, ext) = rest.split_once('.').unwrap();
+            let stamp: u64 = stamp.parse().unwrap();
+            let ahead = format!("data-{}.{}", stamp + 3_600_000, ext);
+            fs::rename(&path, temp_dir.path().join(ahead))?;
+        }
+    }
+
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.set("key".to_owned(), "new".to_owned())?;
+    for i in 0..30 {
+        store.set(format!("key{}", i), format!("{:0>64}", i))?;
+    }
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key".to_owned())?, Some("new".to_owned()));
+    Ok(())
+}
+
+// Should rotate the active file at the configured segment size
+#[test]
+fn open_with_options() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .segment_size(1024 * 1024)
+        .compaction_min_bytes(1024 * 1024)
+        .read_buffer_size(512)
+        .write_buffer_size(512)
+        .sync_policy(SyncPolicy::Always);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i), format!("{:0>64}", i))?;
+    }
+    assert!(files_with_extension(temp_dir.path(), "data", "log").is_empty());
+
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options.segment_size(1024))?;
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("{:0>64}", i)));
+    }
+    store.set("key".to_owned(), "value".to_owned())?;
+    assert!(!files_with_extension(temp_dir.path(), "data", "log").is_empty());
+
+    Ok(())
+}
+
+// Should only create a missing directory when asked to
+#[test]
+fn open_with_create_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("nested").join("store");
+
+    assert!(KvStore::open(&path).is_err());
+
+    let store = KvStore::open_with(&path, KvStoreOptions::new().create_dir(true))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(&path)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
@@ -0,0 +1,159 @@
+use kvs::protocol::{self, ErrorCode, Request, Response, MAGIC, MAX_FRAME_LEN};
+use kvs::Result;
+use std::io::{BufReader, Read, Write};
+use std::net::TcpStream;
+use tempfile::TempDir;
+
+mod common;
+
+use common::start_server;
+
+/// Raw handshake offering versions `min` to `max`, returns the version
+/// picked by the server
+fn raw_handshake(stream: &mut TcpStream, min: u32, max: u32) -> u32 {
+    stream.write_all(&MAGIC).unwrap();
+    stream.write_all(&min.to_be_bytes()).unwrap();
+    stream.write_all(&max.to_be_bytes()).unwrap();
+
+    let mut reply = [0; 8];
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(reply[..4], MAGIC);
+    u32::from_be_bytes([reply[4], reply[5], reply[6], reply[7]])
+}
+
+fn connect(addr: &str) -> TcpStream {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    assert_eq!(raw_handshake(&mut stream, 1, 1), 1);
+    stream
+}
+
+#[test]
+fn protocol_version_negotiation() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = start_server(&temp_dir, &["--engine", "kvs"]);
+    let addr = server.addr();
+
+    // the highest version in common
+    let mut stream = TcpStream::connect(addr).unwrap();
+    assert_eq!(raw_handshake(&mut stream, 1, 7), protocol::VERSION);
+
+    // no version in common, the server hangs up
+    let mut stream = TcpStream::connect(addr).unwrap();
+    assert_eq!(raw_handshake(&mut stream, 5, 7), 0);
+    assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+
+    // not the protocol at all
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+    assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+}
+
+#[test]
+fn protocol_request_ids_and_error_codes() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let server = start_server(&temp_dir, &["--engine", "kvs"]);
+    let addr = server.addr();
+
+    let mut stream = connect(addr);
+    let set = Request::Set {
+        key: b"key1".to_vec(),
+        value: b"value1".to_vec(),
+    };
+    protocol::write_request(&mut stream, 42, &set)?;
+    // unknown opcode
+    protocol::write_frame(&mut stream, &[&7_u64.to_be_bytes()[..], b"z\x00"].concat())?;
+    // truncated get
+    protocol::write_frame(
+        &mut stream,
+        &[&8_u64.to_be_bytes()[..], b"g\x00\x00"].concat(),
+    )?;
+    // frame without an id
+    protocol::write_frame(&mut stream, b"g")?;
+    protocol::write_request(
+        &mut stream,
+        9,
+        &Request::Remove {
+            key: b"key2".to_vec(),
+        },
+    )?;
+    protocol::write_request(
+        &mut stream,
+        10,
+        &Request::Get {
+            key: b"key1".to_vec(),
+        },
+    )?;
+
+    let mut reader = BufReader::new(stream.try_clone()?);
+    assert_eq!(protocol::read_reply(&mut reader)?, (42, Ok(Response::Done)));
+    let (id, reply) = protocol::read_reply(&mut reader)?;
+    assert_eq!((id, reply.unwrap_err().code), (7, ErrorCode::Unsupported));
+    let (id, reply) = protocol::read_reply(&mut reader)?;
+    assert_eq!(
+        (id, reply.unwrap_err().code),
+        (8, ErrorCode::InvalidRequest)
+    );
+    let (id, reply) = protocol::read_reply(&mut reader)?;
+    assert_eq!(
+        (id, reply.unwrap_err().code),
+        (0, ErrorCode::InvalidRequest)
+    );
+    let (id, reply) = protocol::read_reply(&mut reader)?;
+    assert_eq!((id, reply.unwrap_err().code), (9, ErrorCode::NotFound));
+    assert_eq!(
+        protocol::read_reply(&mut reader)?,
+        (10, Ok(Response::Value(Some(b"value1".to_vec()))))
+    );
+
+    Ok(())
+}
+
+#[test]
+fn protocol_too_large_request() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let server = start_server(&temp_dir, &["--engine", "kvs"]);
+    let addr = server.addr();
+
+    let mut stream = connect(addr);
+    let len = MAX_FRAME_LEN + 1;
+    stream.write_all(&len.to_be_bytes())?;
+    stream.write_all(&3_u64.to_be_bytes())?;
+    stream.write_all(&vec![0; (len - 8) as usize])?;
+    protocol::write_request(
+        &mut stream,
+        4,
+        &Request::Get {
+            key: b"key1".to_vec(),
+        },
+    )?;
+
+    // the oversized frame is skipped, the next request is served
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let (id, reply) = protocol::read_reply(&mut reader)?;
+    assert_eq!((id, reply.unwrap_err().code), (3, ErrorCode::TooLarge));
+    assert_eq!(
+        protocol::read_reply(&mut reader)?,
+        (4, Ok(Response::Value(None)))
+    );
+
+    Ok(())
+}
+
+// A reply over the frame limit is replaced by an error
+#[test]
+fn protocol_too_large_reply() -> Result<()> {
+    let value = vec![0; MAX_FRAME_LEN as usize];
+    let pairs = vec![(b"key1".to_vec(), value.clone()), (b"key2".to_vec(), value)];
+    let mut buf = Vec::new();
+    protocol::write_reply(&mut buf, 3, &Ok(Response::Pairs(pairs)))?;
+    protocol::write_reply(&mut buf, 4, &Ok(Response::Value(None)))?;
+
+    let mut reader = buf.as_slice();
+    let (id, reply) = protocol::read_reply(&mut reader)?;
+    assert_eq!((id, reply.unwrap_err().code), (3, ErrorCode::TooLarge));
+    assert_eq!(
+        protocol::read_reply(&mut reader)?,
+        (4, Ok(Response::Value(None)))
+    );
+    Ok(())
+}
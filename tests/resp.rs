@@ -0,0 +1,199 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+mod common;
+
+use common::Server;
+
+fn start_server(temp_dir: &TempDir, engine: &str) -> Server {
+    common::start_server(temp_dir, &["--engine", engine, "--protocol", "resp"])
+}
+
+/// Raw RESP array of bulk strings
+fn command(args: &[&[u8]]) -> Vec<u8> {
+    let mut frame = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        frame.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        frame.extend_from_slice(arg);
+        frame.extend_from_slice(b"\r\n");
+    }
+    frame
+}
+
+/// Send a command and check the raw reply.
+fn expect(stream: &mut BufReader<TcpStream>, args: &[&[u8]], reply: &[u8]) {
+    stream.get_mut().write_all(&command(args)).unwrap();
+    read_reply(stream, reply);
+}
+
+fn read_reply(stream: &mut BufReader<TcpStream>, reply: &[u8]) {
+    let mut buf = vec![0; reply.len()];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&buf),
+        String::from_utf8_lossy(reply)
+    );
+}
+
+fn connect(addr: &str) -> BufReader<TcpStream> {
+    BufReader::new(TcpStream::connect(addr).unwrap())
+}
+
+#[test]
+fn resp_get_set_del_exists() {
+    for engine in ["kvs", "sled"] {
+        let temp_dir = TempDir::new().unwrap();
+        let server = start_server(&temp_dir, engine);
+        let addr = server.addr();
+        let mut conn = connect(addr);
+
+        expect(&mut conn, &[b"GET", b"key1"], b"$-1\r\n");
+        expect(&mut conn, &[b"SET", b"key1", b"value1"], b"+OK\r\n");
+        expect(&mut conn, &[b"get", b"key1"], b"$6\r\nvalue1\r\n");
+
+        // binary safe, CRLF included
+        expect(&mut conn, &[b"SET", b"bin", b"a\r\n\0\xff"], b"+OK\r\n");
+        expect(&mut conn, &[b"GET", b"bin"], b"$5\r\na\r\n\0\xff\r\n");
+
+        expect(
+            &mut conn,
+            &[b"EXISTS", b"key1", b"key2", b"key1"],
+            b":2\r\n",
+        );
+        expect(&mut conn, &[b"DEL", b"key1", b"key2", b"bin"], b":2\r\n");
+        expect(&mut conn, &[b"EXISTS", b"key1"], b":0\r\n");
+        expect(&mut conn, &[b"DEL", b"key1"], b":0\r\n");
+
+        // inline commands and pipelining
+        conn.get_mut()
+            .write_all(b"SET key3 value3\r\nGET key3\r\n\r\nPING\r\n")
+            .unwrap();
+        read_reply(&mut conn, b"+OK\r\n$6\r\nvalue3\r\n+PONG\r\n");
+
+        // values outlive the connection
+        drop(conn);
+        let mut conn = connect(addr);
+        expect(&mut conn, &[b"GET", b"key3"], b"$6\r\nvalue3\r\n");
+    }
+}
+
+#[test]
+fn resp_set_options_and_errors() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = start_server(&temp_dir, "kvs");
+    let addr = server.addr();
+    let mut conn = connect(addr);
+
+    expect(&mut conn, &[b"SET", b"lock", b"a", b"NX"], b"+OK\r\n");
+    expect(&mut conn, &[b"SET", b"lock", b"b", b"NX"], b"$-1\r\n");
+    expect(&mut conn, &[b"GET", b"lock"], b"$1\r\na\r\n");
+
+    expect(&mut conn, &[b"SET", b"ttl", b"v", b"EX", b"1"], b"+OK\r\n");
+    expect(
+        &mut conn,
+        &[b"SET", b"ttl2", b"v", b"nx", b"px", b"200"],
+        b"+OK\r\n",
+    );
+    expect(&mut conn, &[b"EXISTS", b"ttl", b"ttl2"], b":2\r\n");
+    thread::sleep(Duration::from_millis(1500));
+    expect(&mut conn, &[b"GET", b"ttl"], b"$-1\r\n");
+    expect(&mut conn, &[b"EXISTS", b"ttl", b"ttl2"], b":0\r\n");
+
+    expect(
+        &mut conn,
+        &[b"SET", b"k", b"v", b"EX", b"0"],
+        b"-ERR invalid expire time in 'set' command\r\n",
+    );
+    expect(
+        &mut conn,
+        &[b"SET", b"k", b"v", b"EX", b"soon"],
+        b"-ERR value is not an integer or out of range\r\n",
+    );
+    expect(
+        &mut conn,
+        &[b"SET", b"k", b"v", b"XY"],
+        b"-ERR syntax error\r\n",
+    );
+    expect(
+        &mut conn,
+        &[b"GET"],
+        b"-ERR wrong number of arguments for 'get' command\r\n",
+    );
+    expect(
+        &mut conn,
+        &[b"FLUSHALL"],
+        b"-ERR unknown command 'flushall'\r\n",
+    );
+
+    // the connection is still usable after errors
+    expect(&mut conn, &[b"PING", b"hello"], b"$5\r\nhello\r\n");
+
+    // but not after a malformed frame
+    conn.get_mut().write_all(b"*1\r\n+PING\r\n").unwrap();
+    let mut line = String::new();
+    conn.read_line(&mut line).unwrap();
+    assert!(line.starts_with("-ERR Protocol error"), "{}", line);
+    assert_eq!(conn.read(&mut [0; 16]).unwrap(), 0);
+}
+
+#[test]
+fn resp_scan_info_quit() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = start_server(&temp_dir, "kvs");
+    let addr = server.addr();
+    let mut conn = connect(addr);
+
+    for key in ["a1", "a2", "a3", "b1", "b2"] {
+        expect(&mut conn, &[b"SET", key.as_bytes(), b"v"], b"+OK\r\n");
+    }
+
+    // pages of COUNT keys, the cursor is the last key in hex, 0 on the last
+    // page. A key removed meanwhile doesn't make the next page skip any.
+    expect(
+        &mut conn,
+        &[b"SCAN", b"0", b"COUNT", b"2"],
+        b"*2\r\n$4\r\n6132\r\n*2\r\n$2\r\na1\r\n$2\r\na2\r\n",
+    );
+    expect(&mut conn, &[b"DEL", b"a1"], b":1\r\n");
+    expect(
+        &mut conn,
+        &[b"SCAN", b"6132", b"COUNT", b"2"],
+        b"*2\r\n$4\r\n6231\r\n*2\r\n$2\r\na3\r\n$2\r\nb1\r\n",
+    );
+    expect(
+        &mut conn,
+        &[b"SCAN", b"6231", b"COUNT", b"2"],
+        b"*2\r\n$1\r\n0\r\n*1\r\n$2\r\nb2\r\n",
+    );
+    expect(&mut conn, &[b"SET", b"a1", b"v"], b"+OK\r\n");
+    expect(
+        &mut conn,
+        &[b"SCAN", b"0", b"MATCH", b"*[2-3]"],
+        b"*2\r\n$1\r\n0\r\n*3\r\n$2\r\na2\r\n$2\r\na3\r\n$2\r\nb2\r\n",
+    );
+    expect(
+        &mut conn,
+        &[b"SCAN", b"0", b"MATCH", b"b?"],
+        b"*2\r\n$1\r\n0\r\n*2\r\n$2\r\nb1\r\n$2\r\nb2\r\n",
+    );
+    expect(&mut conn, &[b"SCAN", b"x"], b"-ERR invalid cursor\r\n");
+    expect(&mut conn, &[b"SCAN", b"613"], b"-ERR invalid cursor\r\n");
+
+    expect(&mut conn, &[b"PING"], b"+PONG\r\n");
+
+    conn.get_mut().write_all(&command(&[b"INFO"])).unwrap();
+    let mut header = String::new();
+    conn.read_line(&mut header).unwrap();
+    let len: usize = header.trim_end()[1..].parse().unwrap();
+    let mut info = vec![0; len + 2];
+    conn.read_exact(&mut info).unwrap();
+    let info = String::from_utf8(info).unwrap();
+    assert!(info.starts_with("# Server\r\n"));
+    assert!(info.contains(&format!("kvs_version:{}\r\n", env!("CARGO_PKG_VERSION"))));
+
+    expect(&mut conn, &[b"QUIT"], b"+OK\r\n");
+    assert_eq!(conn.read(&mut [0; 16]).unwrap(), 0);
+}
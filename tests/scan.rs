@@ -0,0 +1,151 @@
+use kvs::{KvStore, KvStoreOptions, KvsEngine, Result, SledEngine};
+use std::ops::Bound;
+use tempfile::TempDir;
+
+fn keys(scan: kvs::Scan) -> Result<Vec<String>> {
+    scan.map(|item| item.map(|(key, _)| key)).collect()
+}
+
+fn check_scan<E: KvsEngine>(engine: E) -> Result<()> {
+    for key in [
+        "b",
+        "a",
+        "user:42:name",
+        "user:42:age",
+        "user:420:name",
+        "c",
+    ] {
+        engine.set(key.to_owned(), format!("{}-value", key))?;
+    }
+    engine.remove("c".to_owned())?;
+
+    let pairs: Vec<(String, String)> = engine
+        .scan("a".to_owned()..="b".to_owned())?
+        .collect::<Result<_>>()?;
+    assert_eq!(
+        pairs,
+        vec![
+            ("a".to_owned(), "a-value".to_owned()),
+            ("b".to_owned(), "b-value".to_owned()),
+        ]
+    );
+
+    assert_eq!(
+        keys(engine.scan(..)?)?,
+        vec!["a", "b", "user:420:name", "user:42:age", "user:42:name"]
+    );
+    assert_eq!(
+        keys(engine.scan("b".to_owned().."user".to_owned())?)?,
+        vec!["b"]
+    );
+    assert_eq!(
+        keys(engine.scan((Bound::Excluded("b".to_owned()), Bound::Unbounded))?)?,
+        vec!["user:420:name", "user:42:age", "user:42:name"]
+    );
+    assert_eq!(
+        keys(engine.scan_prefix("user:42:".to_owned())?)?,
+        vec!["user:42:age", "user:42:name"]
+    );
+    assert!(keys(engine.scan_prefix("x".to_owned())?)?.is_empty());
+    assert_eq!(
+        engine
+            .scan_prefix_keys(b"user:42".to_vec())?
+            .collect::<Result<Vec<_>>>()?,
+        vec![
+            b"user:420:name".to_vec(),
+            b"user:42:age".to_vec(),
+            b"user:42:name".to_vec()
+        ]
+    );
+    assert_eq!(engine.scan_prefix_keys(b"c".to_vec())?.count(), 0);
+    assert_eq!(
+        engine
+            .scan_keys(b"b".to_vec()..=b"user:42:age".to_vec())?
+            .collect::<Result<Vec<_>>>()?,
+        vec![
+            b"b".to_vec(),
+            b"user:420:name".to_vec(),
+            b"user:42:age".to_vec()
+        ]
+    );
+
+    // inverted ranges are empty
+    assert!(keys(engine.scan("b".to_owned().."a".to_owned())?)?.is_empty());
+    assert_eq!(engine.scan_keys(b"b".to_vec()..b"a".to_vec())?.count(), 0);
+
+    Ok(())
+}
+
+#[test]
+fn scan_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_scan(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn scan_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_scan(SledEngine::open(temp_dir.path())?)
+}
+
+// Keys are read from the index as the scan goes, writers go on meanwhile
+// and the keys written ahead of the scan are seen
+#[test]
+fn scan_reads_keys_lazily() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..1000 {
+        store.set(format!("key{:04}", i), "value".to_owned())?;
+    }
+
+    let mut scan = store.scan_keys(b"key0500".to_vec()..=b"key1000".to_vec())?;
+    assert_eq!(scan.next().transpose()?, Some(b"key0500".to_vec()));
+    store.set("key1000".to_owned(), "value".to_owned())?;
+    store.remove("key0999".to_owned())?;
+    let rest = scan.collect::<Result<Vec<_>>>()?;
+    assert_eq!(rest.len(), 499);
+    assert_eq!(rest[0], b"key0501".to_vec());
+    assert_eq!(rest.last(), Some(&b"key1000".to_vec()));
+    assert!(rest.windows(2).all(|keys| keys[0] < keys[1]));
+
+    let mut scan = store.scan(..)?;
+    assert_eq!(
+        scan.next().transpose()?.map(|(key, _)| key),
+        Some("key0000".to_owned())
+    );
+    store.set("key2000".to_owned(), "value".to_owned())?;
+    assert_eq!(
+        scan.last().transpose()?.map(|(key, _)| key),
+        Some("key2000".to_owned())
+    );
+    Ok(())
+}
+
+// The ordered index is rebuilt when the store is opened again, and survives
+// compactions
+#[test]
+fn scan_after_reopen_and_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().segment_size(1024);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for iter in 0..100 {
+        for key_id in 0..20 {
+            store.set(format!("key{:02}", key_id), format!("{}", iter))?;
+        }
+    }
+    for key_id in 10..20 {
+        store.remove(format!("key{:02}", key_id))?;
+    }
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    let pairs: Vec<(String, String)> = store
+        .scan_prefix("key".to_owned())?
+        .collect::<Result<_>>()?;
+    let expected: Vec<(String, String)> = (0..10)
+        .map(|key_id| (format!("key{:02}", key_id), "99".to_owned()))
+        .collect();
+    assert_eq!(pairs, expected);
+
+    Ok(())
+}
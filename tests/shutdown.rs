@@ -0,0 +1,98 @@
+use kvs::client::{Connection, KvsClient};
+use kvs::protocol::Request;
+use kvs::{KvStore, KvsEngine, Result, SledEngine};
+use std::io::Read;
+use std::net::TcpStream;
+use std::process::Stdio;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+mod common;
+
+use common::{free_addr, kvs_server, wait_for_server};
+
+/// Start a server, write through it and stop it with `signal` while a
+/// connection is still open, returns the stderr of the server.
+fn write_and_stop(temp_dir: &TempDir, engine: &str, signal: i32) -> Result<String> {
+    let addr = free_addr();
+    let addr = addr.as_str();
+    let mut child = kvs_server(temp_dir, addr)
+        .args(["--engine", engine])
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    wait_for_server(addr);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    for i in 0..100 {
+        client
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+    let mut idle = Connection::connect(addr).unwrap();
+
+    assert_eq!(unsafe { libc::kill(child.id() as i32, signal) }, 0);
+
+    // the idle connection doesn't hold the server up to the drain timeout
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        if start.elapsed() > Duration::from_secs(5) {
+            child.kill().unwrap();
+            child.wait().unwrap();
+            panic!("server still running 5s after the signal");
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+    assert!(status.success());
+
+    // connections are closed and no new ones are accepted
+    idle.send(&Request::Get {
+        key: b"key1".to_vec(),
+    })?;
+    assert!(idle.recv().is_err());
+    assert!(TcpStream::connect(addr).is_err());
+
+    let mut stderr = String::new();
+    child.stderr.take().unwrap().read_to_string(&mut stderr)?;
+    Ok(stderr)
+}
+
+fn check_stderr(stderr: &str) {
+    assert!(stderr.contains("shutting down"), "{}", stderr);
+    assert!(
+        stderr.contains("stopped accepting connections"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("synced storage engine"), "{}", stderr);
+}
+
+#[test]
+fn kvs_server_sigterm() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let stderr = write_and_stop(&temp_dir, "kvs", libc::SIGTERM)?;
+    check_stderr(&stderr);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+#[test]
+fn kvs_server_sigint_sled() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let stderr = write_and_stop(&temp_dir, "sled", libc::SIGINT)?;
+    check_stderr(&stderr);
+
+    let store = SledEngine::open(temp_dir.path())?;
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
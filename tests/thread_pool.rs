@@ -1,9 +1,11 @@
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Barrier};
+use std::time::{Duration, Instant};
 
 use kvs::thread_pool::*;
-use kvs::Result;
+use kvs::{KvsEngine, Result};
 
+use crossbeam::channel::{bounded, Sender};
 use crossbeam_utils::sync::WaitGroup;
 
 fn spawn_counter<P: ThreadPool>(pool: P) -> Result<()> {
@@ -58,13 +60,400 @@ fn shared_queue_thread_pool_spawn_counter() -> Result<()> {
     spawn_counter(pool)
 }
 
-// #[test]
-// fn rayon_thread_pool_spawn_counter() -> Result<()> {
-//     let pool = RayonThreadPool::new(4)?;
-//     spawn_counter(pool)
-// }
+#[test]
+fn work_stealing_thread_pool_spawn_counter() -> Result<()> {
+    let pool = WorkStealingThreadPool::new(4)?;
+    spawn_counter(pool)
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn rayon_thread_pool_spawn_counter() -> Result<()> {
+    let pool = RayonThreadPool::new(4)?;
+    spawn_counter(pool)
+}
 
 #[test]
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
 }
+
+#[test]
+fn work_stealing_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<WorkStealingThreadPool>()
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn rayon_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<RayonThreadPool>()
+}
+
+#[test]
+fn work_stealing_thread_pool_nested_spawn() -> Result<()> {
+    const TASK_NUM: usize = 20;
+    const NESTED_NUM: usize = 50;
+
+    // jobs spawned by jobs go to the deque of their thread, stolen by the
+    // idle threads
+    let pool = Arc::new(WorkStealingThreadPool::new(4)?);
+    let wg = WaitGroup::new();
+    let counter = Arc::new(AtomicUsize::new(0));
+    for _ in 0..TASK_NUM {
+        let inner = Arc::clone(&pool);
+        let counter = Arc::clone(&counter);
+        let wg = wg.clone();
+        pool.spawn(move || {
+            for _ in 0..NESTED_NUM {
+                let counter = Arc::clone(&counter);
+                let wg = wg.clone();
+                inner.spawn(move || {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    drop(wg);
+                });
+            }
+        })
+    }
+
+    wg.wait();
+    assert_eq!(counter.load(Ordering::SeqCst), TASK_NUM * NESTED_NUM);
+    Ok(())
+}
+
+fn shutdown_waits_for_jobs<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(2)?;
+    let counter = Arc::new(AtomicUsize::new(0));
+    for _ in 0..10 {
+        let counter = Arc::clone(&counter);
+        pool.spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            counter.fetch_add(1, Ordering::SeqCst);
+        })
+    }
+
+    pool.shutdown();
+    assert_eq!(counter.load(Ordering::SeqCst), 10);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_shutdown_waits_for_jobs() -> Result<()> {
+    shutdown_waits_for_jobs::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn work_stealing_thread_pool_shutdown_waits_for_jobs() -> Result<()> {
+    shutdown_waits_for_jobs::<WorkStealingThreadPool>()
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn rayon_thread_pool_shutdown_waits_for_jobs() -> Result<()> {
+    shutdown_waits_for_jobs::<RayonThreadPool>()
+}
+
+/// Pool of one thread busy until the returned sender is dropped
+fn busy_pool(options: PoolOptions) -> Result<(SharedQueueThreadPool, Sender<()>)> {
+    let pool = SharedQueueThreadPool::with_options(1, options)?;
+    let (started_tx, started_rx) = bounded(0);
+    let (release_tx, release_rx) = bounded::<()>(0);
+    pool.spawn(move || {
+        started_tx.send(()).unwrap();
+        let _ = release_rx.recv();
+    });
+    started_rx.recv().unwrap();
+    Ok((pool, release_tx))
+}
+
+/// Job adding `n` to `done` once run
+fn add(done: &Arc<AtomicUsize>, n: usize) -> impl FnOnce() + Send + 'static {
+    let done = Arc::clone(done);
+    move || {
+        done.fetch_add(n, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn shared_queue_thread_pool_bounded_block() -> Result<()> {
+    let options = PoolOptions::new().capacity(1);
+    let (pool, release) = busy_pool(options)?;
+    let done = Arc::new(AtomicUsize::new(0));
+
+    pool.spawn(add(&done, 1));
+    assert_eq!(pool.try_spawn(add(&done, 10)), Err(Rejected));
+
+    // spawn waits for the busy thread to take the queued job
+    let releaser = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        drop(release);
+    });
+    pool.spawn(add(&done, 100));
+    releaser.join().unwrap();
+
+    pool.shutdown();
+    assert_eq!(done.load(Ordering::SeqCst), 101);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_bounded_reject() -> Result<()> {
+    let options = PoolOptions::new().capacity(2).policy(QueuePolicy::Reject);
+    let (pool, release) = busy_pool(options)?;
+    let done = Arc::new(AtomicUsize::new(0));
+
+    assert_eq!(pool.try_spawn(add(&done, 1)), Ok(()));
+    pool.spawn(add(&done, 10));
+    assert_eq!(pool.try_spawn(add(&done, 100)), Err(Rejected));
+    pool.spawn(add(&done, 1000));
+
+    drop(release);
+    pool.shutdown();
+    assert_eq!(done.load(Ordering::SeqCst), 11);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_bounded_drop_oldest() -> Result<()> {
+    let options = PoolOptions::new()
+        .capacity(2)
+        .policy(QueuePolicy::DropOldest);
+    let (pool, release) = busy_pool(options)?;
+    let done = Arc::new(AtomicUsize::new(0));
+
+    pool.spawn(add(&done, 1));
+    pool.spawn(add(&done, 10));
+    pool.spawn(add(&done, 100));
+    assert_eq!(pool.try_spawn(add(&done, 1000)), Ok(()));
+
+    drop(release);
+    pool.shutdown();
+    assert_eq!(done.load(Ordering::SeqCst), 1100);
+    Ok(())
+}
+
+#[test]
+fn unbounded_thread_pools_try_spawn() -> Result<()> {
+    let done = Arc::new(AtomicUsize::new(0));
+    let pool = SharedQueueThreadPool::new(2)?;
+    assert_eq!(pool.try_spawn(add(&done, 1)), Ok(()));
+    pool.shutdown();
+    let pool = WorkStealingThreadPool::new(2)?;
+    assert_eq!(pool.try_spawn(add(&done, 10)), Ok(()));
+    pool.shutdown();
+
+    assert_eq!(done.load(Ordering::SeqCst), 11);
+    Ok(())
+}
+
+fn spawn_with_handle<P: ThreadPool>(pool: P) -> Result<()> {
+    let handles: Vec<_> = (0..100u64)
+        .map(|i| pool.spawn_with_handle(move || i * 2))
+        .collect();
+    for (i, handle) in handles.into_iter().enumerate() {
+        assert_eq!(handle.join(), Ok(i as u64 * 2));
+    }
+
+    let handle = pool.spawn_with_handle(|| {
+        panic_control::disable_hook_in_current_thread();
+        panic!("no luck");
+    });
+    assert_eq!(
+        handle.join(),
+        Err::<(), _>(JoinError::Panicked("no luck".to_owned()))
+    );
+    let handle = pool.spawn_with_handle(move || {
+        panic_control::disable_hook_in_current_thread();
+        panic!("no luck {}", 2);
+    });
+    assert_eq!(
+        handle.join(),
+        Err::<(), _>(JoinError::Panicked("no luck 2".to_owned()))
+    );
+
+    // the pool still runs jobs
+    assert_eq!(pool.spawn_with_handle(|| "done").join(), Ok("done"));
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_spawn_with_handle() -> Result<()> {
+    spawn_with_handle(SharedQueueThreadPool::new(4)?)
+}
+
+#[test]
+fn work_stealing_thread_pool_spawn_with_handle() -> Result<()> {
+    spawn_with_handle(WorkStealingThreadPool::new(4)?)
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn rayon_thread_pool_spawn_with_handle() -> Result<()> {
+    spawn_with_handle(RayonThreadPool::new(4)?)
+}
+
+#[test]
+fn job_handle_try_join() -> Result<()> {
+    let options = PoolOptions::new().capacity(1).policy(QueuePolicy::Reject);
+    let (pool, release) = busy_pool(options)?;
+
+    let mut handle = pool.spawn_with_handle(|| 42);
+    for _ in 0..10 {
+        handle = handle.try_join().unwrap_err();
+    }
+
+    // rejected by the full queue
+    let rejected = pool.spawn_with_handle(|| 43);
+    assert_eq!(rejected.join(), Err(JoinError::Cancelled));
+
+    drop(release);
+    let result = loop {
+        match handle.try_join() {
+            Ok(result) => break result,
+            Err(pending) => handle = pending,
+        }
+        std::thread::yield_now();
+    };
+    assert_eq!(result, Ok(42));
+    Ok(())
+}
+
+#[test]
+fn spawn_with_handle_against_a_store() -> Result<()> {
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    let store = kvs::KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(4)?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|i| {
+            let store = store.clone();
+            pool.spawn_with_handle(move || -> Result<usize> {
+                for j in 0..100 {
+                    store.set(format!("key{}-{}", i, j), "value".to_owned())?;
+                }
+                Ok(100)
+            })
+        })
+        .collect();
+    let mut written = 0;
+    for handle in handles {
+        written += handle.join().unwrap()?;
+    }
+
+    assert_eq!(written, 800);
+    assert_eq!(store.scan_prefix("key".to_owned())?.count(), 800);
+    Ok(())
+}
+
+/// Wait up to a second for `pool` to have `threads` threads.
+fn wait_for_threads(pool: &SharedQueueThreadPool, threads: u32) {
+    let start = Instant::now();
+    while pool.threads() != threads {
+        assert!(
+            start.elapsed() < Duration::from_secs(1),
+            "{} threads instead of {}",
+            pool.threads(),
+            threads
+        );
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn shared_queue_thread_pool_grows_and_reaps_idle_threads() -> Result<()> {
+    let options = PoolOptions::new()
+        .max_threads(4)
+        .idle_timeout(Duration::from_millis(200));
+    let pool = SharedQueueThreadPool::with_options(1, options)?;
+    assert_eq!(pool.threads(), 1);
+
+    // the jobs only finish once all of them run at the same time
+    let barrier = Arc::new(Barrier::new(5));
+    for _ in 0..4 {
+        let barrier = Arc::clone(&barrier);
+        pool.spawn(move || {
+            barrier.wait();
+        });
+    }
+    barrier.wait();
+    assert_eq!(pool.threads(), 4);
+
+    // never more than the maximum
+    let wg = WaitGroup::new();
+    for _ in 0..100 {
+        let wg = wg.clone();
+        pool.spawn(move || drop(wg));
+    }
+    wg.wait();
+    assert!(pool.threads() <= 4);
+
+    std::thread::sleep(Duration::from_millis(300));
+    wait_for_threads(&pool, 1);
+    spawn_counter(pool)
+}
+
+#[test]
+fn shared_queue_thread_pool_resize() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(2)?;
+    assert_eq!(pool.threads(), 2);
+
+    pool.resize(6, 8)?;
+    assert_eq!(pool.threads(), 6);
+
+    pool.resize(1, 3)?;
+    wait_for_threads(&pool, 3);
+
+    pool.resize(1, 1)?;
+    wait_for_threads(&pool, 1);
+
+    assert!(pool.resize(3, 2).is_err());
+    assert!(SharedQueueThreadPool::with_options(3, PoolOptions::new().max_threads(2)).is_err());
+    spawn_counter(pool)
+}
+
+#[test]
+fn shared_queue_thread_pool_metrics() -> Result<()> {
+    let (pool, release) = busy_pool(PoolOptions::new())?;
+    let monitor = pool.monitor();
+    let done = Arc::new(AtomicUsize::new(0));
+    for _ in 0..3 {
+        pool.spawn(add(&done, 1));
+    }
+    pool.spawn(|| {
+        panic_control::disable_hook_in_current_thread();
+        panic!();
+    });
+
+    let metrics = pool.metrics().unwrap();
+    assert_eq!(metrics.threads, 1);
+    assert_eq!(metrics.queue_depth, 4);
+    assert_eq!(metrics.active_workers, 1);
+    assert_eq!(metrics.jobs_completed, 0);
+    assert_eq!(metrics.run_latency.count(), 0);
+    assert_eq!(metrics.run_latency.quantile(0.5), None);
+
+    std::thread::sleep(Duration::from_millis(50));
+    drop(release);
+    pool.shutdown();
+
+    let metrics = monitor.metrics();
+    assert_eq!(done.load(Ordering::SeqCst), 3);
+    assert_eq!(metrics.threads, 0);
+    assert_eq!(metrics.queue_depth, 0);
+    assert_eq!(metrics.active_workers, 0);
+    assert_eq!(metrics.jobs_completed, 5);
+    assert_eq!(metrics.panics, 1);
+    assert_eq!(metrics.queue_latency.count(), 5);
+    assert_eq!(metrics.run_latency.count(), 5);
+    // the busy job ran for 50ms, the others waited as long for it
+    assert!(metrics.run_latency.quantile(1.0).unwrap() >= Duration::from_millis(50));
+    assert!(metrics.run_latency.quantile(0.5).unwrap() < Duration::from_millis(50));
+    assert!(metrics.queue_latency.quantile(0.5).unwrap() >= Duration::from_millis(50));
+    Ok(())
+}
+
+#[test]
+fn thread_pools_without_metrics() -> Result<()> {
+    assert_eq!(WorkStealingThreadPool::new(1)?.metrics(), None);
+    assert_eq!(NaiveThreadPool::new(1)?.metrics(), None);
+    Ok(())
+}
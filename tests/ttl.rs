@@ -0,0 +1,128 @@
+use kvs::{KvStore, KvStoreOptions, KvsEngine, Result, SledEngine};
+use std::fs;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const TTL: Duration = Duration::from_millis(200);
+
+fn check_ttl<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set_with_ttl("key1".to_owned(), "value2".to_owned(), TTL)?;
+    engine.set_with_ttl("key2".to_owned(), "value2".to_owned(), TTL)?;
+    engine.set_with_ttl("key3".to_owned(), "value3".to_owned(), TTL)?;
+    engine.set_with_ttl(
+        "key4".to_owned(),
+        "value4".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    // too far in the future to be counted, never expires
+    engine.set_with_ttl("key5".to_owned(), "value5".to_owned(), Duration::MAX)?;
+    // a plain set drops the ttl
+    engine.set("key3".to_owned(), "value4".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    // set only if absent, for a ttl
+    assert!(engine.compare_and_swap_bytes_with_ttl(
+        b"key6".to_vec(),
+        None,
+        b"value6".to_vec(),
+        TTL
+    )?);
+    assert!(!engine.compare_and_swap_bytes_with_ttl(
+        b"key6".to_vec(),
+        None,
+        b"value7".to_vec(),
+        TTL
+    )?);
+    assert_eq!(engine.get("key6".to_owned())?, Some("value6".to_owned()));
+
+    thread::sleep(TTL * 2);
+
+    // an expired value hides the older ones
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert_eq!(engine.get("key3".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(engine.get("key4".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(engine.get("key5".to_owned())?, Some("value5".to_owned()));
+    assert_eq!(engine.get("key6".to_owned())?, None);
+    let keys: Vec<String> = engine
+        .scan(..)?
+        .map(|item| item.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    assert_eq!(keys, vec!["key3", "key4", "key5"]);
+    assert_eq!(engine.scan_prefix_keys(b"key".to_vec())?.count(), 3);
+
+    assert!(engine.remove("key2".to_owned()).is_err());
+    assert!(engine.set_if_absent("key2".to_owned(), "value5".to_owned())?);
+    assert_eq!(engine.get("key2".to_owned())?, Some("value5".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn ttl_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_ttl(KvStore::open(temp_dir.path())?)?;
+
+    // Open from disk again, expired values are dropped by the replay
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value5".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.get("key5".to_owned())?, Some("value5".to_owned()));
+    assert_eq!(store.get("key6".to_owned())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn ttl_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_ttl(SledEngine::open(temp_dir.path())?)
+}
+
+// Expired values are not copied to the compact file
+#[test]
+fn compaction_drops_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .segment_size(1024)
+        .compaction_min_bytes(4 * 1024);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for key_id in 0..20 {
+        store.set_with_ttl(
+            format!("session{}", key_id),
+            "expired-token".to_owned(),
+            TTL,
+        )?;
+    }
+    thread::sleep(TTL * 2);
+
+    // overwrites trigger compactions
+    for iter in 0..200 {
+        store.set(format!("key{}", iter % 10), format!("{}", iter))?;
+    }
+    drop(store);
+
+    let mut compacted = false;
+    for entry in fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        let file_name = path.file_name().unwrap().to_string_lossy().into_owned();
+        if file_name.starts_with("compact") && file_name.ends_with(".log") {
+            compacted = true;
+            let bytes = fs::read(&path)?;
+            assert!(!bytes
+                .windows(b"expired-token".len())
+                .any(|window| window == b"expired-token"));
+        }
+    }
+    assert!(compacted);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..20 {
+        assert_eq!(store.get(format!("session{}", key_id))?, None);
+    }
+
+    Ok(())
+}